@@ -26,6 +26,8 @@ class MainActivity : Activity() {
 
     companion object {
         private val logTag = "sentry"
+        /** Newest version of the server's protocol this app speaks, see src/sentry/protocol.rs */
+        private const val protocolVersion = 2
 
         init {
             System.loadLibrary("sentry_video")
@@ -43,6 +45,8 @@ class MainActivity : Activity() {
     private lateinit var vibrator: Vibrator
     private var tx: PrintWriter? = null
     private val holePuncher = UdpHolePuncher()
+    /** Set once the server has given us a UDP session to send moves over */
+    private var moveSender: UdpMoveSender? = null
     private var isStopped = false
     private var connected = false
     private var queuePosition = 0
@@ -105,11 +109,18 @@ class MainActivity : Activity() {
         joystick.interval = 50 // ms
         joystick.responseCurve = JoystickView.ResponseCurve.Exponential(1.4f)
         joystick.setOnUpdateListener {
-            tx?.println(String.format(
-                """{"pitch":%.3f,"yaw":%.3f}""",
-                it.y * (sensitivity / 100f) * if (invertY) -1 else 1,
-                it.x * (sensitivity / 100f) * if (invertX) -1 else 1
-            ))
+            val pitch = it.y * (sensitivity / 100f) * if (invertY) -1 else 1
+            val yaw = it.x * (sensitivity / 100f) * if (invertX) -1 else 1
+            val sender = moveSender
+            if (sender != null) {
+                try {
+                    sender.send(pitch, yaw)
+                } catch (e: IOException) {
+                    Log.w(logTag, "Could not send move over UDP: ${e.message}")
+                }
+            } else {
+                tx?.println(String.format("""{"type":"move","pitch":%.3f,"yaw":%.3f}""", pitch, yaw))
+            }
         }
 
         fire_button.setOnClickListener {
@@ -167,7 +178,7 @@ class MainActivity : Activity() {
         }
 
         pingTimer.scheduleAtFixedRate(0, 500) {
-            tx?.println("\"ping\"")
+            tx?.println("""{"type":"ping"}""")
         }
 
         updateUi()
@@ -212,6 +223,8 @@ class MainActivity : Activity() {
 
                 val rx = BufferedReader(InputStreamReader(socket!!.getInputStream()))
                 tx = PrintWriter(socket!!.getOutputStream(), true)
+                // The server ignores everything until it has agreed on a version
+                tx!!.println("""{"type":"hello","version":$protocolVersion}""")
                 socket_loop@while (!socket!!.isClosed) {
                     if (isStopped) {
                         Log.i(logTag, "Closing socket")
@@ -235,36 +248,42 @@ class MainActivity : Activity() {
                     try {
                         Log.d(logTag, "Server message: `$line`")
                         val json = JSONObject(line)
-                        when {
-                            json.has("video_offer") -> {
-                                val offer = json.getJSONObject("video_offer")
+                        when (json.optString("type")) {
+                            "hello" -> {
+                                if (json.getBoolean("auth_required")) {
+                                    Log.w(logTag, "Server requires authentication, which isn't supported")
+                                }
+                                // The video is only streamed over RTP to clients that ask for it
+                                tx!!.println("""{"type":"start_rtp"}""")
+                            }
+                            "video_offer" -> {
                                 stopVideo()
                                 holePuncher.start(
-                                    parseSocketAddress(offer.getString("rtp_address")!!),
-                                    offer.getString("nonce")!!
+                                    parseSocketAddress(json.getString("rtp_address")!!),
+                                    json.getString("nonce")!!
                                 )
                             }
-                            json.has("video_streaming") -> {
+                            "video_streaming" -> {
                                 holePuncher.stop()
-                                val command = json
-                                    .getJSONObject("video_streaming")
-                                    .getString("gstreamer_command")
+                                val command = json.getString("gstreamer_command")
                                 this.videoError = playVideo("""
                                     udpsrc port=${holePuncher.boundPort!!} !
                                     ${command!!}
                                 """)
                                 updateUi()
                             }
-                            json.has("video_error") -> {
-                                holePuncher.stop()
-                                videoError = json.getJSONObject("video_error").getString("message")!!
-                                updateUi()
+                            "udp_session" -> {
+                                moveSender?.close()
+                                moveSender = UdpMoveSender(
+                                    InetSocketAddress(socket!!.inetAddress, json.getInt("port")),
+                                    json.getString("token")!!
+                                )
                             }
-                            json.has("queue_position") -> {
-                                this.queuePosition =  json.getInt("queue_position")
+                            "queue" -> {
+                                this.queuePosition =  json.getInt("position")
                                 updateUi()
                             }
-                            json.has("status") -> {
+                            "status" -> {
                                 sentryState = try {
                                     SentryState.valueOf(json.getString("status")!!.toUpperCase())
                                 } catch (e: IllegalArgumentException) {
@@ -275,6 +294,17 @@ class MainActivity : Activity() {
                                 yawPosition = json.getInt("yaw")
                                 updateUi()
                             }
+                            "error" -> {
+                                val message = json.getString("message")!!
+                                when (json.getString("code")) {
+                                    "video" -> {
+                                        holePuncher.stop()
+                                        videoError = message
+                                        updateUi()
+                                    }
+                                    else -> Log.w(logTag, "Server error: $message")
+                                }
+                            }
                             else -> {
                                 Log.w(logTag, "Can't handle message `$line`")
                                 continue@socket_loop
@@ -287,6 +317,8 @@ class MainActivity : Activity() {
                 // Disconnected
                 Log.i(logTag, "Socket disconnected")
                 tx = null
+                moveSender?.close()
+                moveSender = null
                 this.connected = false
                 this.videoError = ""
                 socket = null
@@ -305,7 +337,7 @@ class MainActivity : Activity() {
     }
 
     private fun sendCommand(command: Command) {
-        Thread(Runnable { tx?.println("""{"command":"${command.toString().toLowerCase()}"}""") }).start()
+        Thread(Runnable { tx?.println("""{"type":"${command.toString().toLowerCase()}"}""") }).start()
     }
 
     private fun toggleMenu() {
@@ -0,0 +1,46 @@
+package io.github.kibogaoka.sentry
+
+import org.json.JSONObject
+import java.net.DatagramPacket
+import java.net.DatagramSocket
+import java.net.SocketAddress
+import java.nio.ByteBuffer
+import javax.crypto.Mac
+import javax.crypto.spec.SecretKeySpec
+
+/** Sends joystick moves over UDP, so a lost packet doesn't hold up the ones after it */
+class UdpMoveSender(private val address: SocketAddress, token: String) {
+    private val socket = DatagramSocket()
+    private val key = SecretKeySpec(token.toByteArray(Charsets.UTF_8), "HmacSHA256")
+    private var sequence = 0L
+
+    @Synchronized
+    fun send(pitch: Float, yaw: Float) {
+        // The server drops any datagram that arrives after one with a higher sequence number
+        sequence += 1
+        val bytes = JSONObject()
+            .put("sequence", sequence)
+            .put("pitch", pitch.toDouble())
+            .put("yaw", yaw.toDouble())
+            .put("mac", sign(sequence, pitch.toDouble(), yaw.toDouble()))
+            .toString()
+            .toByteArray()
+        socket.send(DatagramPacket(bytes, bytes.size, address))
+    }
+
+    /** The token itself is never sent, only an HMAC of the move keyed by it */
+    private fun sign(sequence: Long, pitch: Double, yaw: Double): String {
+        val signed = ByteBuffer.allocate(24)
+            .putLong(sequence)
+            .putDouble(pitch)
+            .putDouble(yaw)
+            .array()
+        val mac = Mac.getInstance("HmacSHA256")
+        mac.init(key)
+        return mac.doFinal(signed).joinToString("") { "%02x".format(it) }
+    }
+
+    fun close() {
+        socket.close()
+    }
+}
@@ -13,6 +13,7 @@ typedef enum : uint8_t {
     COMMAND_FIRE_AND_RELOAD,
     COMMAND_MOTORS_ON,
     COMMAND_MOTORS_OFF,
+    COMMAND_MOVE_TO,
 } Command;
 
 } // namespace Sentry
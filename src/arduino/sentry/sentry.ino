@@ -49,6 +49,8 @@ bool homed = false;
 bool homing = false;
 bool homing_failed = false;
 bool reloading = false;
+/// True while the axes are driven by speed commands, which must keep arriving to keep them moving
+bool jogging = false;
 
 inline void ledOn()
 {
@@ -176,6 +178,19 @@ void move(int32_t pitchSpeed, int32_t yawSpeed)
     pitch.moveTo(pitchSpeed >= 0 ? PITCH_MAX_STEPS : 0);
     yaw.setMaxSpeed(static_cast<float>(abs(yawSpeed)));
     yaw.moveTo(yawSpeed >= 0 ? YAW_MAX_STEPS : 0);
+    jogging = true;
+}
+
+void moveTo(uint16_t pitchTarget, uint16_t yawTarget, uint16_t pitchSpeed, uint16_t yawSpeed)
+{
+    if (!homed || !pitch.isEnabled() || !yaw.isEnabled()) {
+        return;
+    }
+    pitch.setMaxSpeed(static_cast<float>(pitchSpeed));
+    pitch.moveTo(min(static_cast<int32_t>(pitchTarget), PITCH_MAX_STEPS));
+    yaw.setMaxSpeed(static_cast<float>(yawSpeed));
+    yaw.moveTo(min(static_cast<int32_t>(yawTarget), YAW_MAX_STEPS));
+    jogging = false;
 }
 
 void releaseMagazine(bool disable)
@@ -316,6 +331,15 @@ void loop()
                             move(pitchSpeed, yawSpeed);
                         }
                         break;
+                    case COMMAND_MOVE_TO:
+                        {
+                            uint16_t pitchTarget = deserialize<uint16_t>(&buffer[3]);
+                            uint16_t yawTarget = deserialize<uint16_t>(&buffer[5]);
+                            uint16_t pitchSpeed = deserialize<uint16_t>(&buffer[7]);
+                            uint16_t yawSpeed = deserialize<uint16_t>(&buffer[9]);
+                            moveTo(pitchTarget, yawTarget, pitchSpeed, yawSpeed);
+                        }
+                        break;
                     case COMMAND_HOME:
                         {
                             uint32_t pitchSpeed = deserialize<uint32_t>(&buffer[3]);
@@ -360,7 +384,7 @@ void loop()
         }
     }
 
-    if (timeDiff(timestamp, lastMessageTime) > 500000) {
+    if (jogging && timeDiff(timestamp, lastMessageTime) > 500000) {
         // Haven't received a message 0.5 seconds
         pitch.setMaxSpeed(0);
         yaw.setMaxSpeed(0);
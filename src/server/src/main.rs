@@ -14,11 +14,12 @@ use simplelog::{
     CombinedLogger, Config as LogConfig, LevelFilter, SharedLogger, TermLogger, WriteLogger,
 };
 use std::env;
+use std::process;
 use tokio::prelude::*;
 use tokio::runtime::Runtime;
 
 mod sentry;
-use crate::sentry::{bus, Message};
+use crate::sentry::{bus, BusReceiver, Device, Message, MessageContent};
 use futures::future::Loop;
 use std::fs::File;
 use std::ops::Add;
@@ -26,6 +27,27 @@ use std::time::{Duration, Instant};
 use tokio::timer::Delay;
 
 fn main() {
+    match env::args().nth(1).as_deref() {
+        None => {}
+        Some("list-cameras") => {
+            if let Err(err) = sentry::camera::print_cameras() {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+            return;
+        }
+        Some("protocol-schema") => {
+            let schema = sentry::protocol::schema();
+            println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+            return;
+        }
+        Some(other) => {
+            eprintln!("Unknown command \"{}\"", other);
+            eprintln!("Usage: sentry [list-cameras | protocol-schema]");
+            process::exit(2);
+        }
+    }
+
     let mut log_path = env::current_exe().expect("Cannot get executable path");
     log_path.pop();
     log_path.push("sentry.log");
@@ -69,9 +91,58 @@ fn run(runtime: Runtime) -> impl Future<Item = (), Error = String> {
             move || sentry::arduino::start(config, bus, &reactor)
         };
 
-        tokio::spawn(run_module(format!("Video"), video));
+        let presets = {
+            let bus = (bus_sink.clone(), bus_stream.clone());
+            move || sentry::presets::start(bus)
+        };
+        let patrol = {
+            let config = config.clone();
+            let bus = (bus_sink.clone(), bus_stream.clone());
+            move || sentry::patrol::start(config, bus)
+        };
+        let tracking = {
+            let config = config.clone();
+            let bus = (bus_sink.clone(), bus_stream.clone());
+            move || sentry::tracking::start(config, bus)
+        };
+        let http = {
+            let config = config.clone();
+            move || sentry::http::start(config)
+        };
+        let audit = {
+            let config = config.clone();
+            let bus = (bus_sink.clone(), bus_stream.clone());
+            move || sentry::audit::start(config, bus)
+        };
+        let hotplug = {
+            let config = config.clone();
+            let bus_sink = bus_sink.clone();
+            move || sentry::hotplug::start(config, bus_sink)
+        };
+
+        tokio::spawn(run_device_module(
+            format!("Video"),
+            video,
+            Device::Camera,
+            bus_stream.cloner(),
+        ));
         tokio::spawn(run_module(format!("Server"), server));
-        tokio::spawn(run_module(format!("Arduino"), arduino));
+        tokio::spawn(run_device_module(
+            format!("Arduino"),
+            arduino,
+            Device::Arduino,
+            bus_stream.cloner(),
+        ));
+        tokio::spawn(run_module("Presets".to_string(), presets));
+        tokio::spawn(run_module("Patrol".to_string(), patrol));
+        tokio::spawn(run_module("Audit".to_string(), audit));
+        tokio::spawn(run_module("Hotplug".to_string(), hotplug));
+        if config.tracking.is_some() {
+            tokio::spawn(run_module("Tracking".to_string(), tracking));
+        }
+        if config.http.is_some() {
+            tokio::spawn(run_module("Http".to_string(), http));
+        }
 
         bus_stream
             .map_err(|_| format!("Failed to read from bus"))
@@ -84,7 +155,47 @@ where
     T: FnOnce() -> M + Clone,
     M: Future<Item = (), Error = String>,
 {
-    future::loop_fn((name, module), move |(name, module)| {
+    run_module_until(name, module, future::empty)
+}
+
+/// Runs a module that depends on a device, restarting it as soon as the device is plugged back in.
+/// `clone_bus` has to clone a receiver that is being polled, or the module never hears about it.
+fn run_device_module<T, M, C>(
+    name: String,
+    module: T,
+    device: Device,
+    clone_bus: C,
+) -> impl Future<Item = (), Error = ()>
+where
+    T: FnOnce() -> M + Clone,
+    M: Future<Item = (), Error = String>,
+    C: Fn() -> BusReceiver<Message>,
+{
+    run_module_until(name, module, move || {
+        clone_bus()
+            .filter(move |message| match message.content {
+                MessageContent::DeviceConnected(connected) => connected == device,
+                _ => false,
+            })
+            .into_future()
+            .map(|_| ())
+            .map_err(|_| ())
+    })
+}
+
+/// Runs a module, restarting it 5 seconds after it fails or when `wake` resolves, if sooner
+fn run_module_until<T, M, W, F>(
+    name: String,
+    module: T,
+    wake: W,
+) -> impl Future<Item = (), Error = ()>
+where
+    T: FnOnce() -> M + Clone,
+    M: Future<Item = (), Error = String>,
+    W: Fn() -> F,
+    F: Future<Item = (), Error = ()>,
+{
+    future::loop_fn((name, module, wake), move |(name, module, wake)| {
         info!("Starting module {}", name);
         module.clone()()
             .and_then({
@@ -99,7 +210,65 @@ where
                 info!("Restarting module {} in 5 seconds...", name);
                 Delay::new(Instant::now().add(Duration::from_secs(5)))
                     .map_err(|_| ())
-                    .and_then(|_| Ok(Loop::Continue((name, module))))
+                    .select(wake())
+                    .then(|_| Ok(Loop::Continue((name, module, wake))))
             })
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sentry::MessageSource;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc};
+    use tokio::runtime::current_thread::Runtime;
+
+    #[test]
+    fn restarts_device_modules_when_plugged_in() {
+        let mut runtime = Runtime::new().unwrap();
+        let (bus_sink, bus_stream) = bus::new::<Message>();
+        let starts = Arc::new(AtomicUsize::new(0));
+        let (restarted_tx, restarted_rx) = mpsc::channel();
+        let module = {
+            let starts = starts.clone();
+            move || {
+                if starts.fetch_add(1, Ordering::SeqCst) == 0 {
+                    future::err("Camera unplugged".to_string())
+                } else {
+                    restarted_tx.send(()).unwrap();
+                    future::ok(())
+                }
+            }
+        };
+
+        runtime.spawn(run_device_module(
+            "Video".to_string(),
+            module,
+            Device::Camera,
+            bus_stream.cloner(),
+        ));
+        runtime.spawn(bus_stream.for_each(|_| Ok(())));
+        runtime
+            .block_on(Delay::new(Instant::now() + Duration::from_millis(100)))
+            .unwrap();
+        assert_eq!(starts.load(Ordering::SeqCst), 1);
+
+        // Other devices don't wake the module
+        for &device in &[Device::Arduino, Device::Camera] {
+            bus_sink
+                .unbounded_send(Message {
+                    content: MessageContent::DeviceConnected(device),
+                    source: MessageSource::Hotplug,
+                })
+                .unwrap();
+        }
+        runtime
+            .block_on(Delay::new(Instant::now() + Duration::from_millis(100)))
+            .unwrap();
+
+        // Well before the 5 second delay
+        assert!(restarted_rx.try_recv().is_ok());
+        assert_eq!(starts.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,23 +1,36 @@
 use crate::sentry::config::Config;
-use crate::sentry::{Bus, Command, HardwareStatus, Message, MessageContent, MessageSource};
+use crate::sentry::simulator;
+use crate::sentry::{
+    Bus, Command, CommandOutcome, HardwareStatus, Message, MessageContent, MessageSource,
+};
 use byteorder::{BigEndian, ByteOrder};
 use bytes::{BufMut, BytesMut};
 use crc::crc16::checksum_usb as crc16;
+use futures::future::Either;
 use futures::{Sink, Stream};
 use std::io;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::codec::{Decoder, Encoder};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::prelude::*;
 use tokio::reactor::Handle;
 use tokio_serial::{DataBits, FlowControl, Parity, Serial, SerialPortSettings, StopBits};
 
+mod limits;
+use limits::Envelope;
+
 struct ArduinoCodec {
     config: Config,
+    /// Whether a homed status has been seen since the arduino connected
+    homed: bool,
 }
 
 impl ArduinoCodec {
     pub fn new(config: Config) -> Self {
-        ArduinoCodec { config }
+        ArduinoCodec {
+            config,
+            homed: false,
+        }
     }
 }
 
@@ -26,33 +39,35 @@ impl Decoder for ArduinoCodec {
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() >= 11 {
+        // Skip bytes until a valid message is found, since there may be another one behind
+        // a corrupt one and we won't be called again until more data arrives
+        while src.len() >= 11 {
             let (our_crc, their_crc) = (BigEndian::read_u16(src), crc16(&src[2..11]));
             if our_crc == their_crc {
                 let message = src.split_to(11);
-                Ok(Some(MessageContent::HardwareState {
-                    status: match message[2] {
-                        100 => HardwareStatus::Ready,
-                        101 => HardwareStatus::NotLoaded,
-                        102 => HardwareStatus::MagazineReleased,
-                        103 => HardwareStatus::Reloading,
-                        104 => HardwareStatus::HomingRequired,
-                        105 => HardwareStatus::Homing,
-                        106 => HardwareStatus::MotorsOff,
-                        107 => HardwareStatus::HomingFailed,
-                        _ => HardwareStatus::Error,
-                    },
+                let status = match message[2] {
+                    100 => HardwareStatus::Ready,
+                    101 => HardwareStatus::NotLoaded,
+                    102 => HardwareStatus::MagazineReleased,
+                    103 => HardwareStatus::Reloading,
+                    104 => HardwareStatus::HomingRequired,
+                    105 => HardwareStatus::Homing,
+                    106 => HardwareStatus::MotorsOff,
+                    107 => HardwareStatus::HomingFailed,
+                    _ => HardwareStatus::Error,
+                };
+                self.homed = status.homed_after(self.homed);
+                return Ok(Some(MessageContent::HardwareState {
+                    status,
                     pitch_pos: BigEndian::read_u32(&message[3..]),
                     yaw_pos: BigEndian::read_u32(&message[7..]),
-                }))
-            } else {
-                warn!("Arduino CRC mismatch: {:#X}/{:#X}", our_crc, their_crc);
-                src.advance(1);
-                Ok(None)
+                    homed: self.homed,
+                }));
             }
-        } else {
-            Ok(None)
+            warn!("Arduino CRC mismatch: {:#X}/{:#X}", our_crc, their_crc);
+            src.advance(1);
         }
+        Ok(None)
     }
 }
 
@@ -73,6 +88,7 @@ impl Encoder for ArduinoCodec {
             Command::FireAndReload => 206,
             Command::MotorsOn => 207,
             Command::MotorsOff => 208,
+            Command::MoveTo { .. } => 209,
         };
         match item {
             Command::Move { pitch, yaw } => {
@@ -85,6 +101,21 @@ impl Encoder for ArduinoCodec {
                     (yaw * self.config.arduino.yaw_max_speed as f64) as i32,
                 );
             }
+            Command::MoveTo { pitch, yaw } => {
+                // Targets and speeds are sent as 16 bit values so they all fit in one message
+                let to_u16 = |value: u32| value.min(u32::from(u16::MAX)) as u16;
+                BigEndian::write_u16(&mut message[3..], to_u16(pitch));
+                BigEndian::write_u16(&mut message[5..], to_u16(yaw));
+                let arduino = &self.config.arduino;
+                BigEndian::write_u16(
+                    &mut message[7..],
+                    to_u16(arduino.pitch_limits.cap_speed(arduino.pitch_max_speed)),
+                );
+                BigEndian::write_u16(
+                    &mut message[9..],
+                    to_u16(arduino.yaw_limits.cap_speed(arduino.yaw_max_speed)),
+                );
+            }
             Command::Home => {
                 BigEndian::write_u32(&mut message[3..], self.config.arduino.pitch_homing_speed);
                 BigEndian::write_u32(&mut message[7..], self.config.arduino.yaw_homing_speed);
@@ -103,6 +134,13 @@ pub fn start(
     bus: Bus<Message>,
     handle: &Handle,
 ) -> impl Future<Item = (), Error = String> {
+    if config.arduino.simulate {
+        return Either::A(
+            future::result(simulator::start())
+                .and_then(move |arduino| handle_arduino(config, arduino, bus)),
+        );
+    }
+
     let serial_settings = SerialPortSettings {
         baud_rate: config.arduino.baud,
         parity: Parity::None,
@@ -112,27 +150,54 @@ pub fn start(
         timeout: Duration::from_millis(10),
     };
 
-    future::result(Serial::from_path_with_handle(
-        &config.arduino.device,
-        &serial_settings,
-        handle,
-    ))
-    .map_err({
-        let config = config.clone();
-        move |err| format!("Cannot open {}: {}", config.arduino.device, err)
-    })
-    .and_then(move |arduino| handle_arduino(config, arduino, bus))
+    Either::B(
+        future::result(Serial::from_path_with_handle(
+            &config.arduino.device,
+            &serial_settings,
+            handle,
+        ))
+        .map_err({
+            let config = config.clone();
+            move |err| format!("Cannot open {}: {}", config.arduino.device, err)
+        })
+        .and_then(move |arduino| handle_arduino(config, arduino, bus)),
+    )
+}
+
+/// Returns why a command must not be sent, if it would fire from inside a no-fire zone. Until the
+/// turret is homed its position is unknown, so nothing fires then if any zones are configured.
+fn fire_blocked_reason(
+    config: &Config,
+    command: &Command,
+    position: Option<(u32, u32)>,
+) -> Option<String> {
+    match command {
+        Command::Fire | Command::FireAndReload if !config.no_fire_zones.is_empty() => {}
+        _ => return None,
+    }
+    let (pitch, yaw) = match position {
+        Some(position) => position,
+        None => return Some("the turret's position is unknown until it is homed".to_string()),
+    };
+    config
+        .no_fire_zones
+        .iter()
+        .find(|zone| zone.contains(&config.arduino, pitch, yaw))
+        .map(|zone| format!("the turret is inside no-fire zone \"{}\"", zone.name))
 }
 
-fn handle_arduino(
+fn handle_arduino<T: AsyncRead + AsyncWrite>(
     config: Config,
-    arduino: Serial,
+    arduino: T,
     bus: Bus<Message>,
 ) -> impl Future<Item = (), Error = String> {
     let (bus_sink, bus_stream) = bus;
     let (arduino_sink, arduino_stream) = ArduinoCodec::new(config.clone()).framed(arduino).split();
     let mut message_count = 0;
     let mut last_calculation_time = SystemTime::now();
+    // Whether the last move forwarded to the arduino came from a client rather than a module
+    let mut client_moved_last = true;
+    let mut envelope = Envelope::new(&config.arduino);
 
     // Spawn a task to forward arduino messages to the server through an unbounded channel
     let arduino_future = arduino_stream
@@ -148,36 +213,104 @@ fn handle_arduino(
         )
         .map(|_| ());
 
+    // Rate-limit the amount of commands we get to prevent straining the serial connection
+    let mut is_rate_limited = move || {
+        message_count += 1;
+        if message_count >= 10 {
+            // Allow <=10 messages/100ms
+            match last_calculation_time.elapsed() {
+                Ok(duration) if duration.as_millis() < 100 => {
+                    warn!("Discarding arduino command due to rate-limiting");
+                    return true;
+                }
+                _ => {}
+            }
+
+            message_count = 0;
+            last_calculation_time = SystemTime::now();
+        }
+        false
+    };
+
     let bus_future = bus_stream
         .map_err(|_| format!("Failed to read from bus"))
         .filter_map(move |message| {
-            match &message.source {
+            let now = Instant::now();
+            let (command, source, is_correction) = match message.content {
+                // Keep track of where the turret is pointing, which only counts once it's been homed,
+                // and slow down whatever move is in progress as it nears a limit. Corrections are
+                // reported like any other command, as the arduino module's own.
+                MessageContent::HardwareState {
+                    pitch_pos,
+                    yaw_pos,
+                    homed,
+                    ..
+                } => {
+                    let position = if homed {
+                        Some((pitch_pos, yaw_pos))
+                    } else {
+                        None
+                    };
+                    let correction = envelope.update_position(position, now)?;
+                    (correction, MessageSource::Arduino, true)
+                }
+                MessageContent::Command(command) => (command, message.source, false),
+                _ => return None,
+            };
+
+            let is_client = matches!(source, MessageSource::Client(_));
+            let is_stop = command
+                == Command::Move {
+                    pitch: 0.0,
+                    yaw: 0.0,
+                };
+            let outcome = match &source {
                 // Ignore messages from clients that aren't first in the queue
-                MessageSource::Client(client) if client.queue_position > 0 => None,
-                _ => match message.content {
-                    // Match Command messages only
-                    MessageContent::Command(command) => Some(command),
-                    _ => None,
-                },
-            }
-        })
-        .filter(move |_| {
-            // Rate-limit the amount of commands we get to prevent straining the serial connection
-            message_count += 1;
-            if message_count >= 10 {
-                // Allow <=10 messages/100ms
-                match last_calculation_time.elapsed() {
-                    Ok(duration) if duration.as_millis() < 100 => {
-                        warn!("Discarding arduino command due to rate-limiting");
-                        return false;
+                MessageSource::Client(client) if client.queue_position > 0 => {
+                    CommandOutcome::Ignored
+                }
+                // Clients keep sending zero moves while their joystick is idle, which would
+                // stop the turret whenever another module moves it
+                MessageSource::Client(_) if is_stop && !client_moved_last => {
+                    CommandOutcome::Ignored
+                }
+                _ => match fire_blocked_reason(&config, &command, envelope.position()) {
+                    Some(reason) => {
+                        warn!("Refusing to fire because {}", reason);
+                        CommandOutcome::Blocked { reason }
                     }
-                    _ => {}
+                    // Corrections are already spaced out, and stopping at a limit mustn't wait
+                    // for clients to stop flooding the serial connection
+                    None if !is_correction && is_rate_limited() => CommandOutcome::RateLimited,
+                    None => CommandOutcome::Forwarded,
+                },
+            };
+            // A correction only slows down whichever move is in progress
+            if outcome == CommandOutcome::Forwarded && !is_correction {
+                if let Command::Move { .. } = command {
+                    client_moved_last = is_client;
                 }
+            }
+
+            // Let the rest of the server know what became of the command
+            bus_sink
+                .unbounded_send(Message {
+                    content: MessageContent::CommandProcessed {
+                        command: command.clone(),
+                        source,
+                        outcome: outcome.clone(),
+                    },
+                    source: MessageSource::Arduino,
+                })
+                .unwrap_or_else(|err| error!("Failed to send bus message: {}", err));
 
-                message_count = 0;
-                last_calculation_time = SystemTime::now();
+            match outcome {
+                CommandOutcome::Forwarded if is_correction => {
+                    Some(envelope.apply_correction(command, now))
+                }
+                CommandOutcome::Forwarded => Some(envelope.apply(command)),
+                _ => None,
             }
-            true
         })
         // Forward server messages to the arduino
         .forward(
@@ -190,3 +323,6 @@ fn handle_arduino(
         .map_err(|(err, _)| err)
         .map(|_| ())
 }
+
+#[cfg(test)]
+mod tests;
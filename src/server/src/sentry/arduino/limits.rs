@@ -0,0 +1,311 @@
+//! Keeps moves within the soft limits of each axis before they are encoded
+use crate::sentry::config::{self, ArduinoConfig, AxisConfig, AxisLimits};
+use crate::sentry::Command;
+use std::time::{Duration, Instant};
+
+/// Shortest time between the slower moves sent as the turret approaches a limit, so they don't
+/// flood the serial connection at the rate the firmware reports its position
+const CORRECTION_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Where an axis may travel and how fast, in steps. Without soft limits the firmware's own
+/// range applies, which it enforces itself.
+struct AxisEnvelope {
+    min: u32,
+    max: u32,
+    /// Speed of a move at full rate
+    full_speed: f64,
+    max_speed: f64,
+    /// Without it, moves are cut off once they reach a limit
+    deceleration: Option<f64>,
+}
+
+impl AxisEnvelope {
+    fn new(axis: &AxisConfig, full_speed: u32, acceleration: f64, limits: &AxisLimits) -> Self {
+        let min = limits
+            .min_degrees
+            .map_or(0, |degrees| axis.to_steps(degrees));
+        let max = limits
+            .max_degrees
+            .map_or(u32::MAX, |degrees| axis.to_steps(degrees));
+        AxisEnvelope {
+            min: min.min(max),
+            max: max.max(min),
+            full_speed: f64::from(full_speed),
+            max_speed: f64::from(limits.cap_speed(full_speed)),
+            deceleration: limits.deceleration_or(acceleration),
+        }
+    }
+
+    /// Lowers a move rate to the axis' top speed and, once its position is known, to a speed it
+    /// can still stop from before the limit it's heading for
+    fn limit_rate(&self, rate: f64, position: Option<u32>) -> f64 {
+        let mut speed = (rate * self.full_speed).clamp(-self.max_speed, self.max_speed);
+        if let Some(position) = position {
+            let distance = if speed > 0.0 {
+                self.max.saturating_sub(position)
+            } else {
+                position.saturating_sub(self.min)
+            };
+            let stopping_speed = match self.deceleration {
+                Some(deceleration) => (2.0 * deceleration * f64::from(distance)).sqrt(),
+                None if distance > 0 => f64::INFINITY,
+                None => 0.0,
+            };
+            speed = speed.clamp(-stopping_speed, stopping_speed);
+        }
+        speed / self.full_speed
+    }
+
+    fn limit_target(&self, target: u32) -> u32 {
+        target.clamp(self.min, self.max)
+    }
+}
+
+pub struct Envelope {
+    pitch: AxisEnvelope,
+    yaw: AxisEnvelope,
+    /// Where the turret is, once it has been homed
+    position: Option<(u32, u32)>,
+    /// Rates of the move in progress as they were asked for, which are limited again as the
+    /// turret travels
+    requested: Option<(f64, f64)>,
+    /// Rates of the move in progress as they were sent
+    sent: (f64, f64),
+    last_correction: Option<Instant>,
+}
+
+impl Envelope {
+    pub fn new(config: &ArduinoConfig) -> Self {
+        Envelope {
+            pitch: AxisEnvelope::new(
+                &config.pitch,
+                config.pitch_max_speed,
+                config::PITCH_ACCEL,
+                &config.pitch_limits,
+            ),
+            yaw: AxisEnvelope::new(
+                &config.yaw,
+                config.yaw_max_speed,
+                config::YAW_ACCEL,
+                &config.yaw_limits,
+            ),
+            position: None,
+            requested: None,
+            sent: (0.0, 0.0),
+            last_correction: None,
+        }
+    }
+
+    pub fn position(&self) -> Option<(u32, u32)> {
+        self.position
+    }
+
+    fn limit_move(&self, pitch: f64, yaw: f64) -> (f64, f64) {
+        (
+            self.pitch
+                .limit_rate(pitch, self.position.map(|position| position.0)),
+            self.yaw
+                .limit_rate(yaw, self.position.map(|position| position.1)),
+        )
+    }
+
+    /// Returns the command to send in place of one about to be forwarded to the arduino
+    pub fn apply(&mut self, command: Command) -> Command {
+        match command {
+            Command::Move { pitch, yaw } => {
+                self.requested = Some((pitch, yaw));
+                self.sent = self.limit_move(pitch, yaw);
+                Command::Move {
+                    pitch: self.sent.0,
+                    yaw: self.sent.1,
+                }
+            }
+            Command::MoveTo { pitch, yaw } => {
+                self.requested = None;
+                Command::MoveTo {
+                    pitch: self.pitch.limit_target(pitch),
+                    yaw: self.yaw.limit_target(yaw),
+                }
+            }
+            Command::Home | Command::MotorsOff => {
+                self.requested = None;
+                command
+            }
+            _ => command,
+        }
+    }
+
+    /// Records where the turret is now, returning a slower move if the one in progress would
+    /// carry it past a limit. The correction only counts as sent once it has been applied.
+    pub fn update_position(
+        &mut self,
+        position: Option<(u32, u32)>,
+        now: Instant,
+    ) -> Option<Command> {
+        self.position = position;
+        let (pitch, yaw) = self.requested?;
+        let limited = self.limit_move(pitch, yaw);
+        let recently_corrected = self
+            .last_correction
+            .map(|time| now.duration_since(time) < CORRECTION_INTERVAL)
+            .unwrap_or(false);
+        if limited == self.sent || recently_corrected {
+            return None;
+        }
+        Some(Command::Move {
+            pitch: limited.0,
+            yaw: limited.1,
+        })
+    }
+
+    /// Records that a correction from `update_position` is about to be forwarded to the arduino.
+    /// Unlike `apply`, the move that was asked for is kept, so it can speed up again once the
+    /// turret turns away from the limit.
+    pub fn apply_correction(&mut self, command: Command, now: Instant) -> Command {
+        if let Command::Move { pitch, yaw } = command {
+            self.sent = (pitch, yaw);
+            self.last_correction = Some(now);
+        }
+        command
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope(limits: AxisLimits) -> Envelope {
+        let config: ArduinoConfig = toml::from_str(
+            r#"
+            device = "/dev/null"
+            baud = 115200
+            pitch_max_speed = 4000
+            yaw_max_speed = 8000
+            pitch_homing_speed = 1000
+            yaw_homing_speed = 1000
+
+            [pitch]
+            gear_ratio = 9.0
+            microsteps = 4
+            min_degrees = -72.0
+            max_degrees = 53.0
+
+            [yaw]
+            gear_ratio = 9.0
+            microsteps = 4
+            min_degrees = 0.0
+            max_degrees = 352.0
+            "#,
+        )
+        .expect("Invalid test configuration");
+        Envelope::new(&ArduinoConfig {
+            yaw_limits: limits,
+            ..config
+        })
+    }
+
+    fn yaw_move(yaw: f64) -> Command {
+        Command::Move { pitch: 0.0, yaw }
+    }
+
+    #[test]
+    fn caps_speed_and_targets() {
+        let mut envelope = envelope(AxisLimits {
+            min_degrees: Some(10.0),
+            max_degrees: Some(90.0),
+            max_speed: Some(2000),
+            deceleration: Some(0.0),
+        });
+        assert_eq!(envelope.apply(yaw_move(-1.0)), yaw_move(-0.25));
+        assert_eq!(envelope.apply(yaw_move(0.125)), yaw_move(0.125));
+        // 20 steps per degree, and pitch is left for the firmware to clamp
+        assert_eq!(
+            envelope.apply(Command::MoveTo {
+                pitch: 5000,
+                yaw: 100
+            }),
+            Command::MoveTo {
+                pitch: 5000,
+                yaw: 200
+            }
+        );
+        assert_eq!(envelope.apply(Command::Fire), Command::Fire);
+    }
+
+    #[test]
+    fn stops_at_limits() {
+        let mut envelope = envelope(AxisLimits {
+            max_degrees: Some(90.0),
+            deceleration: Some(0.0),
+            ..AxisLimits::default()
+        });
+        let start = Instant::now();
+        assert_eq!(envelope.update_position(Some((0, 1000)), start), None);
+        assert_eq!(envelope.apply(yaw_move(0.5)), yaw_move(0.5));
+        assert_eq!(envelope.update_position(Some((0, 1700)), start), None);
+
+        let later = start + CORRECTION_INTERVAL;
+        assert_eq!(
+            envelope.update_position(Some((0, 1800)), later),
+            Some(yaw_move(0.0))
+        );
+        // Until a correction is sent, it is asked for again
+        assert_eq!(
+            envelope.update_position(Some((0, 1800)), later),
+            Some(yaw_move(0.0))
+        );
+        envelope.apply_correction(yaw_move(0.0), later);
+        assert_eq!(
+            envelope.update_position(Some((0, 1800)), later + CORRECTION_INTERVAL),
+            None
+        );
+        // Moving away from the limit is still allowed
+        assert_eq!(envelope.apply(yaw_move(-0.5)), yaw_move(-0.5));
+    }
+
+    #[test]
+    fn slows_down_ahead_of_limits() {
+        let mut envelope = envelope(AxisLimits {
+            max_degrees: Some(90.0),
+            deceleration: Some(10000.0),
+            ..AxisLimits::default()
+        });
+        let start = Instant::now();
+        envelope.update_position(Some((0, 0)), start);
+        // Full speed is too fast to stop from even 1800 steps away
+        assert_eq!(envelope.apply(yaw_move(1.0)), yaw_move(0.75));
+        assert_eq!(envelope.apply(yaw_move(0.5)), yaw_move(0.5));
+
+        // 200 steps from the limit it can only stop in time from 2000 steps per second
+        let later = start + CORRECTION_INTERVAL;
+        assert_eq!(
+            envelope.update_position(Some((0, 1600)), later),
+            Some(yaw_move(0.25))
+        );
+        envelope.apply_correction(yaw_move(0.25), later);
+        // Corrections are spaced out
+        assert_eq!(
+            envelope.update_position(Some((0, 1750)), later + CORRECTION_INTERVAL / 2),
+            None
+        );
+        assert_eq!(
+            envelope.update_position(Some((0, 1800)), later + CORRECTION_INTERVAL),
+            Some(yaw_move(0.0))
+        );
+    }
+
+    #[test]
+    fn slows_down_as_the_firmware_does_by_default() {
+        let mut envelope = envelope(AxisLimits {
+            max_degrees: Some(90.0),
+            ..AxisLimits::default()
+        });
+        envelope.update_position(Some((0, 1700)), Instant::now());
+        // YAW_ACCEL stops it from 100 steps away at just under 1550 steps per second
+        let speed = match envelope.apply(yaw_move(1.0)) {
+            Command::Move { yaw, .. } => yaw * 8000.0,
+            other => panic!("Expected a move, got {:?}", other),
+        };
+        assert!((speed - (2.0 * config::YAW_ACCEL * 100.0).sqrt()).abs() < 1e-6);
+    }
+}
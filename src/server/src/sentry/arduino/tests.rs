@@ -0,0 +1,654 @@
+//! Exercises the arduino module against scripted firmware on the other end of a pseudo-terminal
+use super::*;
+use crate::sentry::bus::{self, BusReceiver, BusSender};
+use crate::sentry::simulator::FirmwareCodec;
+use crate::sentry::Client;
+use std::net::SocketAddr;
+use tokio::codec::Framed;
+use tokio::runtime::current_thread::Runtime;
+use tokio_serial::SerialPort;
+
+const PITCH_MAX_SPEED: u32 = 5000;
+const YAW_MAX_SPEED: u32 = 8000;
+const PITCH_HOMING_SPEED: u32 = 1500;
+const YAW_HOMING_SPEED: u32 = 2500;
+
+/// How long to wait for the arduino module before deciding nothing else is coming
+const QUIET_PERIOD: Duration = Duration::from_millis(300);
+
+/// The test configuration, followed by any extra TOML a test needs
+fn test_config(device: String, extra: &str) -> Config {
+    toml::from_str(&format!(
+        r#"
+        camera = {{}}
+
+        [server]
+        host = "127.0.0.1"
+        port = 0
+
+        [video]
+        encoder = "fakesink"
+        decoder = "fakesink"
+        host = "127.0.0.1"
+
+        [arduino]
+        device = "{}"
+        baud = 115200
+        pitch_max_speed = {}
+        yaw_max_speed = {}
+        pitch_homing_speed = {}
+        yaw_homing_speed = {}
+
+        [arduino.pitch]
+        gear_ratio = 9.0
+        microsteps = 4
+        min_degrees = -72.0
+        max_degrees = 53.0
+
+        [arduino.yaw]
+        gear_ratio = 10.5
+        microsteps = 4
+        min_degrees = 0.0
+        max_degrees = 352.0
+
+        {}
+        "#,
+        device, PITCH_MAX_SPEED, YAW_MAX_SPEED, PITCH_HOMING_SPEED, YAW_HOMING_SPEED, extra,
+    ))
+    .expect("Invalid test configuration")
+}
+
+fn status_frame(status: u8, pitch_pos: u32, yaw_pos: u32) -> BytesMut {
+    let mut frame = BytesMut::new();
+    FirmwareCodec
+        .encode((status, pitch_pos, yaw_pos), &mut frame)
+        .unwrap();
+    frame
+}
+
+/// The arduino module running on one end of a pty pair, with the test acting as the firmware on the other
+struct Harness {
+    runtime: Runtime,
+    firmware: Framed<Serial, FirmwareCodec>,
+    bus_sink: BusSender<Message>,
+    bus_stream: BusReceiver<Message>,
+    // Keeps the pty open even if the module closes its end
+    _device: Serial,
+}
+
+impl Harness {
+    fn new() -> Self {
+        Self::with_config("")
+    }
+
+    fn with_config(extra: &str) -> Self {
+        let mut runtime = Runtime::new().unwrap();
+        let (firmware, mut device) = Serial::pair().expect("Cannot open pty pair");
+        // Let the module open the device by path, the same way it opens a real arduino
+        device.set_exclusive(false).unwrap();
+        let config = test_config(device.name().expect("pty has no device path"), extra);
+
+        let (bus_sink, bus_stream) = bus::new::<Message>();
+        let module_bus = (bus_sink.clone(), bus_stream.clone());
+        let test_stream = bus_stream.clone();
+        // Clones only receive messages while the original receiver is being polled
+        runtime.spawn(bus_stream.for_each(|_| Ok(())));
+
+        let module = runtime
+            .block_on(future::lazy(move || {
+                Ok::<_, ()>(start(config, module_bus, &Handle::default()))
+            }))
+            .unwrap();
+        runtime.spawn(module.map_err(|err| panic!("Arduino module failed: {}", err)));
+
+        Harness {
+            runtime,
+            firmware: FirmwareCodec.framed(firmware),
+            bus_sink,
+            bus_stream: test_stream,
+            _device: device,
+        }
+    }
+
+    /// Writes bytes to the server as if they came from the firmware
+    fn write_raw(&mut self, bytes: &[u8]) {
+        self.runtime
+            .block_on(tokio::io::write_all(
+                self.firmware.get_mut(),
+                bytes.to_vec(),
+            ))
+            .expect("Failed to write to pty");
+    }
+
+    /// Publishes a command on the bus from the client at the head of the queue
+    fn send_command(&mut self, command: Command) {
+        self.bus_sink
+            .unbounded_send(Message {
+                content: MessageContent::Command(command),
+                source: MessageSource::Client(Client {
+                    address: SocketAddr::from(([127, 0, 0, 1], 5000)),
+                    queue_position: 0,
+                }),
+            })
+            .unwrap();
+    }
+
+    /// Waits for the next `HardwareState` the module publishes on the bus
+    fn next_hardware_state(&mut self) -> (HardwareStatus, u32, u32) {
+        let state = self
+            .runtime
+            .block_on(
+                self.bus_stream
+                    .by_ref()
+                    .filter_map(|message| match (message.source, message.content) {
+                        (
+                            MessageSource::Arduino,
+                            MessageContent::HardwareState {
+                                status,
+                                pitch_pos,
+                                yaw_pos,
+                                ..
+                            },
+                        ) => Some((status, pitch_pos, yaw_pos)),
+                        _ => None,
+                    })
+                    .into_future()
+                    .map_err(|_| ())
+                    .timeout(QUIET_PERIOD),
+            )
+            .expect("Timed out waiting for hardware state");
+        state.0.expect("Bus closed")
+    }
+
+    /// Waits for the outcome of the next command the module processes
+    fn next_outcome(&mut self) -> (Command, CommandOutcome) {
+        let outcome = self
+            .runtime
+            .block_on(
+                self.bus_stream
+                    .by_ref()
+                    .filter_map(|message| match message.content {
+                        MessageContent::CommandProcessed {
+                            command, outcome, ..
+                        } => Some((command, outcome)),
+                        _ => None,
+                    })
+                    .into_future()
+                    .map_err(|_| ())
+                    .timeout(QUIET_PERIOD),
+            )
+            .expect("Timed out waiting for command outcome");
+        outcome.0.expect("Bus closed")
+    }
+
+    /// Waits for the outcome of the next move the module sends to keep the turret within limits
+    fn next_correction(&mut self) -> (Command, CommandOutcome) {
+        let outcome = self
+            .runtime
+            .block_on(
+                self.bus_stream
+                    .by_ref()
+                    .filter_map(|message| match message.content {
+                        MessageContent::CommandProcessed {
+                            command,
+                            source: MessageSource::Arduino,
+                            outcome,
+                        } => Some((command, outcome)),
+                        _ => None,
+                    })
+                    .into_future()
+                    .map_err(|_| ())
+                    .timeout(QUIET_PERIOD),
+            )
+            .expect("Timed out waiting for a correction");
+        outcome.0.expect("Bus closed")
+    }
+
+    /// Collects every command frame the module writes until it has been quiet for a while
+    fn read_commands(&mut self) -> Vec<(u8, i32, i32)> {
+        self.runtime
+            .block_on(
+                self.firmware
+                    .by_ref()
+                    .timeout(QUIET_PERIOD)
+                    .then(|result| match result {
+                        Ok(frame) => Ok(Some(frame)),
+                        Err(ref err) if err.is_elapsed() => Ok(None),
+                        Err(err) => Err(err),
+                    })
+                    .take_while(|frame| Ok(frame.is_some()))
+                    .filter_map(|frame| frame)
+                    .collect(),
+            )
+            .expect("Failed to read from pty")
+    }
+}
+
+#[test]
+fn decodes_status_frames() {
+    let mut harness = Harness::new();
+    let statuses = vec![
+        (100, HardwareStatus::Ready),
+        (101, HardwareStatus::NotLoaded),
+        (102, HardwareStatus::MagazineReleased),
+        (103, HardwareStatus::Reloading),
+        (104, HardwareStatus::HomingRequired),
+        (105, HardwareStatus::Homing),
+        (106, HardwareStatus::MotorsOff),
+        (107, HardwareStatus::HomingFailed),
+        (42, HardwareStatus::Error),
+    ];
+
+    for (i, (byte, status)) in statuses.into_iter().enumerate() {
+        let (pitch_pos, yaw_pos) = (i as u32 * 100, 0x0102_0304 + i as u32);
+        harness.write_raw(&status_frame(byte, pitch_pos, yaw_pos));
+        assert_eq!(harness.next_hardware_state(), (status, pitch_pos, yaw_pos));
+    }
+}
+
+#[test]
+fn resyncs_after_leading_garbage() {
+    let mut harness = Harness::new();
+    let mut bytes = vec![0xFF, 0x00, 0x13, 0x37, 0xC8];
+    bytes.extend_from_slice(&status_frame(100, 1234, 5678));
+    harness.write_raw(&bytes);
+
+    assert_eq!(
+        harness.next_hardware_state(),
+        (HardwareStatus::Ready, 1234, 5678)
+    );
+}
+
+#[test]
+fn resyncs_after_crc_mismatch() {
+    let mut harness = Harness::new();
+    let mut corrupt = status_frame(101, 1, 2);
+    corrupt[5] ^= 0x40;
+    let mut bytes = corrupt.to_vec();
+    bytes.extend_from_slice(&status_frame(105, 300, 400));
+    bytes.extend_from_slice(&status_frame(100, 500, 600));
+    harness.write_raw(&bytes);
+
+    assert_eq!(
+        harness.next_hardware_state(),
+        (HardwareStatus::Homing, 300, 400)
+    );
+    assert_eq!(
+        harness.next_hardware_state(),
+        (HardwareStatus::Ready, 500, 600)
+    );
+}
+
+#[test]
+fn resyncs_across_split_writes() {
+    let mut harness = Harness::new();
+    let mut bytes = vec![0x55, 0xAA];
+    bytes.extend_from_slice(&status_frame(106, 7, 8));
+    let (first, second) = bytes.split_at(6);
+    harness.write_raw(first);
+    harness.write_raw(second);
+
+    assert_eq!(
+        harness.next_hardware_state(),
+        (HardwareStatus::MotorsOff, 7, 8)
+    );
+}
+
+#[test]
+fn encodes_every_command() {
+    let mut harness = Harness::new();
+    let commands = vec![
+        Command::Move {
+            pitch: 0.5,
+            yaw: -0.25,
+        },
+        Command::Home,
+        Command::ReleaseMagazine,
+        Command::LoadMagazine,
+        Command::Reload,
+        Command::Fire,
+        Command::FireAndReload,
+        Command::MotorsOn,
+        Command::MotorsOff,
+    ];
+    for command in commands {
+        harness.send_command(command);
+    }
+
+    assert_eq!(
+        harness.read_commands(),
+        vec![
+            (200, PITCH_MAX_SPEED as i32 / 2, -(YAW_MAX_SPEED as i32) / 4),
+            (201, PITCH_HOMING_SPEED as i32, YAW_HOMING_SPEED as i32),
+            (202, 0, 0),
+            (203, 0, 0),
+            (204, 0, 0),
+            (205, 0, 0),
+            (206, 0, 0),
+            (207, 0, 0),
+            (208, 0, 0),
+        ]
+    );
+}
+
+#[test]
+fn encodes_move_to() {
+    let mut harness = Harness::new();
+    harness.send_command(Command::MoveTo {
+        pitch: 1200,
+        yaw: 70000,
+    });
+
+    // Targets are clamped to 16 bits and packed together with the max speeds
+    assert_eq!(
+        harness.read_commands(),
+        vec![(
+            209,
+            (1200 << 16) | 0xFFFF,
+            (PITCH_MAX_SPEED << 16 | YAW_MAX_SPEED) as i32
+        )]
+    );
+}
+
+#[test]
+fn ignores_clients_behind_the_head_of_the_queue() {
+    let mut harness = Harness::new();
+    harness
+        .bus_sink
+        .unbounded_send(Message {
+            content: MessageContent::Command(Command::Fire),
+            source: MessageSource::Client(Client {
+                address: SocketAddr::from(([127, 0, 0, 1], 5001)),
+                queue_position: 1,
+            }),
+        })
+        .unwrap();
+    harness.send_command(Command::Home);
+
+    let commands = harness.read_commands();
+    assert_eq!(commands.len(), 1);
+    assert_eq!(commands[0].0, 201);
+}
+
+#[test]
+fn rate_limits_commands() {
+    let mut harness = Harness::new();
+    for _ in 0..20 {
+        harness.send_command(Command::Fire);
+    }
+    // The 10th command within 100ms and everything after it is dropped
+    assert_eq!(harness.read_commands().len(), 9);
+
+    // The window has passed by now, so commands are accepted again
+    harness.send_command(Command::MotorsOn);
+    assert_eq!(harness.read_commands(), vec![(207, 0, 0)]);
+}
+
+#[test]
+fn reports_command_outcomes() {
+    let mut harness = Harness::new();
+    harness
+        .bus_sink
+        .unbounded_send(Message {
+            content: MessageContent::Command(Command::Fire),
+            source: MessageSource::Client(Client {
+                address: SocketAddr::from(([127, 0, 0, 1], 5001)),
+                queue_position: 1,
+            }),
+        })
+        .unwrap();
+    for _ in 0..10 {
+        harness.send_command(Command::Reload);
+    }
+
+    let outcomes = harness
+        .runtime
+        .block_on(
+            harness
+                .bus_stream
+                .by_ref()
+                .filter_map(|message| match message.content {
+                    MessageContent::CommandProcessed {
+                        command, outcome, ..
+                    } => Some((command, outcome)),
+                    _ => None,
+                })
+                .take(11)
+                .collect()
+                .map_err(|_| ())
+                .timeout(QUIET_PERIOD),
+        )
+        .expect("Timed out waiting for command outcomes");
+
+    let mut expected = vec![(Command::Fire, CommandOutcome::Ignored)];
+    expected.extend(vec![(Command::Reload, CommandOutcome::Forwarded); 9]);
+    expected.push((Command::Reload, CommandOutcome::RateLimited));
+    assert_eq!(outcomes, expected);
+}
+
+#[test]
+fn ignores_idle_joystick_while_another_module_moves() {
+    let mut harness = Harness::new();
+    let stop = Command::Move {
+        pitch: 0.0,
+        yaw: 0.0,
+    };
+    let turn = Command::Move {
+        pitch: 0.0,
+        yaw: 0.5,
+    };
+    harness.send_command(stop.clone());
+    harness
+        .bus_sink
+        .unbounded_send(Message {
+            content: MessageContent::Command(turn.clone()),
+            source: MessageSource::Tracking,
+        })
+        .unwrap();
+    harness.send_command(stop.clone());
+    harness.send_command(turn);
+    harness.send_command(stop);
+
+    let half_yaw = YAW_MAX_SPEED as i32 / 2;
+    assert_eq!(
+        harness.read_commands(),
+        vec![
+            (200, 0, 0),
+            (200, 0, half_yaw),
+            (200, 0, half_yaw),
+            (200, 0, 0)
+        ]
+    );
+}
+
+#[test]
+fn refuses_to_fire_inside_no_fire_zones() {
+    let mut harness = Harness::with_config(
+        r#"
+        [[no_fire_zones]]
+        name = "door"
+        units = "steps"
+        pitch_min = 0.0
+        pitch_max = 1000.0
+        yaw_min = 0.0
+        yaw_max = 1000.0
+        "#,
+    );
+
+    let blocked = |reason: &str| CommandOutcome::Blocked {
+        reason: reason.to_string(),
+    };
+
+    // Where the turret is pointing is unknown until it has been homed
+    harness.send_command(Command::Fire);
+    assert_eq!(
+        harness.next_outcome(),
+        (
+            Command::Fire,
+            blocked("the turret's position is unknown until it is homed")
+        )
+    );
+
+    harness.write_raw(&status_frame(100, 100, 2000));
+    harness.next_hardware_state();
+    harness.send_command(Command::Fire);
+    assert_eq!(
+        harness.next_outcome(),
+        (Command::Fire, CommandOutcome::Forwarded)
+    );
+
+    harness.write_raw(&status_frame(100, 100, 500));
+    harness.next_hardware_state();
+    harness.send_command(Command::FireAndReload);
+    assert_eq!(
+        harness.next_outcome(),
+        (
+            Command::FireAndReload,
+            blocked("the turret is inside no-fire zone \"door\"")
+        )
+    );
+    harness.send_command(Command::Reload);
+    assert_eq!(
+        harness.next_outcome(),
+        (Command::Reload, CommandOutcome::Forwarded)
+    );
+
+    assert_eq!(harness.read_commands(), vec![(205, 0, 0), (204, 0, 0)]);
+}
+
+#[test]
+fn refuses_to_fire_with_motors_off_until_homed() {
+    let mut harness = Harness::with_config(
+        r#"
+        [[no_fire_zones]]
+        name = "door"
+        units = "steps"
+        pitch_min = 0.0
+        pitch_max = 1000.0
+        yaw_min = 0.0
+        yaw_max = 1000.0
+        "#,
+    );
+    let unknown = CommandOutcome::Blocked {
+        reason: "the turret's position is unknown until it is homed".to_string(),
+    };
+
+    // The firmware reports its motors being off at power-up, before it has been homed
+    harness.write_raw(&status_frame(106, 0, 0));
+    harness.next_hardware_state();
+    harness.send_command(Command::Fire);
+    assert_eq!(harness.next_outcome(), (Command::Fire, unknown.clone()));
+
+    // Once homed, turning the motors off doesn't lose the position
+    harness.write_raw(&status_frame(100, 100, 2000));
+    harness.next_hardware_state();
+    harness.write_raw(&status_frame(106, 100, 2000));
+    harness.next_hardware_state();
+    harness.send_command(Command::Fire);
+    assert_eq!(
+        harness.next_outcome(),
+        (Command::Fire, CommandOutcome::Forwarded)
+    );
+
+    harness.write_raw(&status_frame(104, 0, 0));
+    harness.next_hardware_state();
+    harness.send_command(Command::FireAndReload);
+    assert_eq!(harness.next_outcome(), (Command::FireAndReload, unknown));
+
+    assert_eq!(harness.read_commands(), vec![(205, 0, 0)]);
+}
+
+#[test]
+fn keeps_moves_within_soft_limits() {
+    let mut harness = Harness::with_config(
+        r#"
+        [arduino.yaw_limits]
+        max_degrees = 90.0
+        max_speed = 2000
+        "#,
+    );
+
+    harness.send_command(Command::Move {
+        pitch: 0.0,
+        yaw: 1.0,
+    });
+    harness.send_command(Command::MoveTo {
+        pitch: 100,
+        yaw: 5000,
+    });
+    harness.send_command(Command::Move {
+        pitch: 0.0,
+        yaw: 0.5,
+    });
+    // 90 degrees is 2100 steps along the yaw axis
+    assert_eq!(
+        harness.read_commands(),
+        vec![
+            (200, 0, 2000),
+            (
+                209,
+                (100 << 16) | 2100,
+                (PITCH_MAX_SPEED << 16 | 2000) as i32
+            ),
+            (200, 0, 2000),
+        ]
+    );
+
+    // The move in progress is stopped once the turret reports reaching the limit
+    harness.write_raw(&status_frame(100, 0, 2100));
+    assert_eq!(
+        harness.next_correction(),
+        (
+            Command::Move {
+                pitch: 0.0,
+                yaw: 0.0
+            },
+            CommandOutcome::Forwarded
+        )
+    );
+    assert_eq!(harness.read_commands(), vec![(200, 0, 0)]);
+}
+
+#[test]
+fn forwards_limit_corrections_while_rate_limited() {
+    let mut harness = Harness::with_config(
+        r#"
+        [arduino.yaw_limits]
+        max_degrees = 90.0
+        "#,
+    );
+    harness.send_command(Command::Move {
+        pitch: 0.0,
+        yaw: 0.5,
+    });
+    for _ in 0..10 {
+        harness.send_command(Command::Reload);
+    }
+    assert_eq!(
+        harness.next_outcome(),
+        (
+            Command::Move {
+                pitch: 0.0,
+                yaw: 0.5
+            },
+            CommandOutcome::Forwarded
+        )
+    );
+    let outcomes: Vec<_> = (0..10).map(|_| harness.next_outcome().1).collect();
+    assert_eq!(outcomes.last(), Some(&CommandOutcome::RateLimited));
+
+    // Reaching the limit while clients flood the serial connection still stops the turret
+    harness.write_raw(&status_frame(100, 0, 2100));
+    assert_eq!(
+        harness.next_correction(),
+        (
+            Command::Move {
+                pitch: 0.0,
+                yaw: 0.0
+            },
+            CommandOutcome::Forwarded
+        )
+    );
+    let commands = harness.read_commands();
+    assert_eq!(commands.last(), Some(&(200, 0, 0)));
+}
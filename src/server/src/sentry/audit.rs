@@ -0,0 +1,451 @@
+use crate::sentry::config::{self, Config};
+use crate::sentry::{
+    Bus, Command, CommandOutcome, HardwareStatus, Message, MessageContent, MessageSource,
+};
+use chrono::{SecondsFormat, Utc};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use tokio::prelude::*;
+
+/// An append-only file that is rotated to `<path>.1`, `<path>.2`, ... when it grows too large
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: u32,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, max_files: u32) -> Result<Self, String> {
+        let file = Self::open_file(&path)?;
+        let size = file
+            .metadata()
+            .map_err(|err| format!("Could not read size of \"{}\": {}", path.display(), err))?
+            .len();
+        Ok(RotatingFile {
+            path,
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn open_file(path: &Path) -> Result<File, String> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| format!("Could not open \"{}\": {}", path.display(), err))
+    }
+
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        PathBuf::from(format!("{}.{}", self.path.display(), index))
+    }
+
+    fn rotate(&mut self) -> Result<(), String> {
+        // Shift every rotated file up by one, dropping the oldest
+        for index in (1..self.max_files).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(index + 1))
+                    .map_err(|err| format!("Could not rotate \"{}\": {}", from.display(), err))?;
+            }
+        }
+        if self.max_files > 0 {
+            fs::rename(&self.path, self.rotated_path(1))
+        } else {
+            fs::remove_file(&self.path)
+        }
+        .map_err(|err| format!("Could not rotate \"{}\": {}", self.path.display(), err))?;
+
+        self.file = Self::open_file(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<(), String> {
+        let line = format!("{}\n", line);
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file
+            .write_all(line.as_bytes())
+            .map_err(|err| format!("Could not write to \"{}\": {}", self.path.display(), err))?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+fn source_name(source: &MessageSource) -> &'static str {
+    match source {
+        MessageSource::Arduino => "arduino",
+        MessageSource::WebsocketServer => "server",
+        MessageSource::VideoServer => "video",
+        MessageSource::Presets => "presets",
+        MessageSource::Patrol => "patrol",
+        MessageSource::Tracking => "tracking",
+        MessageSource::Hotplug => "hotplug",
+        MessageSource::Client(_) => "client",
+    }
+}
+
+fn client_address(source: &MessageSource) -> Option<SocketAddr> {
+    match source {
+        MessageSource::Client(client) => Some(client.address),
+        _ => None,
+    }
+}
+
+fn command_entry(command: &Command, source: &MessageSource) -> Value {
+    let client = match source {
+        MessageSource::Client(client) => Some(client),
+        _ => None,
+    };
+    json!({
+        "event": "command",
+        "command": command,
+        "source": source_name(source),
+        "client": client.map(|client| client.address),
+        "queue_position": client.map(|client| client.queue_position),
+    })
+}
+
+fn outcome_name(outcome: &CommandOutcome) -> &'static str {
+    match outcome {
+        CommandOutcome::Forwarded => "forwarded",
+        CommandOutcome::RateLimited => "rate_limited",
+        CommandOutcome::Ignored => "ignored",
+        CommandOutcome::Blocked { .. } => "blocked",
+    }
+}
+
+/// Number of commands kept waiting for their outcome, beyond which the oldest are forgotten.
+/// Commands only get an outcome while the arduino module is running.
+const MAX_PENDING_COMMANDS: usize = 64;
+
+/// A command that has been logged, but whose outcome hasn't been yet
+struct PendingCommand {
+    id: u64,
+    command: Command,
+    source: &'static str,
+    client: Option<SocketAddr>,
+}
+
+struct AuditLog {
+    file: RotatingFile,
+    last_status: Option<HardwareStatus>,
+    next_command_id: u64,
+    pending_commands: VecDeque<PendingCommand>,
+}
+
+impl AuditLog {
+    fn new(file: RotatingFile) -> Self {
+        AuditLog {
+            file,
+            last_status: None,
+            next_command_id: 0,
+            pending_commands: VecDeque::new(),
+        }
+    }
+
+    /// Returns the log entry for a bus message, if it is one we keep a record of
+    fn entry(&mut self, message: Message) -> Option<Value> {
+        match message.content {
+            // Commands are logged as soon as they are sent, so there is a record of them even if
+            // nothing processes them, and their outcome is logged with the same id once it's known
+            MessageContent::Command(command) => {
+                let id = self.next_command_id;
+                self.next_command_id += 1;
+                let mut entry = command_entry(&command, &message.source);
+                entry["id"] = json!(id);
+                if self.pending_commands.len() >= MAX_PENDING_COMMANDS {
+                    self.pending_commands.pop_front();
+                }
+                self.pending_commands.push_back(PendingCommand {
+                    id,
+                    command,
+                    source: source_name(&message.source),
+                    client: client_address(&message.source),
+                });
+                Some(entry)
+            }
+            MessageContent::CommandProcessed {
+                command,
+                source,
+                outcome,
+            } => {
+                let (name, client) = (source_name(&source), client_address(&source));
+                let pending = self.pending_commands.iter().position(|pending| {
+                    pending.command == command && pending.source == name && pending.client == client
+                });
+                match pending.and_then(|index| self.pending_commands.remove(index)) {
+                    Some(pending) => Some(json!({
+                        "event": "command_outcome",
+                        "id": pending.id,
+                        "outcome": outcome_name(&outcome),
+                    })),
+                    // Commands that were never sent on the bus are logged along with their outcome
+                    None => {
+                        let mut entry = command_entry(&command, &source);
+                        entry["outcome"] = json!(outcome_name(&outcome));
+                        Some(entry)
+                    }
+                }
+            }
+            MessageContent::HardwareState {
+                pitch_pos,
+                yaw_pos,
+                status,
+                ..
+            } => {
+                if self.last_status.as_ref() == Some(&status) {
+                    return None;
+                }
+                let entry = json!({
+                    "event": "status",
+                    "from": self.last_status.as_ref().map(|status| status.name()),
+                    "to": status.name(),
+                    "pitch": pitch_pos,
+                    "yaw": yaw_pos,
+                });
+                self.last_status = Some(status);
+                Some(entry)
+            }
+            MessageContent::ClientConnected(client) => Some(json!({
+                "event": "client_connected",
+                "client": client.address,
+                "queue_position": client.queue_position,
+            })),
+            MessageContent::ClientDisconnected(client) => Some(json!({
+                "event": "client_disconnected",
+                "client": client.address,
+            })),
+            MessageContent::DeviceConnected(device) => Some(json!({
+                "event": "device_connected",
+                "device": device.name(),
+            })),
+            MessageContent::DeviceDisconnected(device) => Some(json!({
+                "event": "device_disconnected",
+                "device": device.name(),
+            })),
+            _ => None,
+        }
+    }
+
+    fn record(&mut self, message: Message) -> Result<(), String> {
+        if let Some(mut entry) = self.entry(message) {
+            entry["time"] = json!(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true));
+            self.file.write_line(&entry.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+pub fn start(config: Config, bus: Bus<Message>) -> impl Future<Item = (), Error = String> {
+    let (_, bus_stream) = bus;
+
+    future::result(config::file_path(&config.audit.path))
+        .and_then(move |path| {
+            info!("Writing audit log to \"{}\"", path.display());
+            RotatingFile::open(path, config.audit.max_size, config.audit.max_files)
+        })
+        .and_then(|file| {
+            let mut log = AuditLog::new(file);
+            bus_stream
+                .map_err(|_| "Failed to read from bus".to_string())
+                .for_each(move |message| log.record(message))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sentry::{Client, Device};
+    use std::env;
+
+    fn log(name: &str) -> AuditLog {
+        let path = env::temp_dir().join(format!("sentry-audit-{}.log", name));
+        let _ = fs::remove_file(&path);
+        AuditLog::new(RotatingFile::open(path, 1024 * 1024, 0).unwrap())
+    }
+
+    fn client(queue_position: usize) -> MessageSource {
+        MessageSource::Client(Client {
+            address: SocketAddr::from(([127, 0, 0, 1], 5000)),
+            queue_position,
+        })
+    }
+
+    fn message(content: MessageContent, source: MessageSource) -> Message {
+        Message { content, source }
+    }
+
+    fn processed(command: Command, source: MessageSource, outcome: CommandOutcome) -> Message {
+        message(
+            MessageContent::CommandProcessed {
+                command,
+                source,
+                outcome,
+            },
+            MessageSource::Arduino,
+        )
+    }
+
+    fn state(status: HardwareStatus) -> Message {
+        message(
+            MessageContent::HardwareState {
+                pitch_pos: 10,
+                yaw_pos: 20,
+                status,
+                homed: true,
+            },
+            MessageSource::Arduino,
+        )
+    }
+
+    #[test]
+    fn logs_commands_on_receipt_and_their_outcome() {
+        let mut log = log("commands");
+
+        let entry = log.entry(message(MessageContent::Command(Command::Fire), client(0)));
+        assert_eq!(
+            entry,
+            Some(json!({
+                "event": "command",
+                "id": 0,
+                "command": { "command": "fire" },
+                "source": "client",
+                "client": "127.0.0.1:5000",
+                "queue_position": 0,
+            }))
+        );
+        let entry = log.entry(message(
+            MessageContent::Command(Command::Home),
+            MessageSource::Patrol,
+        ));
+        assert_eq!(entry.as_ref().map(|entry| &entry["id"]), Some(&json!(1)));
+        assert_eq!(
+            entry.as_ref().map(|entry| &entry["source"]),
+            Some(&json!("patrol"))
+        );
+        assert_eq!(
+            entry.as_ref().map(|entry| &entry["client"]),
+            Some(&Value::Null)
+        );
+
+        // Outcomes are matched with their command, whatever order they arrive in
+        assert_eq!(
+            log.entry(processed(
+                Command::Home,
+                MessageSource::Patrol,
+                CommandOutcome::RateLimited
+            )),
+            Some(json!({ "event": "command_outcome", "id": 1, "outcome": "rate_limited" }))
+        );
+        assert_eq!(
+            log.entry(processed(
+                Command::Fire,
+                client(0),
+                CommandOutcome::Blocked {
+                    reason: "not homed".to_string()
+                }
+            )),
+            Some(json!({ "event": "command_outcome", "id": 0, "outcome": "blocked" }))
+        );
+
+        // A command whose receipt wasn't logged still gets an entry with its outcome
+        assert_eq!(
+            log.entry(processed(Command::Fire, client(1), CommandOutcome::Ignored)),
+            Some(json!({
+                "event": "command",
+                "command": { "command": "fire" },
+                "source": "client",
+                "client": "127.0.0.1:5000",
+                "queue_position": 1,
+                "outcome": "ignored",
+            }))
+        );
+    }
+
+    #[test]
+    fn logs_status_transitions() {
+        let mut log = log("status");
+        assert_eq!(
+            log.entry(state(HardwareStatus::HomingRequired)),
+            Some(json!({
+                "event": "status",
+                "from": null,
+                "to": "homing_required",
+                "pitch": 10,
+                "yaw": 20,
+            }))
+        );
+        assert_eq!(log.entry(state(HardwareStatus::HomingRequired)), None);
+        assert_eq!(
+            log.entry(state(HardwareStatus::Homing))
+                .map(|entry| (entry["from"].clone(), entry["to"].clone())),
+            Some((json!("homing_required"), json!("homing")))
+        );
+    }
+
+    #[test]
+    fn logs_connections() {
+        let mut log = log("connections");
+        let client = Client {
+            address: SocketAddr::from(([127, 0, 0, 1], 5000)),
+            queue_position: 2,
+        };
+        assert_eq!(
+            log.entry(message(
+                MessageContent::ClientConnected(client.clone()),
+                MessageSource::WebsocketServer
+            )),
+            Some(json!({
+                "event": "client_connected",
+                "client": "127.0.0.1:5000",
+                "queue_position": 2,
+            }))
+        );
+        assert_eq!(
+            log.entry(message(
+                MessageContent::ClientDisconnected(client),
+                MessageSource::WebsocketServer
+            )),
+            Some(json!({ "event": "client_disconnected", "client": "127.0.0.1:5000" }))
+        );
+        assert_eq!(
+            log.entry(message(
+                MessageContent::DeviceDisconnected(Device::Camera),
+                MessageSource::Hotplug
+            )),
+            Some(json!({ "event": "device_disconnected", "device": "camera" }))
+        );
+    }
+
+    #[test]
+    fn rotates_when_full() {
+        let dir = env::temp_dir().join("sentry-audit-rotation");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+
+        // Every line is 10 bytes including the newline, so each file fits two
+        let mut file = RotatingFile::open(path.clone(), 25, 2).unwrap();
+        for i in 0..7 {
+            file.write_line(&format!("line {:04}", i)).unwrap();
+        }
+
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("audit.log"), "line 0006\n");
+        assert_eq!(read("audit.log.1"), "line 0004\nline 0005\n");
+        assert_eq!(read("audit.log.2"), "line 0002\nline 0003\n");
+        assert!(!dir.join("audit.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,6 +1,6 @@
 use futures::sync::mpsc::{unbounded, SendError, UnboundedReceiver, UnboundedSender};
 use futures::{Poll, Sink, StartSend, Stream};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use tokio::prelude::Async;
 
 pub struct BusSender<T: Clone> {
@@ -42,25 +42,38 @@ impl<T: Clone> Clone for BusSender<T> {
     }
 }
 
+type Clones<T> = Arc<Mutex<Vec<UnboundedSender<T>>>>;
+
 pub struct BusReceiver<T: Clone> {
     receiver: UnboundedReceiver<T>,
-    clones: Mutex<Vec<UnboundedSender<T>>>,
+    clones: Clones<T>,
 }
 
 impl<T: Clone> BusReceiver<T> {
     fn new(receiver: UnboundedReceiver<T>) -> Self {
         BusReceiver {
             receiver,
-            clones: Mutex::new(Vec::new()),
+            clones: Arc::new(Mutex::new(Vec::new())),
         }
     }
+
+    fn clone_from(clones: &Clones<T>) -> Self {
+        let (sender, receiver) = unbounded::<T>();
+        clones.lock().unwrap().push(sender);
+        BusReceiver::new(receiver)
+    }
+
+    /// Returns a function that clones this receiver, which keeps working after the receiver has
+    /// been moved into whatever polls it
+    pub fn cloner(&self) -> impl Fn() -> BusReceiver<T> + Clone {
+        let clones = self.clones.clone();
+        move || Self::clone_from(&clones)
+    }
 }
 
 impl<T: Clone> Clone for BusReceiver<T> {
     fn clone(&self) -> Self {
-        let (sender, receiver) = unbounded::<T>();
-        self.clones.lock().unwrap().push(sender);
-        BusReceiver::new(receiver)
+        Self::clone_from(&self.clones)
     }
 }
 
@@ -71,10 +84,11 @@ impl<T: Clone> Stream for BusReceiver<T> {
     fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
         let result = self.receiver.poll();
         if let Ok(Async::Ready(Some(msg))) = &result {
-            for sender in self.clones.lock().unwrap().iter() {
-                // TODO handle this
-                sender.unbounded_send(msg.clone());
-            }
+            // Forget clones that have been dropped
+            self.clones
+                .lock()
+                .unwrap()
+                .retain(|sender| sender.unbounded_send(msg.clone()).is_ok());
         }
         result
     }
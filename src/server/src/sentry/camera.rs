@@ -0,0 +1,262 @@
+use glob::Pattern;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Shorthand names for `[camera]` properties, alongside the sysfs attribute they stand for.
+/// There is no `product` alias, since USB devices have a `product` attribute with their name.
+const ALIASES: &[(&str, &str)] = &[("vendor_id", "idVendor"), ("product_id", "idProduct")];
+
+/// A device in sysfs and the attributes it exposes
+pub struct SysfsDevice {
+    pub path: PathBuf,
+    pub attributes: BTreeMap<String, String>,
+}
+
+/// A V4L2 device node, with its own sysfs device followed by each of its parents
+pub struct Camera {
+    pub device: String,
+    pub devices: Vec<SysfsDevice>,
+}
+
+impl Camera {
+    /// Every value of an attribute in the device chain, nearest first
+    fn values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.devices
+            .iter()
+            .filter_map(move |device| device.attributes.get(name))
+            .map(|value| value.as_str())
+    }
+
+    /// The attribute names a `[camera]` property is checked against
+    fn attribute_names(property: &str) -> Vec<&str> {
+        let mut names = vec![property];
+        names.extend(
+            ALIASES
+                .iter()
+                .filter(|&&(alias, _)| alias == property)
+                .map(|&(_, name)| name),
+        );
+        names
+    }
+
+    /// Whether every property glob matches some attribute of the device or one of its parents
+    pub fn matches(&self, properties: &HashMap<String, String>) -> Result<bool, String> {
+        for (property, value) in properties {
+            let pattern = Pattern::new(value).map_err(|err| {
+                format!(
+                    "Invalid pattern \"{}\" for camera property {}: {}",
+                    value, property, err
+                )
+            })?;
+            let found = Self::attribute_names(property)
+                .into_iter()
+                .flat_map(|name| self.values(name))
+                .any(|value| pattern.matches(value));
+            if !found {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// The value of a shorthand property, if the device has it
+    pub fn property<'a>(&'a self, property: &'a str) -> Option<&'a str> {
+        Self::attribute_names(property)
+            .into_iter()
+            .rev()
+            .flat_map(|name| self.values(name))
+            .next()
+    }
+}
+
+fn read_attributes(path: &Path) -> BTreeMap<String, String> {
+    let mut attributes = BTreeMap::new();
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return attributes,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        // Attributes are plain files, subdirectories and links are other devices
+        let is_file = entry
+            .file_type()
+            .map(|file_type| file_type.is_file())
+            .unwrap_or(false);
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if !is_file || name == "uevent" {
+            continue;
+        }
+        // Some attributes are write-only or binary, and can't be matched against anyway
+        if let Some(value) = fs::read(entry.path())
+            .ok()
+            .and_then(|contents| String::from_utf8(contents).ok())
+        {
+            attributes.insert(name, value.trim().to_string());
+        }
+    }
+    attributes
+}
+
+fn read_camera(sysfs: &Path, name: &str) -> Result<Camera, String> {
+    let class_path = sysfs.join("class/video4linux").join(name);
+    let mut path = fs::canonicalize(&class_path)
+        .map_err(|err| format!("Could not resolve \"{}\": {}", class_path.display(), err))?;
+    let root = fs::canonicalize(sysfs.join("devices")).unwrap_or_else(|_| sysfs.join("devices"));
+
+    let mut devices = Vec::new();
+    while path.starts_with(&root) && path != root {
+        // Directories without a uevent file are only there to group devices
+        if path.join("uevent").exists() {
+            devices.push(SysfsDevice {
+                attributes: read_attributes(&path),
+                path: path.clone(),
+            });
+        }
+        path.pop();
+    }
+    Ok(Camera {
+        device: format!("/dev/{}", name),
+        devices,
+    })
+}
+
+fn list_in(sysfs: &Path) -> Result<Vec<Camera>, String> {
+    let class = sysfs.join("class/video4linux");
+    if !class.exists() {
+        return Ok(Vec::new());
+    }
+    let mut names = fs::read_dir(&class)
+        .map_err(|err| format!("Could not list \"{}\": {}", class.display(), err))?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect::<Vec<_>>();
+    // Sort by number so video2 comes before video10
+    names.sort_by_key(|name| {
+        let number = name.trim_start_matches(|c: char| !c.is_ascii_digit());
+        (number.parse::<u32>().unwrap_or(u32::MAX), name.clone())
+    });
+    names.iter().map(|name| read_camera(sysfs, name)).collect()
+}
+
+/// Returns every V4L2 device on the system
+pub fn list() -> Result<Vec<Camera>, String> {
+    list_in(Path::new("/sys"))
+}
+
+/// Returns the device node of the first V4L2 device matching all of the `[camera]` properties
+pub fn find(properties: &HashMap<String, String>) -> Result<Option<String>, String> {
+    for camera in list()? {
+        if camera.matches(properties)? {
+            return Ok(Some(camera.device));
+        }
+    }
+    Ok(None)
+}
+
+/// Prints every V4L2 device with its attributes, to help write the `[camera]` table
+pub fn print_cameras() -> Result<(), String> {
+    let cameras = list()?;
+    if cameras.is_empty() {
+        println!("No V4L2 devices found");
+    }
+    for camera in cameras {
+        println!("{}", camera.device);
+        for &property in &["name", "vendor_id", "product_id", "product", "serial"] {
+            if let Some(value) = camera.property(property) {
+                println!("  {} = {:?}", property, value);
+            }
+        }
+        for device in &camera.devices {
+            println!();
+            println!("  {}", device.path.display());
+            for (name, value) in &device.attributes {
+                println!("    {} = {:?}", name, value);
+            }
+        }
+        println!();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::symlink;
+
+    fn write_device(path: &Path, attributes: &[(&str, &str)]) {
+        fs::create_dir_all(path).unwrap();
+        fs::write(path.join("uevent"), "").unwrap();
+        for (name, value) in attributes {
+            fs::write(path.join(name), format!("{}\n", value)).unwrap();
+        }
+    }
+
+    #[test]
+    fn finds_cameras_by_parent_attributes() {
+        let sysfs = env::temp_dir().join("sentry-camera-sysfs");
+        let _ = fs::remove_dir_all(&sysfs);
+        let usb = sysfs.join("devices/pci0000:00/usb1/1-1");
+        write_device(
+            &usb,
+            &[
+                ("idVendor", "046d"),
+                ("idProduct", "0826"),
+                ("product", "HD Webcam C525"),
+                ("serial", "A1B2C3"),
+            ],
+        );
+        write_device(&usb.join("1-1:1.0"), &[("bInterfaceClass", "0e")]);
+        for &(name, index) in &[("video0", "0"), ("video1", "1")] {
+            let node = usb.join("1-1:1.0/video4linux").join(name);
+            write_device(&node, &[("name", "HD Webcam C525"), ("index", index)]);
+        }
+        let class = sysfs.join("class/video4linux");
+        fs::create_dir_all(&class).unwrap();
+        symlink(usb.join("1-1:1.0/video4linux/video1"), class.join("video1")).unwrap();
+        symlink(usb.join("1-1:1.0/video4linux/video0"), class.join("video0")).unwrap();
+
+        let cameras = list_in(&sysfs).unwrap();
+        assert_eq!(cameras.len(), 2);
+        let camera = &cameras[0];
+        assert_eq!(camera.device, "/dev/video0");
+        assert_eq!(camera.devices.len(), 3);
+        assert!(!camera.devices[0].attributes.contains_key("uevent"));
+        assert_eq!(camera.property("vendor_id"), Some("046d"));
+        assert_eq!(camera.property("product_id"), Some("0826"));
+        assert_eq!(camera.property("product"), Some("HD Webcam C525"));
+        assert_eq!(camera.property("name"), Some("HD Webcam C525"));
+
+        let matches = |properties: &[(&str, &str)]| {
+            camera.matches(
+                &properties
+                    .iter()
+                    .map(|&(property, value)| (property.to_string(), value.to_string()))
+                    .collect(),
+            )
+        };
+        assert_eq!(matches(&[]), Ok(true));
+        assert_eq!(
+            matches(&[("vendor_id", "046d"), ("product_id", "0826")]),
+            Ok(true)
+        );
+        assert_eq!(
+            matches(&[("idVendor", "046d"), ("serial", "A1*")]),
+            Ok(true)
+        );
+        assert_eq!(matches(&[("product", "HD Webcam C5??")]), Ok(true));
+        assert_eq!(matches(&[("product", "0826")]), Ok(false));
+        assert_eq!(matches(&[("name", "*C525"), ("index", "0")]), Ok(true));
+        assert_eq!(
+            matches(&[("vendor_id", "046d"), ("serial", "Z*")]),
+            Ok(false)
+        );
+        assert_eq!(matches(&[("driver", "*")]), Ok(false));
+        assert!(matches(&[("serial", "[")]).is_err());
+
+        fs::remove_dir_all(&sysfs).unwrap();
+    }
+}
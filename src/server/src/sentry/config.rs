@@ -1,6 +1,90 @@
 use std::collections::HashMap;
 use std::env;
 use std::fs;
+use std::path::PathBuf;
+
+/// Full steps per revolution of the stepper motors, before microstepping
+const MOTOR_STEPS_PER_REV: f64 = 200.0;
+/// PITCH_ACCEL from the firmware's config.h, in steps per second squared
+pub const PITCH_ACCEL: f64 = 18000.0;
+/// YAW_ACCEL from the firmware's config.h, in steps per second squared
+pub const YAW_ACCEL: f64 = 12000.0;
+
+/// Mechanical properties of a turret axis, matching the firmware's config.h
+#[derive(Clone, Deserialize)]
+pub struct AxisConfig {
+    pub gear_ratio: f64,
+    pub microsteps: u32,
+    pub min_degrees: f64,
+    pub max_degrees: f64,
+}
+
+impl AxisConfig {
+    /// PITCH_* values from the firmware's config.h
+    fn default_pitch() -> Self {
+        AxisConfig {
+            gear_ratio: 72.0 / 8.0,
+            microsteps: 4,
+            min_degrees: -72.0,
+            max_degrees: 53.0,
+        }
+    }
+
+    /// YAW_* values from the firmware's config.h
+    fn default_yaw() -> Self {
+        AxisConfig {
+            gear_ratio: 84.0 / 8.0,
+            microsteps: 4,
+            min_degrees: 0.0,
+            max_degrees: 352.0,
+        }
+    }
+
+    pub fn steps_per_degree(&self) -> f64 {
+        self.gear_ratio * f64::from(self.microsteps) * MOTOR_STEPS_PER_REV / 360.0
+    }
+
+    /// Converts a position reported by the arduino into degrees
+    pub fn to_degrees(&self, steps: u32) -> f64 {
+        self.min_degrees + f64::from(steps) / self.steps_per_degree()
+    }
+
+    /// Converts an angle into an arduino position, clamped to the axis limits
+    pub fn to_steps(&self, degrees: f64) -> u32 {
+        let degrees = degrees.max(self.min_degrees).min(self.max_degrees);
+        ((degrees - self.min_degrees) * self.steps_per_degree()).round() as u32
+    }
+}
+
+/// Restricts where an axis may travel and how fast, beyond what the firmware allows, so one
+/// installation can be limited without reflashing it
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AxisLimits {
+    /// Defaults to the axis' own limit
+    pub min_degrees: Option<f64>,
+    pub max_degrees: Option<f64>,
+    /// Top speed in steps per second
+    pub max_speed: Option<u32>,
+    /// Steps per second squared the axis slows down at, which defaults to the firmware's
+    /// PITCH_ACCEL or YAW_ACCEL. Moves toward a limit are slowed in time to stop at it. 0 turns
+    /// this off, so moves are only cut off once the limit has been reached.
+    pub deceleration: Option<f64>,
+}
+
+impl AxisLimits {
+    /// How quickly to slow down ahead of a limit, given the firmware's acceleration for the
+    /// axis, unless that has been turned off
+    pub fn deceleration_or(&self, acceleration: f64) -> Option<f64> {
+        Some(self.deceleration.unwrap_or(acceleration)).filter(|&deceleration| deceleration > 0.0)
+    }
+
+    /// Lowers a speed in steps per second to the top speed, if there is one
+    pub fn cap_speed(&self, speed: u32) -> u32 {
+        self.max_speed
+            .map_or(speed, |max_speed| speed.min(max_speed))
+    }
+}
 
 #[derive(Clone, Deserialize)]
 pub struct ArduinoConfig {
@@ -10,33 +94,389 @@ pub struct ArduinoConfig {
     pub yaw_max_speed: u32,
     pub pitch_homing_speed: u32,
     pub yaw_homing_speed: u32,
+    #[serde(default = "AxisConfig::default_pitch")]
+    pub pitch: AxisConfig,
+    #[serde(default = "AxisConfig::default_yaw")]
+    pub yaw: AxisConfig,
+    #[serde(default)]
+    pub pitch_limits: AxisLimits,
+    #[serde(default)]
+    pub yaw_limits: AxisLimits,
+    /// Use a software simulation of the turret instead of the serial device
+    #[serde(default)]
+    pub simulate: bool,
+}
+
+/// What the limits of a no-fire zone are measured in
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ZoneUnits {
+    #[default]
+    Degrees,
+    /// Positions as reported by the arduino
+    Steps,
+}
+
+/// A range of turret positions the turret must never fire from, limits included
+#[derive(Clone, Deserialize)]
+pub struct NoFireZone {
+    pub name: String,
+    #[serde(default)]
+    pub units: ZoneUnits,
+    pub pitch_min: f64,
+    pub pitch_max: f64,
+    pub yaw_min: f64,
+    pub yaw_max: f64,
+}
+
+impl NoFireZone {
+    /// Whether a position reported by the arduino is inside the zone
+    pub fn contains(&self, arduino: &ArduinoConfig, pitch: u32, yaw: u32) -> bool {
+        let (pitch, yaw) = match self.units {
+            ZoneUnits::Degrees => (arduino.pitch.to_degrees(pitch), arduino.yaw.to_degrees(yaw)),
+            ZoneUnits::Steps => (f64::from(pitch), f64::from(yaw)),
+        };
+        (self.pitch_min..=self.pitch_max).contains(&pitch)
+            && (self.yaw_min..=self.yaw_max).contains(&yaw)
+    }
+}
+
+/// Where the video pipeline gets its frames from
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum VideoSource {
+    /// The V4L2 device whose udev attributes match the `camera` table
+    #[default]
+    V4l2,
+    /// GStreamer's test pattern, e.g. "smpte", "ball" or "snow"
+    Test {
+        #[serde(default = "VideoSource::default_pattern")]
+        pattern: String,
+    },
+    /// A file or network stream, e.g. "file:///home/pi/sentry.mp4" or "rtsp://camera.local/stream"
+    Uri { uri: String },
+}
+
+impl VideoSource {
+    fn default_pattern() -> String {
+        "smpte".to_string()
+    }
 }
 
 #[derive(Clone, Deserialize)]
 pub struct VideoConfig {
+    #[serde(default)]
+    pub source: VideoSource,
     pub encoder: String,
     pub decoder: String,
     pub host: String,
+    /// STUN server for WebRTC peers to find their public address with, e.g. "stun://stun.l.google.com:19302"
+    pub stun_server: Option<String>,
+}
+
+/// Certificate and private key for the control channel, as PEM files relative to the executable.
+/// The key must be in PKCS #8 format.
+#[derive(Clone, Deserialize)]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
 }
 
 #[derive(Clone, Deserialize)]
 pub struct TcpServerConfig {
     pub host: String,
     pub port: u16,
+    /// Port for browser clients to connect to over WebSocket, sharing the queue with TCP clients
+    pub websocket_port: Option<u16>,
+    /// Port for clients to send moves to over UDP, alongside their TCP or WebSocket connection
+    pub udp_port: Option<u16>,
+    /// Shared secret clients must present before they join the queue
+    pub secret: Option<String>,
+    /// Tokens for individual users, keyed by user name
+    #[serde(default)]
+    pub users: HashMap<String, String>,
+    /// Accept TLS connections instead of plain TCP
+    pub tls: Option<TlsConfig>,
+}
+
+impl TcpServerConfig {
+    /// Whether clients have to authenticate before they join the queue
+    pub fn requires_auth(&self) -> bool {
+        self.secret.is_some() || !self.users.is_empty()
+    }
+}
+
+/// A position to visit while patrolling, in degrees
+#[derive(Clone, Deserialize)]
+pub struct PatrolPosition {
+    pub pitch: f64,
+    pub yaw: f64,
+    /// Overrides the patrol's dwell time for this position
+    pub dwell: Option<f64>,
+}
+
+/// Sweeps the yaw axis back and forth at a fixed pitch, all in degrees
+#[derive(Clone, Deserialize)]
+pub struct SweepConfig {
+    pub pitch: f64,
+    pub yaw_min: f64,
+    pub yaw_max: f64,
+    pub yaw_step: f64,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct PatrolConfig {
+    /// Seconds to stay at each position
+    pub dwell: f64,
+    #[serde(default)]
+    pub positions: Vec<PatrolPosition>,
+    pub sweep: Option<SweepConfig>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct AuditConfig {
+    /// Path of the audit log, relative to the executable
+    pub path: String,
+    /// Size in bytes at which the log is rotated
+    pub max_size: u64,
+    /// Number of rotated logs to keep
+    pub max_files: u32,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            path: "audit.log".to_string(),
+            max_size: 10 * 1024 * 1024,
+            max_files: 5,
+        }
+    }
+}
+
+/// Container to write recordings in
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordingFormat {
+    #[default]
+    Mp4,
+    Mkv,
+}
+
+impl RecordingFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            RecordingFormat::Mp4 => "mp4",
+            RecordingFormat::Mkv => "mkv",
+        }
+    }
+
+    pub fn muxer(self) -> &'static str {
+        match self {
+            RecordingFormat::Mp4 => "mp4mux",
+            RecordingFormat::Mkv => "matroskamux",
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct RecordingConfig {
+    /// Directory to write recordings to, relative to the executable
+    pub path: String,
+    pub format: RecordingFormat,
+    /// Turns the encoder's output back into something the muxer accepts
+    pub depayloader: String,
+    /// Seconds of video in each file
+    pub segment_duration: u64,
+    /// Total size in bytes of the recordings to keep, deleting the oldest first
+    pub max_size: Option<u64>,
+    /// Seconds to keep recordings for
+    pub max_age: Option<u64>,
+    /// Seconds of video from before a shot to include in its recording
+    pub pre_roll: u64,
+    /// Seconds to keep recording for after a shot
+    pub fire_duration: u64,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        RecordingConfig {
+            path: "recordings".to_string(),
+            format: RecordingFormat::Mp4,
+            depayloader: "rtph264depay ! h264parse".to_string(),
+            segment_duration: 60,
+            max_size: Some(4 * 1024 * 1024 * 1024),
+            max_age: None,
+            pre_roll: 5,
+            fire_duration: 10,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct SnapshotConfig {
+    /// Directory to save snapshots to, relative to the executable
+    pub path: String,
+    /// Turns the encoder's output back into raw video
+    pub decoder: String,
+    /// Frames per second to encode as JPEG, so there is always a recent one ready
+    pub max_rate: u32,
+    /// JPEG quality, from 0 to 100
+    pub quality: u32,
+    /// Take a snapshot whenever the turret fires
+    pub on_fire: bool,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        SnapshotConfig {
+            path: "snapshots".to_string(),
+            decoder: "rtph264depay ! avdec_h264".to_string(),
+            max_rate: 5,
+            quality: 85,
+            on_fire: true,
+        }
+    }
+}
+
+/// An area of the frame to watch for motion, in fractions of the frame's width and height
+#[derive(Clone, Debug, Deserialize)]
+pub struct MotionRegion {
+    pub name: String,
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct MotionConfig {
+    /// How much a pixel's brightness has to change, out of 255, to count as moving
+    pub threshold: u8,
+    /// Fraction of a region's pixels that have to change to count as motion in it
+    pub min_area: f64,
+    /// Frames per second to analyse
+    pub max_rate: u32,
+    /// Seconds to wait before alerting clients about motion in the same region again
+    pub cooldown: f64,
+    /// Watches the whole frame if none are given
+    pub regions: Vec<MotionRegion>,
+}
+
+impl Default for MotionConfig {
+    fn default() -> Self {
+        MotionConfig {
+            threshold: 25,
+            min_area: 0.01,
+            max_rate: 5,
+            cooldown: 2.0,
+            regions: Vec::new(),
+        }
+    }
+}
+
+/// Gains of a PID controller, from an offset from the centre of the frame to a move rate
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default)]
+pub struct PidGains {
+    pub kp: f64,
+    pub ki: f64,
+    pub kd: f64,
+}
+
+impl Default for PidGains {
+    fn default() -> Self {
+        PidGains {
+            kp: 1.0,
+            ki: 0.0,
+            kd: 0.0,
+        }
+    }
+}
+
+/// Follows motion reported by the detector. Offsets are fractions of half the frame, from -1 to
+/// 1, with positive values to the right and up. Negate the gains of an axis if the turret turns
+/// away from targets.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct TrackingConfig {
+    pub pitch: PidGains,
+    pub yaw: PidGains,
+    /// Offset within which a target counts as centred
+    pub dead_zone: f64,
+    /// Fastest move rate to send, from 0 to 1
+    pub max_rate: f64,
+    /// Only follow motion in this region
+    pub region: Option<String>,
+    /// Seconds without new motion before the target is lost. It has to be longer than a step
+    /// and the settling time after it, since the target is only looked for in between steps.
+    pub target_timeout: f64,
+    /// Seconds to move toward each sighting of the target before stopping to look again. The
+    /// detector can't tell the target from the turret's own movement, so it isn't followed
+    /// while moving.
+    pub step: f64,
+    /// Seconds to ignore motion for after a step, until the detector has seen still frames
+    pub settle: f64,
+    /// Seconds to hold off after input from the client in control before tracking again
+    pub resume_after: f64,
+}
+
+impl Default for TrackingConfig {
+    fn default() -> Self {
+        TrackingConfig {
+            pitch: PidGains::default(),
+            yaw: PidGains::default(),
+            dead_zone: 0.05,
+            max_rate: 0.5,
+            region: None,
+            target_timeout: 2.0,
+            step: 0.25,
+            settle: 0.5,
+            resume_after: 5.0,
+        }
+    }
+}
+
+/// Where to serve the web control panel from
+#[derive(Clone, Deserialize)]
+pub struct HttpServerConfig {
+    pub host: String,
+    pub port: u16,
 }
 
 #[derive(Clone, Deserialize)]
 pub struct Config {
     pub server: TcpServerConfig,
+    pub http: Option<HttpServerConfig>,
     pub video: VideoConfig,
     pub camera: HashMap<String, String>,
     pub arduino: ArduinoConfig,
+    /// Firing is refused inside these, and anywhere before the turret has been homed if any
+    /// are configured
+    #[serde(default)]
+    pub no_fire_zones: Vec<NoFireZone>,
+    pub patrol: Option<PatrolConfig>,
+    #[serde(default)]
+    pub audit: AuditConfig,
+    pub recording: Option<RecordingConfig>,
+    pub snapshots: Option<SnapshotConfig>,
+    pub motion: Option<MotionConfig>,
+    pub tracking: Option<TrackingConfig>,
 }
 
-pub fn load() -> Result<Config, String> {
+/// Returns the path of a file stored next to the executable, alongside config.toml
+pub fn file_path(name: &str) -> Result<PathBuf, String> {
     let mut path = env::current_exe().map_err(|err| err.to_string())?;
     path.pop();
-    path.push("config.toml");
+    path.push(name);
+    Ok(path)
+}
+
+pub fn load() -> Result<Config, String> {
+    let path = file_path("config.toml")?;
     let path = path.to_str().unwrap();
     info!("Reading configuration at \"{}\"...", path);
 
@@ -59,3 +499,116 @@ pub fn load() -> Result<Config, String> {
         )
     })?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pitch() -> AxisConfig {
+        AxisConfig::default_pitch()
+    }
+
+    #[test]
+    fn converts_between_steps_and_degrees() {
+        let pitch = pitch();
+        assert_eq!(pitch.steps_per_degree(), 20.0);
+        assert_eq!(pitch.to_degrees(0), -72.0);
+        assert_eq!(pitch.to_degrees(1440), 0.0);
+        assert_eq!(pitch.to_degrees(2500), 53.0);
+        assert_eq!(pitch.to_steps(0.0), 1440);
+        assert_eq!(pitch.to_steps(-71.5), 10);
+    }
+
+    #[test]
+    fn clamps_angles_to_axis_limits() {
+        let pitch = pitch();
+        assert_eq!(pitch.to_steps(-90.0), 0);
+        assert_eq!(pitch.to_steps(90.0), 2500);
+    }
+
+    #[test]
+    fn defaults_axes_to_the_firmware_config() {
+        let arduino: ArduinoConfig = toml::from_str(
+            r#"
+            device = "/dev/ttyACM0"
+            baud = 115200
+            pitch_max_speed = 4000
+            yaw_max_speed = 8000
+            pitch_homing_speed = 1000
+            yaw_homing_speed = 1000
+            "#,
+        )
+        .expect("Axes should be optional");
+        assert_eq!(arduino.pitch.steps_per_degree(), 20.0);
+        assert_eq!(arduino.pitch.to_degrees(0), -72.0);
+        assert_eq!(arduino.yaw.to_degrees(0), 0.0);
+        // YAW_MAX_STEPS
+        assert_eq!(arduino.yaw.to_steps(352.0), 8213);
+    }
+
+    #[test]
+    fn parses_video_sources() {
+        let source = |toml: &str| {
+            toml::from_str::<VideoConfig>(&format!(
+                "encoder = \"x264enc\"\ndecoder = \"avdec_h264\"\nhost = \"0.0.0.0\"\n{}",
+                toml
+            ))
+            .unwrap()
+            .source
+        };
+        assert_eq!(source(""), VideoSource::V4l2);
+        assert_eq!(source("source = { type = \"v4l2\" }"), VideoSource::V4l2);
+        assert_eq!(
+            source("source = { type = \"test\" }"),
+            VideoSource::Test {
+                pattern: "smpte".to_string()
+            }
+        );
+        assert_eq!(
+            source("source = { type = \"uri\", uri = \"file:///tmp/sentry.mp4\" }"),
+            VideoSource::Uri {
+                uri: "file:///tmp/sentry.mp4".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn checks_positions_against_no_fire_zones() {
+        let arduino = ArduinoConfig {
+            device: "/dev/null".to_string(),
+            baud: 115200,
+            pitch_max_speed: 1000,
+            yaw_max_speed: 1000,
+            pitch_homing_speed: 1000,
+            yaw_homing_speed: 1000,
+            pitch: pitch(),
+            yaw: AxisConfig {
+                min_degrees: 0.0,
+                max_degrees: 352.0,
+                ..pitch()
+            },
+            pitch_limits: AxisLimits::default(),
+            yaw_limits: AxisLimits::default(),
+            simulate: false,
+        };
+        let zone = |toml: &str| {
+            toml::from_str::<NoFireZone>(&format!(
+                "name = \"door\"\npitch_min = -10.0\npitch_max = 10.0\n{}",
+                toml
+            ))
+            .unwrap()
+        };
+
+        let degrees = zone("yaw_min = 90.0\nyaw_max = 120.0");
+        assert_eq!(degrees.units, ZoneUnits::Degrees);
+        // 0 degrees pitch and 90 degrees yaw, right on the edge
+        assert!(degrees.contains(&arduino, 1440, 1800));
+        assert!(degrees.contains(&arduino, 1640, 2400));
+        assert!(!degrees.contains(&arduino, 1641, 2400));
+        assert!(!degrees.contains(&arduino, 1440, 1799));
+
+        let steps = zone("units = \"steps\"\nyaw_min = 100.0\nyaw_max = 200.0");
+        assert!(steps.contains(&arduino, 0, 150));
+        assert!(!steps.contains(&arduino, 11, 150));
+    }
+}
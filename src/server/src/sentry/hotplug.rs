@@ -0,0 +1,237 @@
+use crate::sentry::camera;
+use crate::sentry::config::{Config, VideoSource};
+use crate::sentry::{BusSender, Device, Message, MessageContent, MessageSource};
+use futures::oneshot;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::io::FromRawFd;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use tokio::prelude::*;
+
+/// How long to wait after the kernel announces a device for udev to set up its permissions and
+/// symlinks, before trying to open it
+const SETTLE_DELAY: Duration = Duration::from_secs(1);
+
+/// A kernel uevent, as broadcast over netlink
+#[derive(Debug, PartialEq)]
+struct Uevent {
+    action: String,
+    subsystem: String,
+    /// The device node, relative to /dev
+    devname: Option<String>,
+}
+
+impl Uevent {
+    fn device_node(&self) -> Option<PathBuf> {
+        self.devname
+            .as_ref()
+            .map(|devname| PathBuf::from("/dev").join(devname))
+    }
+}
+
+/// Parses an `action@devpath` header followed by `KEY=value` fields, all null-terminated
+fn parse_uevent(data: &[u8]) -> Option<Uevent> {
+    let mut fields = data
+        .split(|&byte| byte == 0)
+        .filter_map(|field| std::str::from_utf8(field).ok());
+    // Messages from udev itself start with "libudev" instead, and are only sent to its own group
+    let action = fields.next()?.split('@').next()?.to_string();
+
+    let mut subsystem = None;
+    let mut devname = None;
+    for field in fields {
+        let mut parts = field.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some("SUBSYSTEM"), Some(value)) => subsystem = Some(value.to_string()),
+            (Some("DEVNAME"), Some(value)) => devname = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    Some(Uevent {
+        action,
+        subsystem: subsystem?,
+        devname,
+    })
+}
+
+/// Opens a netlink socket receiving the kernel's uevents
+fn open_socket() -> Result<File, String> {
+    unsafe {
+        let fd = libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            libc::NETLINK_KOBJECT_UEVENT,
+        );
+        if fd < 0 {
+            return Err(format!(
+                "Could not open uevent socket: {}",
+                io::Error::last_os_error()
+            ));
+        }
+        // Take ownership first so the socket gets closed if binding fails
+        let socket = File::from_raw_fd(fd);
+
+        let mut addr: libc::sockaddr_nl = mem::zeroed();
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        // Multicast group 1 carries the kernel's own events
+        addr.nl_groups = 1;
+        if libc::bind(
+            fd,
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        ) < 0
+        {
+            return Err(format!(
+                "Could not bind uevent socket: {}",
+                io::Error::last_os_error()
+            ));
+        }
+        Ok(socket)
+    }
+}
+
+/// Keeps track of the device nodes of the devices we depend on, and announces their comings
+/// and goings on the bus
+struct Watcher {
+    config: Config,
+    camera: Option<PathBuf>,
+    arduino: Option<PathBuf>,
+    bus_sink: BusSender<Message>,
+}
+
+impl Watcher {
+    fn new(config: Config, bus_sink: BusSender<Message>) -> Self {
+        let mut watcher = Watcher {
+            config,
+            camera: None,
+            arduino: None,
+            bus_sink,
+        };
+        watcher.camera = watcher.find_camera();
+        watcher.arduino = watcher.find_arduino();
+        watcher
+    }
+
+    fn find_camera(&self) -> Option<PathBuf> {
+        if self.config.video.source != VideoSource::V4l2 {
+            return None;
+        }
+        camera::find(&self.config.camera)
+            .unwrap_or_else(|err| {
+                warn!("Could not look for camera: {}", err);
+                None
+            })
+            .map(PathBuf::from)
+    }
+
+    fn find_arduino(&self) -> Option<PathBuf> {
+        if self.config.arduino.simulate {
+            return None;
+        }
+        // The configured path may be a symlink such as /dev/serial/by-id/...
+        fs::canonicalize(&self.config.arduino.device).ok()
+    }
+
+    fn publish(&self, content: MessageContent) {
+        self.bus_sink
+            .unbounded_send(Message {
+                content,
+                source: MessageSource::Hotplug,
+            })
+            .unwrap_or_else(|err| error!("Failed to send bus message: {}", err));
+    }
+
+    fn handle(&mut self, event: Uevent) {
+        let (tracked, device) = match event.subsystem.as_str() {
+            "video4linux" => (&mut self.camera, Device::Camera),
+            "tty" => (&mut self.arduino, Device::Arduino),
+            _ => return,
+        };
+        match event.action.as_str() {
+            "add" if tracked.is_none() => {
+                thread::sleep(SETTLE_DELAY);
+                let found = match device {
+                    Device::Camera => self.find_camera(),
+                    Device::Arduino => self
+                        .find_arduino()
+                        .filter(|path| Some(path) == event.device_node().as_ref()),
+                };
+                if let Some(path) = found {
+                    info!("The {} was plugged in at {}", device.name(), path.display());
+                    match device {
+                        Device::Camera => self.camera = Some(path),
+                        Device::Arduino => self.arduino = Some(path),
+                    }
+                    self.publish(MessageContent::DeviceConnected(device));
+                }
+            }
+            "remove" if tracked.is_some() && *tracked == event.device_node() => {
+                warn!("The {} was unplugged", device.name());
+                *tracked = None;
+                self.publish(MessageContent::DeviceDisconnected(device));
+            }
+            _ => {}
+        }
+    }
+}
+
+fn watch(mut socket: File, mut watcher: Watcher) -> Result<(), String> {
+    let mut buf = [0; 8192];
+    loop {
+        let len = socket
+            .read(&mut buf)
+            .map_err(|err| format!("Could not read uevent: {}", err))?;
+        if let Some(event) = parse_uevent(&buf[..len]) {
+            watcher.handle(event);
+        }
+    }
+}
+
+/// Only needs to send on the bus, since holding a receiver that is never read would keep every
+/// message in memory
+pub fn start(
+    config: Config,
+    bus_sink: BusSender<Message>,
+) -> impl Future<Item = (), Error = String> {
+    future::result(open_socket()).and_then(move |socket| {
+        // Reading the socket blocks, so it gets a thread of its own
+        let (tx, rx) = oneshot::<Result<(), String>>();
+        thread::spawn(move || tx.send(watch(socket, Watcher::new(config, bus_sink))));
+
+        rx.map_err(|err| format!("Error communicating with thread: {}", err))
+            .and_then(|result| result)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_kernel_uevents() {
+        let event = parse_uevent(
+            b"remove@/devices/pci0000:00/usb1/1-1/1-1:1.0/tty/ttyACM0\0ACTION=remove\0\
+              DEVPATH=/devices/pci0000:00/usb1/1-1/1-1:1.0/tty/ttyACM0\0SUBSYSTEM=tty\0\
+              MAJOR=166\0MINOR=0\0DEVNAME=ttyACM0\0SEQNUM=4242\0",
+        )
+        .unwrap();
+        assert_eq!(
+            event,
+            Uevent {
+                action: "remove".to_string(),
+                subsystem: "tty".to_string(),
+                devname: Some("ttyACM0".to_string()),
+            }
+        );
+        assert_eq!(event.device_node(), Some(PathBuf::from("/dev/ttyACM0")));
+
+        let event = parse_uevent(b"add@/devices/pci0000:00/usb1/1-1\0SUBSYSTEM=usb\0").unwrap();
+        assert_eq!(event.subsystem, "usb");
+        assert_eq!(event.devname, None);
+
+        assert_eq!(parse_uevent(b"libudev\0\xfe\xed\xca\xfe"), None);
+    }
+}
@@ -0,0 +1,241 @@
+use crate::sentry::config::Config;
+use crate::sentry::server::tls_acceptor;
+use futures::future::Either;
+use hyper::header::CONTENT_TYPE;
+use hyper::service::service_fn_ok;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde_json::json;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::prelude::*;
+
+/// How long a browser has to complete the TLS handshake after connecting
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many TLS handshakes can be in progress at once, so a slow browser doesn't hold up the rest
+const MAX_HANDSHAKES: usize = 32;
+
+/// The web control panel, embedded in the binary as (path, content type, contents)
+const FILES: &[(&str, &str, &str)] = &[
+    (
+        "/",
+        "text/html; charset=utf-8",
+        include_str!("../../web/index.html"),
+    ),
+    (
+        "/app.js",
+        "application/javascript; charset=utf-8",
+        include_str!("../../web/app.js"),
+    ),
+    (
+        "/style.css",
+        "text/css; charset=utf-8",
+        include_str!("../../web/style.css"),
+    ),
+];
+
+fn respond(request: &Request<Body>, server_config: &str) -> Response<Body> {
+    let mut response = Response::builder();
+    if request.method() != Method::GET {
+        return response
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    let path = request.uri().path();
+    // Tells the page where to find the WebSocket server
+    if path == "/config.json" {
+        return response
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(server_config.to_string()))
+            .unwrap();
+    }
+
+    match FILES.iter().find(|(file_path, _, _)| *file_path == path) {
+        Some((_, content_type, contents)) => response
+            .header(CONTENT_TYPE, *content_type)
+            .body(Body::from(*contents))
+            .unwrap(),
+        None => response
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap(),
+    }
+}
+
+pub fn start(config: Config) -> impl Future<Item = (), Error = String> {
+    future::result(
+        config
+            .http
+            .clone()
+            .ok_or_else(|| "No http configuration".to_string())
+            .and_then(|http| match config.server.websocket_port {
+                Some(websocket_port) => Ok((http, websocket_port)),
+                None => Err("The web control panel requires server.websocket_port".to_string()),
+            }),
+    )
+    .and_then(move |(http, websocket_port)| {
+        let addr = SocketAddr::new(http.host.as_str().parse().unwrap(), http.port);
+        let server_config = json!({
+            "websocket_port": websocket_port,
+            "tls": config.server.tls.is_some(),
+            "auth": config.server.requires_auth(),
+            "stun_server": config.video.stun_server,
+        })
+        .to_string();
+        let make_service = move || {
+            let server_config = server_config.clone();
+            service_fn_ok(move |request| respond(&request, &server_config))
+        };
+
+        info!("Binding HTTP server on {}...", addr);
+
+        let tls_acceptor = config.server.tls.as_ref().map(tls_acceptor).transpose()?;
+        let listener = TcpListener::bind(&addr)
+            .map_err(|err| format!("Could not bind HTTP server: {}", err))?;
+
+        let incoming = listener.incoming();
+        Ok(match tls_acceptor {
+            // Serve the page over TLS too, so browsers allow it to open a secure WebSocket
+            Some(tls_acceptor) => Either::A(
+                Server::builder(
+                    incoming
+                        .map(move |socket| {
+                            tls_acceptor
+                                .accept(socket)
+                                .timeout(HANDSHAKE_TIMEOUT)
+                                .then(|result| match result {
+                                    Ok(socket) => Ok::<_, io::Error>(Some(socket)),
+                                    Err(err) => {
+                                        warn!("TLS handshake with browser failed: {}", err);
+                                        Ok(None)
+                                    }
+                                })
+                        })
+                        .buffer_unordered(MAX_HANDSHAKES)
+                        .filter_map(|socket| socket),
+                )
+                .serve(make_service),
+            ),
+            None => Either::B(Server::builder(incoming).serve(make_service)),
+        })
+    })
+    .and_then(|server| server.map_err(|err| format!("HTTP server error: {}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use tokio::io::{read_to_end, write_all};
+    use tokio::net::TcpStream;
+    use tokio::runtime::current_thread::Runtime;
+    use tokio::timer::Delay;
+
+    fn get(path: &str) -> Response<Body> {
+        let request = Request::get(path).body(Body::empty()).unwrap();
+        respond(&request, r#"{"websocket_port":8081}"#)
+    }
+
+    #[test]
+    fn serves_the_control_panel() {
+        for &(path, content_type, _) in FILES {
+            let response = get(path);
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[CONTENT_TYPE], content_type);
+        }
+        assert_eq!(
+            get("/config.json").headers()[CONTENT_TYPE],
+            "application/json"
+        );
+        assert_eq!(get("/missing.js").status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn handshakes_concurrently() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .port();
+        let config: Config = toml::from_str(&format!(
+            r#"
+            camera = {{}}
+
+            [server]
+            host = "127.0.0.1"
+            port = 0
+            websocket_port = 8081
+            tls = {{ cert = "{0}/testdata/cert.pem", key = "{0}/testdata/key.pem" }}
+
+            [http]
+            host = "127.0.0.1"
+            port = {1}
+
+            [video]
+            encoder = "fakesink"
+            decoder = "fakesink"
+            host = "127.0.0.1"
+
+            [arduino]
+            device = "/dev/null"
+            baud = 115200
+            pitch_max_speed = 1000
+            yaw_max_speed = 1000
+            pitch_homing_speed = 1000
+            yaw_homing_speed = 1000
+            "#,
+            env!("CARGO_MANIFEST_DIR"),
+            port
+        ))
+        .expect("Invalid test configuration");
+
+        let mut runtime = Runtime::new().unwrap();
+        runtime.spawn(
+            future::lazy(move || start(config)).map_err(|err| panic!("Server failed: {}", err)),
+        );
+
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        // The test certificate is self-signed
+        let connector = tokio_tls::TlsConnector::from(
+            native_tls::TlsConnector::builder()
+                .danger_accept_invalid_certs(true)
+                .build()
+                .unwrap(),
+        );
+        let response = runtime
+            .block_on(
+                Delay::new(Instant::now() + Duration::from_millis(100))
+                    .map_err(|err| err.to_string())
+                    // A browser that connects but never starts its handshake
+                    .and_then(move |_| TcpStream::connect(&addr).map_err(|err| err.to_string()))
+                    .and_then(move |stalled| {
+                        TcpStream::connect(&addr)
+                            .map_err(|err| err.to_string())
+                            .and_then(move |socket| {
+                                connector
+                                    .connect("localhost", socket)
+                                    .map_err(|err| err.to_string())
+                            })
+                            .and_then(|socket| {
+                                write_all(socket, b"GET /config.json HTTP/1.0\r\n\r\n")
+                                    .map_err(|err| err.to_string())
+                            })
+                            .and_then(|(socket, _)| {
+                                read_to_end(socket, Vec::new()).map_err(|err| err.to_string())
+                            })
+                            .map(move |(_, response)| {
+                                drop(stalled);
+                                String::from_utf8(response).unwrap()
+                            })
+                    })
+                    // Well before the stalled handshake times out
+                    .timeout(HANDSHAKE_TIMEOUT / 2),
+            )
+            .expect("Waited for the stalled handshake");
+
+        assert!(response.starts_with("HTTP/1.0 200 OK"));
+        assert!(response.contains(r#""websocket_port":8081"#));
+    }
+}
@@ -9,11 +9,14 @@ extern crate tokio_fs;
 extern crate tokio_io;
 extern crate tokio_serial;
 
+use schemars::JsonSchema;
 use std::net::SocketAddr;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
 pub enum Command {
     Move { pitch: f64, yaw: f64 },
+    MoveTo { pitch: u32, yaw: u32 },
     Home,
     Fire,
     ReleaseMagazine,
@@ -24,7 +27,54 @@ pub enum Command {
     MotorsOff,
 }
 
-#[derive(Clone)]
+/// What the arduino module did with a command it received
+#[derive(Clone, Debug, PartialEq)]
+pub enum CommandOutcome {
+    Forwarded,
+    RateLimited,
+    /// The command came from a client that isn't in control of the turret, or is a client's
+    /// idle joystick that would only stop a move another module started
+    Ignored,
+    /// Firing isn't allowed where the turret is pointing
+    Blocked {
+        reason: String,
+    },
+}
+
+/// Why a snapshot was taken
+#[derive(Clone, Copy, Debug, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SnapshotReason {
+    Fire,
+    Request,
+}
+
+impl SnapshotReason {
+    pub fn name(self) -> &'static str {
+        match self {
+            SnapshotReason::Fire => "fire",
+            SnapshotReason::Request => "request",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum PresetCommand {
+    List,
+    Save(String),
+    Delete(String),
+    Recall(String),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Preset {
+    pub name: String,
+    pub pitch: u32,
+    pub yaw: u32,
+}
+
+#[derive(Clone, Debug, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum HardwareStatus {
     Ready,
     NotLoaded,
@@ -37,6 +87,64 @@ pub enum HardwareStatus {
     Error,
 }
 
+impl HardwareStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HardwareStatus::Ready => "ready",
+            HardwareStatus::NotLoaded => "not_loaded",
+            HardwareStatus::MagazineReleased => "magazine_released",
+            HardwareStatus::Reloading => "reloading",
+            HardwareStatus::HomingRequired => "homing_required",
+            HardwareStatus::Homing => "homing",
+            HardwareStatus::MotorsOff => "motors_off",
+            HardwareStatus::HomingFailed => "homing_failed",
+            HardwareStatus::Error => "error",
+        }
+    }
+
+    /// Whether the turret is homed once this status is reported, given whether it was before.
+    /// Motors being off says nothing either way, as the firmware reports it at power-up as well.
+    pub fn homed_after(&self, was_homed: bool) -> bool {
+        match self {
+            HardwareStatus::Ready
+            | HardwareStatus::NotLoaded
+            | HardwareStatus::MagazineReleased
+            | HardwareStatus::Reloading => true,
+            HardwareStatus::MotorsOff => was_homed,
+            HardwareStatus::HomingRequired
+            | HardwareStatus::Homing
+            | HardwareStatus::HomingFailed
+            | HardwareStatus::Error => false,
+        }
+    }
+}
+
+/// A piece of hardware that can be unplugged while the server is running
+#[derive(Clone, Copy, Debug, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Device {
+    Camera,
+    Arduino,
+}
+
+impl Device {
+    pub fn name(self) -> &'static str {
+        match self {
+            Device::Camera => "camera",
+            Device::Arduino => "arduino",
+        }
+    }
+}
+
+/// A rectangle in fractions of the video frame's width and height
+#[derive(Clone, Copy, Debug, JsonSchema, PartialEq, Serialize)]
+pub struct BoundingBox {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
 #[derive(Clone)]
 pub struct Client {
     pub address: SocketAddr,
@@ -48,6 +156,10 @@ pub enum MessageSource {
     Arduino,
     WebsocketServer,
     VideoServer,
+    Presets,
+    Patrol,
+    Tracking,
+    Hotplug,
     Client(Client),
 }
 
@@ -57,6 +169,9 @@ pub enum MessageContent {
         pitch_pos: u32,
         yaw_pos: u32,
         status: HardwareStatus,
+        /// Whether the arduino has been homed since it connected, without which the position
+        /// means nothing
+        homed: bool,
     },
     VideoOffer {
         nonce: String,
@@ -70,9 +185,76 @@ pub enum MessageContent {
         message: String,
         for_client: Option<SocketAddr>,
     },
+    /// A client asking for the video over RTP/UDP
+    StartRtp,
+    /// A client asking for the video over WebRTC instead of RTP/UDP
+    StartWebRtc,
+    WebRtcOffer {
+        sdp: String,
+        for_client: SocketAddr,
+    },
+    /// A client's answer to the `WebRtcOffer` sent to it
+    WebRtcAnswer {
+        sdp: String,
+    },
+    /// An ICE candidate for the server's end when sent to a client, or the client's end when sent by one
+    WebRtcIceCandidate {
+        candidate: String,
+        sdp_mline_index: u32,
+        for_client: Option<SocketAddr>,
+    },
     Command(Command),
+    CommandProcessed {
+        command: Command,
+        source: MessageSource,
+        outcome: CommandOutcome,
+    },
+    PresetCommand(PresetCommand),
+    Presets {
+        presets: Vec<Preset>,
+        for_client: Option<SocketAddr>,
+    },
+    PresetError {
+        message: String,
+        for_client: SocketAddr,
+    },
+    StartPatrol,
+    StopPatrol,
+    PatrolState {
+        active: bool,
+        paused: bool,
+    },
+    StartRecording,
+    StopRecording,
+    RecordingState {
+        active: bool,
+    },
+    MotionDetected {
+        region: String,
+        bbox: BoundingBox,
+        /// Whether clients should be alerted, which only happens once per cooldown. The region
+        /// being tracked is reported on every frame that moved, for the tracker to follow.
+        alert: bool,
+    },
+    StartTracking,
+    StopTracking,
+    TrackingState {
+        enabled: bool,
+        /// Whether the client in control has taken over for now
+        paused: bool,
+    },
+    TakeSnapshot,
+    /// A snapshot was saved, because of a shot or a client's request
+    Snapshot {
+        file_name: String,
+        pitch: Option<u32>,
+        yaw: Option<u32>,
+        reason: SnapshotReason,
+    },
     ClientConnected(Client),
     ClientDisconnected(Client),
+    DeviceConnected(Device),
+    DeviceDisconnected(Device),
     Ping,
 }
 
@@ -86,6 +268,15 @@ pub mod bus;
 pub use bus::*;
 
 pub mod arduino;
+pub mod audit;
+pub mod camera;
 pub mod config;
+pub mod hotplug;
+pub mod http;
+pub mod patrol;
+pub mod presets;
+pub mod protocol;
 pub mod server;
+pub mod simulator;
+pub mod tracking;
 pub mod video;
@@ -0,0 +1,480 @@
+use crate::sentry::config::Config;
+use crate::sentry::{Bus, BusSender, Command, Message, MessageContent, MessageSource};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::prelude::*;
+use tokio::timer::Interval;
+
+/// How often the patrol checks on the turret's progress
+const UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+/// How often a move is repeated while the turret hasn't reached its target
+const RESEND_INTERVAL: Duration = Duration::from_secs(1);
+/// Distance in steps at which the turret is considered to have arrived at a position
+const POSITION_TOLERANCE: u32 = 5;
+
+#[derive(Debug, PartialEq)]
+struct Waypoint {
+    pitch: u32,
+    yaw: u32,
+    dwell: Duration,
+}
+
+/// Builds the list of positions to cycle through from the patrol configuration
+fn waypoints(config: &Config) -> Vec<Waypoint> {
+    let patrol = match &config.patrol {
+        Some(patrol) => patrol,
+        None => return Vec::new(),
+    };
+    let waypoint = |pitch: f64, yaw: f64, dwell: f64| Waypoint {
+        pitch: config.arduino.pitch.to_steps(pitch),
+        yaw: config.arduino.yaw.to_steps(yaw),
+        dwell: Duration::from_secs_f64(dwell.max(0.0)),
+    };
+
+    let mut waypoints: Vec<_> = patrol
+        .positions
+        .iter()
+        .map(|position| {
+            waypoint(
+                position.pitch,
+                position.yaw,
+                position.dwell.unwrap_or(patrol.dwell),
+            )
+        })
+        .collect();
+
+    if let Some(sweep) = &patrol.sweep {
+        if sweep.yaw_step > 0.0 && sweep.yaw_max >= sweep.yaw_min {
+            let mut yaws: Vec<f64> = (0..)
+                .map(|i| sweep.yaw_min + f64::from(i) * sweep.yaw_step)
+                .take_while(|&yaw| yaw < sweep.yaw_max)
+                .collect();
+            yaws.push(sweep.yaw_max);
+            // Come back the same way without visiting either end twice in a row
+            let back: Vec<f64> = yaws
+                .iter()
+                .rev()
+                .skip(1)
+                .take(yaws.len().saturating_sub(2))
+                .cloned()
+                .collect();
+            yaws.extend(back);
+            waypoints.extend(
+                yaws.into_iter()
+                    .map(|yaw| waypoint(sweep.pitch, yaw, patrol.dwell)),
+            );
+        } else {
+            warn!("Ignoring patrol sweep with an empty yaw range or non-positive yaw_step");
+        }
+    }
+
+    waypoints
+}
+
+struct Patrol {
+    waypoints: Vec<Waypoint>,
+    active: bool,
+    /// Addresses of connected clients. Patrolling pauses while anyone is in the queue,
+    /// since the client at the head of it is in control of the turret.
+    /// Clients that connected before the module started are picked up from the messages
+    /// they send, which they do at least every few seconds to stay connected.
+    clients: HashSet<SocketAddr>,
+    index: usize,
+    position: Option<(u32, u32)>,
+    arrived_time: Option<Instant>,
+    last_sent_time: Option<Instant>,
+    bus_sink: BusSender<Message>,
+}
+
+impl Patrol {
+    fn new(config: &Config, bus_sink: BusSender<Message>) -> Self {
+        Patrol {
+            waypoints: waypoints(config),
+            active: false,
+            clients: HashSet::new(),
+            index: 0,
+            position: None,
+            arrived_time: None,
+            last_sent_time: None,
+            bus_sink,
+        }
+    }
+
+    fn is_paused(&self) -> bool {
+        !self.clients.is_empty()
+    }
+
+    /// Starts or stops pausing for a client, announcing the change in state
+    fn set_connected(&mut self, client: SocketAddr, connected: bool) {
+        let changed = if connected {
+            self.clients.insert(client)
+        } else {
+            self.clients.remove(&client)
+        };
+        if !changed {
+            return;
+        }
+        if connected && self.clients.len() == 1 && self.active {
+            info!("Pausing patrol while a client is connected");
+        } else if !connected && self.clients.is_empty() && self.active {
+            info!("Resuming patrol");
+            self.restart_waypoint();
+        }
+        self.send_state();
+    }
+
+    fn handle_message(&mut self, message: Message) {
+        if let MessageSource::Client(ref client) = message.source {
+            self.set_connected(client.address, true);
+        }
+        match message.content {
+            MessageContent::HardwareState {
+                pitch_pos,
+                yaw_pos,
+                homed,
+                ..
+            } => {
+                self.position = if homed {
+                    Some((pitch_pos, yaw_pos))
+                } else {
+                    None
+                };
+            }
+            MessageContent::ClientConnected(client) => self.set_connected(client.address, true),
+            MessageContent::ClientDisconnected(client) => self.set_connected(client.address, false),
+            MessageContent::StartPatrol | MessageContent::StopPatrol => {
+                // Only the client in control of the turret can start or stop patrolling
+                match message.source {
+                    MessageSource::Client(ref client) if client.queue_position == 0 => {}
+                    _ => return,
+                }
+                match message.content {
+                    MessageContent::StartPatrol if self.waypoints.is_empty() => {
+                        warn!("Cannot start patrol because no positions are configured");
+                    }
+                    MessageContent::StartPatrol => {
+                        info!("Starting patrol of {} positions", self.waypoints.len());
+                        self.active = true;
+                        self.restart_waypoint();
+                    }
+                    _ => {
+                        info!("Stopping patrol");
+                        self.active = false;
+                    }
+                }
+                self.send_state();
+            }
+            _ => {}
+        }
+    }
+
+    fn restart_waypoint(&mut self) {
+        self.arrived_time = None;
+        self.last_sent_time = None;
+    }
+
+    fn update(&mut self) {
+        if !self.active || self.is_paused() {
+            return;
+        }
+
+        let target = &self.waypoints[self.index];
+        let arrived = match self.position {
+            Some((pitch, yaw)) => {
+                distance(pitch, target.pitch) <= POSITION_TOLERANCE
+                    && distance(yaw, target.yaw) <= POSITION_TOLERANCE
+            }
+            None => false,
+        };
+
+        if arrived {
+            let arrived_time = *self.arrived_time.get_or_insert_with(Instant::now);
+            if arrived_time.elapsed() < target.dwell {
+                return;
+            }
+            self.index = (self.index + 1) % self.waypoints.len();
+            self.restart_waypoint();
+        }
+
+        let resend = self
+            .last_sent_time
+            .map(|time| time.elapsed() >= RESEND_INTERVAL)
+            .unwrap_or(true);
+        if resend {
+            let target = &self.waypoints[self.index];
+            self.send(MessageContent::Command(Command::MoveTo {
+                pitch: target.pitch,
+                yaw: target.yaw,
+            }));
+            self.last_sent_time = Some(Instant::now());
+        }
+    }
+
+    fn send_state(&self) {
+        self.send(MessageContent::PatrolState {
+            active: self.active,
+            paused: self.is_paused(),
+        });
+    }
+
+    fn send(&self, content: MessageContent) {
+        self.bus_sink
+            .unbounded_send(Message {
+                content,
+                source: MessageSource::Patrol,
+            })
+            .unwrap_or_else(|err| error!("Failed to send bus message: {}", err));
+    }
+}
+
+fn distance(a: u32, b: u32) -> u32 {
+    a.max(b) - a.min(b)
+}
+
+enum Event {
+    Message(Message),
+    Tick,
+}
+
+pub fn start(config: Config, bus: Bus<Message>) -> impl Future<Item = (), Error = String> {
+    let (bus_sink, bus_stream) = bus;
+    let mut patrol = Patrol::new(&config, bus_sink);
+
+    bus_stream
+        .map(Event::Message)
+        .map_err(|_| "Failed to read from bus".to_string())
+        .select(
+            Interval::new(Instant::now(), UPDATE_INTERVAL)
+                .map(|_| Event::Tick)
+                .map_err(|err| format!("Patrol timer error: {}", err)),
+        )
+        .for_each(move |event| {
+            match event {
+                Event::Message(message) => patrol.handle_message(message),
+                Event::Tick => patrol.update(),
+            }
+            Ok(())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sentry::bus::{self, BusReceiver};
+    use crate::sentry::{Client, HardwareStatus};
+
+    fn config(patrol: &str) -> Config {
+        toml::from_str(&format!(
+            r#"
+            camera = {{}}
+
+            [server]
+            host = "127.0.0.1"
+            port = 0
+
+            [video]
+            encoder = "fakesink"
+            decoder = "fakesink"
+            host = "127.0.0.1"
+
+            [arduino]
+            device = "/dev/null"
+            baud = 115200
+            pitch_max_speed = 1000
+            yaw_max_speed = 1000
+            pitch_homing_speed = 1000
+            yaw_homing_speed = 1000
+
+            [arduino.pitch]
+            gear_ratio = 9.0
+            microsteps = 4
+            min_degrees = -72.0
+            max_degrees = 53.0
+
+            [arduino.yaw]
+            gear_ratio = 9.0
+            microsteps = 4
+            min_degrees = 0.0
+            max_degrees = 352.0
+
+            [patrol]
+            {}
+            "#,
+            patrol
+        ))
+        .expect("Invalid test configuration")
+    }
+
+    fn yaws(waypoints: &[Waypoint]) -> Vec<u32> {
+        // 20 steps per degree with the test configuration
+        waypoints.iter().map(|waypoint| waypoint.yaw / 20).collect()
+    }
+
+    #[test]
+    fn sweeps_back_and_forth() {
+        let waypoints = waypoints(&config(
+            "dwell = 2.0
+            sweep = { pitch = 0.0, yaw_min = 30.0, yaw_max = 100.0, yaw_step = 30.0 }",
+        ));
+        assert_eq!(yaws(&waypoints), vec![30, 60, 90, 100, 90, 60]);
+        assert!(waypoints
+            .iter()
+            .all(|waypoint| waypoint.pitch == 1440 && waypoint.dwell == Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn visits_positions_before_sweeping() {
+        let waypoints = waypoints(&config(
+            "dwell = 1.0
+            positions = [
+                { pitch = -72.0, yaw = 10.0 },
+                { pitch = 53.0, yaw = 20.0, dwell = 5.0 },
+            ]
+            sweep = { pitch = 0.0, yaw_min = 40.0, yaw_max = 40.0, yaw_step = 10.0 }",
+        ));
+        assert_eq!(
+            waypoints,
+            vec![
+                Waypoint {
+                    pitch: 0,
+                    yaw: 200,
+                    dwell: Duration::from_secs(1)
+                },
+                Waypoint {
+                    pitch: 2500,
+                    yaw: 400,
+                    dwell: Duration::from_secs(5)
+                },
+                Waypoint {
+                    pitch: 1440,
+                    yaw: 800,
+                    dwell: Duration::from_secs(1)
+                },
+            ]
+        );
+    }
+
+    fn client(port: u16) -> Client {
+        Client {
+            address: SocketAddr::from(([127, 0, 0, 1], port)),
+            queue_position: 0,
+        }
+    }
+
+    fn message(content: MessageContent, source: MessageSource) -> Message {
+        Message { content, source }
+    }
+
+    /// Whether the last patrol state sent is paused, if any was sent
+    fn paused(bus_stream: &mut BusReceiver<Message>) -> Option<bool> {
+        // Polling needs a task, even though nothing waits for more messages
+        future::lazy(|| {
+            let mut paused = None;
+            while let Ok(Async::Ready(Some(message))) = bus_stream.poll() {
+                if let MessageContent::PatrolState { paused: p, .. } = message.content {
+                    paused = Some(p);
+                }
+            }
+            Ok::<_, ()>(paused)
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn pauses_while_clients_are_connected() {
+        let (bus_sink, mut bus_stream) = bus::new::<Message>();
+        let mut patrol = Patrol::new(
+            &config("dwell = 1.0\npositions = [{ pitch = 0.0, yaw = 10.0 }]"),
+            bus_sink,
+        );
+        patrol.handle_message(message(
+            MessageContent::StartPatrol,
+            MessageSource::Client(client(5000)),
+        ));
+        let disconnect = |patrol: &mut Patrol, port| {
+            patrol.handle_message(message(
+                MessageContent::ClientDisconnected(client(port)),
+                MessageSource::WebsocketServer,
+            ))
+        };
+        disconnect(&mut patrol, 5000);
+        assert_eq!(paused(&mut bus_stream), Some(false));
+        assert!(patrol.active);
+
+        for &port in &[5000, 5001] {
+            patrol.handle_message(message(
+                MessageContent::ClientConnected(client(port)),
+                MessageSource::WebsocketServer,
+            ));
+        }
+        assert_eq!(paused(&mut bus_stream), Some(true));
+        // Disconnecting twice doesn't resume while the other client is still connected
+        disconnect(&mut patrol, 5000);
+        disconnect(&mut patrol, 5000);
+        assert_eq!(paused(&mut bus_stream), Some(true));
+        disconnect(&mut patrol, 5001);
+        assert_eq!(paused(&mut bus_stream), Some(false));
+    }
+
+    #[test]
+    fn pauses_for_clients_connected_before_starting() {
+        let (bus_sink, mut bus_stream) = bus::new::<Message>();
+        let mut patrol = Patrol::new(
+            &config("dwell = 1.0\npositions = [{ pitch = 0.0, yaw = 10.0 }]"),
+            bus_sink,
+        );
+        patrol.active = true;
+        patrol.handle_message(message(
+            MessageContent::Ping,
+            MessageSource::Client(client(5000)),
+        ));
+        assert_eq!(paused(&mut bus_stream), Some(true));
+        patrol.update();
+        assert_eq!(paused(&mut bus_stream), None);
+
+        patrol.handle_message(message(
+            MessageContent::ClientDisconnected(client(5000)),
+            MessageSource::WebsocketServer,
+        ));
+        assert_eq!(paused(&mut bus_stream), Some(false));
+    }
+
+    #[test]
+    fn only_arrives_at_homed_positions() {
+        let (bus_sink, _bus_stream) = bus::new::<Message>();
+        let mut patrol = Patrol::new(
+            &config("dwell = 1.0\npositions = [{ pitch = -72.0, yaw = 0.0 }]"),
+            bus_sink,
+        );
+        patrol.handle_message(message(
+            MessageContent::StartPatrol,
+            MessageSource::Client(client(5000)),
+        ));
+        patrol.handle_message(message(
+            MessageContent::ClientDisconnected(client(5000)),
+            MessageSource::WebsocketServer,
+        ));
+        let state = |homed| {
+            message(
+                MessageContent::HardwareState {
+                    pitch_pos: 0,
+                    yaw_pos: 0,
+                    status: HardwareStatus::MotorsOff,
+                    homed,
+                },
+                MessageSource::Arduino,
+            )
+        };
+
+        // The firmware reports (0, 0) with its motors off before it has been homed
+        patrol.handle_message(state(false));
+        patrol.update();
+        assert_eq!(patrol.arrived_time, None);
+
+        patrol.handle_message(state(true));
+        patrol.update();
+        assert!(patrol.arrived_time.is_some());
+    }
+}
@@ -0,0 +1,322 @@
+use crate::sentry::config;
+use crate::sentry::{
+    Bus, BusSender, Command, Message, MessageContent, MessageSource, Preset, PresetCommand,
+};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio::prelude::*;
+
+#[derive(Deserialize, Serialize)]
+struct PresetFile {
+    #[serde(default)]
+    preset: Vec<Preset>,
+}
+
+struct Presets {
+    path: PathBuf,
+    presets: Vec<Preset>,
+    /// Last known turret position, if it has been homed
+    position: Option<(u32, u32)>,
+    bus_sink: BusSender<Message>,
+}
+
+impl Presets {
+    fn load(path: PathBuf, bus_sink: BusSender<Message>) -> Result<Self, String> {
+        info!("Reading presets at \"{}\"...", path.display());
+        let presets = if path.exists() {
+            toml::from_str::<PresetFile>(
+                fs::read_to_string(&path)
+                    .map_err(|err| {
+                        format!(
+                            "Could not read presets file \"{}\": {}",
+                            path.display(),
+                            err
+                        )
+                    })?
+                    .as_str(),
+            )
+            .map_err(|err| {
+                format!(
+                    "Could not parse presets file \"{}\": {}",
+                    path.display(),
+                    err
+                )
+            })?
+            .preset
+        } else {
+            Vec::new()
+        };
+
+        Ok(Presets {
+            path,
+            presets,
+            position: None,
+            bus_sink,
+        })
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let contents = toml::to_string(&PresetFile {
+            preset: self.presets.clone(),
+        })
+        .map_err(|err| format!("Could not serialize presets: {}", err))?;
+
+        // Write to a temporary file first so a failed write can't lose existing presets
+        let temp_path = self.path.with_extension("toml.tmp");
+        fs::write(&temp_path, contents)
+            .and_then(|_| fs::rename(&temp_path, &self.path))
+            .map_err(|err| {
+                format!(
+                    "Could not write presets file \"{}\": {}",
+                    self.path.display(),
+                    err
+                )
+            })
+    }
+
+    fn handle_command(
+        &mut self,
+        command: PresetCommand,
+        source: MessageSource,
+        client: SocketAddr,
+    ) -> Result<(), String> {
+        // Clients waiting in the queue can look at presets, but not change the operator's
+        let in_control = match &source {
+            MessageSource::Client(client) => client.queue_position == 0,
+            _ => false,
+        };
+        match command {
+            PresetCommand::Save(_) | PresetCommand::Delete(_) if !in_control => {
+                return Err(
+                    "Only the client in control of the turret can change presets".to_string(),
+                );
+            }
+            PresetCommand::List => {
+                self.send(MessageContent::Presets {
+                    presets: self.presets.clone(),
+                    for_client: Some(client),
+                });
+            }
+            PresetCommand::Save(name) => {
+                let (pitch, yaw) = self
+                    .position
+                    .ok_or_else(|| "The turret must be homed before saving presets".to_string())?;
+                info!(
+                    "Client {} saved preset '{}' at pitch {}, yaw {}",
+                    client, name, pitch, yaw
+                );
+                match self.presets.iter_mut().find(|p| p.name == name) {
+                    Some(preset) => {
+                        preset.pitch = pitch;
+                        preset.yaw = yaw;
+                    }
+                    None => {
+                        self.presets.push(Preset { name, pitch, yaw });
+                        self.presets.sort_by(|a, b| a.name.cmp(&b.name));
+                    }
+                }
+                self.save()?;
+                self.send_to_all();
+            }
+            PresetCommand::Delete(name) => {
+                let index = self.index_of(&name)?;
+                info!("Client {} deleted preset '{}'", client, name);
+                self.presets.remove(index);
+                self.save()?;
+                self.send_to_all();
+            }
+            PresetCommand::Recall(name) => {
+                let preset = &self.presets[self.index_of(&name)?];
+                info!("Client {} recalled preset '{}'", client, name);
+                // Send the move as the client that requested it, so it is only
+                // carried out if they are in control of the turret
+                self.bus_sink
+                    .unbounded_send(Message {
+                        content: MessageContent::Command(Command::MoveTo {
+                            pitch: preset.pitch,
+                            yaw: preset.yaw,
+                        }),
+                        source,
+                    })
+                    .unwrap_or_else(|err| error!("Failed to send bus message: {}", err));
+            }
+        }
+        Ok(())
+    }
+
+    fn index_of(&self, name: &str) -> Result<usize, String> {
+        self.presets
+            .iter()
+            .position(|p| p.name == name)
+            .ok_or_else(|| format!("There is no preset named '{}'", name))
+    }
+
+    fn send_to_all(&self) {
+        self.send(MessageContent::Presets {
+            presets: self.presets.clone(),
+            for_client: None,
+        });
+    }
+
+    fn send(&self, content: MessageContent) {
+        self.bus_sink
+            .unbounded_send(Message {
+                content,
+                source: MessageSource::Presets,
+            })
+            .unwrap_or_else(|err| error!("Failed to send bus message: {}", err));
+    }
+}
+
+pub fn start(bus: Bus<Message>) -> impl Future<Item = (), Error = String> {
+    let (bus_sink, bus_stream) = bus;
+
+    future::result(config::file_path("presets.toml"))
+        .and_then(move |path| Presets::load(path, bus_sink))
+        .and_then(move |mut presets| {
+            bus_stream
+                .map_err(|_| "Failed to read from bus".to_string())
+                .for_each(move |message| {
+                    match message.content {
+                        MessageContent::HardwareState {
+                            pitch_pos,
+                            yaw_pos,
+                            homed,
+                            ..
+                        } => {
+                            presets.position = if homed {
+                                Some((pitch_pos, yaw_pos))
+                            } else {
+                                None
+                            };
+                        }
+                        MessageContent::PresetCommand(command) => {
+                            if let MessageSource::Client(client) = &message.source {
+                                let address = client.address;
+                                if let Err(message) =
+                                    presets.handle_command(command, message.source.clone(), address)
+                                {
+                                    warn!("Preset command from {} failed: {}", address, message);
+                                    presets.send(MessageContent::PresetError {
+                                        message,
+                                        for_client: address,
+                                    });
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                    Ok(())
+                })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sentry::bus::{self, BusReceiver};
+    use crate::sentry::Client;
+    use std::env;
+
+    const CLIENT: ([u8; 4], u16) = ([127, 0, 0, 1], 5000);
+
+    fn client_source(queue_position: usize) -> MessageSource {
+        MessageSource::Client(Client {
+            address: SocketAddr::from(CLIENT),
+            queue_position,
+        })
+    }
+
+    fn presets(name: &str) -> (Presets, BusReceiver<Message>) {
+        let path = env::temp_dir().join(format!("sentry-presets-{}.toml", name));
+        let _ = fs::remove_file(&path);
+        let (bus_sink, bus_stream) = bus::new::<Message>();
+        (Presets::load(path, bus_sink).unwrap(), bus_stream)
+    }
+
+    fn next_message(bus_stream: &mut BusReceiver<Message>) -> MessageContent {
+        bus_stream.by_ref().wait().next().unwrap().unwrap().content
+    }
+
+    fn run(presets: &mut Presets, command: PresetCommand) -> Result<(), String> {
+        presets.handle_command(command, client_source(0), SocketAddr::from(CLIENT))
+    }
+
+    #[test]
+    fn saves_and_reloads_presets() {
+        let (mut presets, mut bus_stream) = presets("reload");
+        presets.position = Some((100, 200));
+        run(&mut presets, PresetCommand::Save("door".to_string())).unwrap();
+        presets.position = Some((300, 400));
+        run(&mut presets, PresetCommand::Save("alley".to_string())).unwrap();
+
+        match next_message(&mut bus_stream) {
+            MessageContent::Presets {
+                presets,
+                for_client: None,
+            } => assert_eq!(presets.len(), 1),
+            _ => panic!("Expected preset list"),
+        }
+
+        let (bus_sink, _) = bus::new::<Message>();
+        let reloaded = Presets::load(presets.path.clone(), bus_sink).unwrap();
+        let names: Vec<_> = reloaded.presets.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["alley", "door"]);
+        assert_eq!(
+            (reloaded.presets[1].pitch, reloaded.presets[1].yaw),
+            (100, 200)
+        );
+        fs::remove_file(&presets.path).unwrap();
+    }
+
+    #[test]
+    fn recalls_presets_as_the_requesting_client() {
+        let (mut presets, mut bus_stream) = presets("recall");
+        presets.position = Some((1234, 5678));
+        run(&mut presets, PresetCommand::Save("door".to_string())).unwrap();
+        next_message(&mut bus_stream);
+
+        run(&mut presets, PresetCommand::Recall("door".to_string())).unwrap();
+        let message = bus_stream.by_ref().wait().next().unwrap().unwrap();
+        match (message.source, message.content) {
+            (
+                MessageSource::Client(client),
+                MessageContent::Command(Command::MoveTo { pitch, yaw }),
+            ) => {
+                assert_eq!(client.address, SocketAddr::from(CLIENT));
+                assert_eq!((pitch, yaw), (1234, 5678));
+            }
+            _ => panic!("Expected MoveTo command"),
+        }
+        fs::remove_file(&presets.path).unwrap();
+    }
+
+    #[test]
+    fn only_the_client_in_control_changes_presets() {
+        let (mut presets, _bus_stream) = presets("queued");
+        presets.position = Some((100, 200));
+        run(&mut presets, PresetCommand::Save("door".to_string())).unwrap();
+
+        let mut run_queued =
+            |command| presets.handle_command(command, client_source(1), SocketAddr::from(CLIENT));
+        assert!(run_queued(PresetCommand::Save("door".to_string())).is_err());
+        assert!(run_queued(PresetCommand::Save("alley".to_string())).is_err());
+        assert!(run_queued(PresetCommand::Delete("door".to_string())).is_err());
+        assert!(run_queued(PresetCommand::List).is_ok());
+        assert!(run_queued(PresetCommand::Recall("door".to_string())).is_ok());
+
+        let names: Vec<_> = presets.presets.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["door"]);
+        fs::remove_file(&presets.path).unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_requests() {
+        let (mut presets, _bus_stream) = presets("invalid");
+        assert!(run(&mut presets, PresetCommand::Save("door".to_string())).is_err());
+        assert!(run(&mut presets, PresetCommand::Recall("door".to_string())).is_err());
+        assert!(run(&mut presets, PresetCommand::Delete("door".to_string())).is_err());
+        assert!(!presets.path.exists());
+    }
+}
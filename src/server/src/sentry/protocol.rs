@@ -0,0 +1,599 @@
+//! Messages exchanged with clients over the control connection. Each one is a JSON object whose
+//! `type` field says which message it is.
+//!
+//! A client starts by sending `hello` with the newest protocol version it speaks, and the server
+//! answers with the version both sides will use, followed by `auth` from the client if the server
+//! requires it. Once in the queue, clients have to send something at least every 3 seconds,
+//! which is what `ping` is for.
+//!
+//! `hello` and its reply are always JSON, but a client can ask for every message after them to be
+//! CBOR instead, which is smaller and quicker to parse. See [`Encoding`] for how each is framed.
+//!
+//! If the server has a UDP port, clients in the queue are sent a `udp_session` token, and can send
+//! moves there as [`MoveDatagram`]s instead, so a lost packet doesn't hold up the ones after it.
+//! The token never goes over UDP itself, only a MAC keyed by it.
+//! Everything else, including pings, stays on the control connection.
+//!
+//! The video isn't streamed until a client asks for it, with `start_rtp` for RTP/UDP or
+//! `start_webrtc` for WebRTC.
+//!
+//! `sentry protocol-schema` prints a JSON Schema of every message, see [`schema`].
+use crate::sentry::{BoundingBox, Device, HardwareStatus, SnapshotReason};
+use byteorder::{BigEndian, ByteOrder};
+use hmac::{Hmac, Mac};
+use schemars::gen::SchemaSettings;
+use schemars::schema::{Metadata, RootSchema, SchemaObject, SubschemaValidation};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use sha2::Sha256;
+use std::net::SocketAddr;
+
+/// Version of the protocol described here, which goes up whenever a change would break clients
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Oldest version the server can still speak. The untyped protocol before `hello` was version 1.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq)]
+// Requests without fields are empty structs rather than unit variants, as serde only rejects
+// unknown fields in the struct variants of an internally tagged enum
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Request {
+    Hello {
+        version: u32,
+        /// How the client wants messages after the handshake to be encoded
+        #[serde(default)]
+        encoding: Encoding,
+    },
+    /// Either the shared secret, or a user's token
+    Auth {
+        #[serde(default)]
+        user: Option<String>,
+        token: String,
+    },
+    Ping {},
+    /// Moves at a rate from -1 to 1 on each axis until told otherwise
+    Move {
+        pitch: f64,
+        yaw: f64,
+    },
+    /// Moves to a position, either in steps or in degrees
+    MoveTo {
+        #[serde(default)]
+        pitch: Option<u32>,
+        #[serde(default)]
+        yaw: Option<u32>,
+        #[serde(default)]
+        pitch_degrees: Option<f64>,
+        #[serde(default)]
+        yaw_degrees: Option<f64>,
+    },
+    Home {},
+    Fire {},
+    FireAndReload {},
+    Reload {},
+    ReleaseMagazine {},
+    LoadMagazine {},
+    MotorsOn {},
+    MotorsOff {},
+    /// Asks for the video over RTP/UDP, which starts with a `video_offer` to punch a hole with
+    StartRtp {},
+    /// Asks for the video over WebRTC instead of RTP/UDP
+    #[serde(rename = "start_webrtc")]
+    StartWebRtc {},
+    #[serde(rename = "webrtc_answer")]
+    WebRtcAnswer {
+        sdp: String,
+    },
+    #[serde(rename = "webrtc_ice_candidate")]
+    WebRtcIceCandidate {
+        candidate: String,
+        sdp_mline_index: u32,
+    },
+    StartPatrol {},
+    StopPatrol {},
+    StartTracking {},
+    StopTracking {},
+    StartRecording {},
+    StopRecording {},
+    Snapshot {},
+    ListPresets {},
+    SavePreset {
+        name: String,
+    },
+    DeletePreset {
+        name: String,
+    },
+    RecallPreset {
+        name: String,
+    },
+}
+
+/// How messages are encoded, and framed on each transport
+#[derive(Clone, Copy, Debug, Default, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    /// One JSON message per line over TCP, or per WebSocket text frame
+    #[default]
+    Json,
+    /// One CBOR message per WebSocket binary frame, or over TCP, prefixed with its length as a
+    /// 32-bit big-endian integer
+    Cbor,
+}
+
+impl Encoding {
+    pub fn decode<T: DeserializeOwned>(self, message: &[u8]) -> Result<T, String> {
+        match self {
+            Encoding::Json => serde_json::from_slice(message).map_err(|err| err.to_string()),
+            Encoding::Cbor => serde_cbor::from_slice(message).map_err(|err| err.to_string()),
+        }
+    }
+
+    pub fn encode(self, response: &Response) -> Vec<u8> {
+        match self {
+            Encoding::Json => serde_json::to_vec(response).expect("Could not serialize response"),
+            Encoding::Cbor => serde_cbor::to_vec(response).expect("Could not serialize response"),
+        }
+    }
+}
+
+/// A move sent over UDP, where datagrams can arrive late, out of order or not at all. Clients should
+/// keep sending their joystick's position, even when it's centred, so a lost datagram is soon made
+/// up for.
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+pub struct MoveDatagram {
+    /// Has to go up with every datagram, as any that arrive after a later one are dropped
+    pub sequence: u64,
+    pub pitch: f64,
+    pub yaw: f64,
+    /// HMAC-SHA256 of the sequence, pitch and yaw as big-endian `u64`, `f64` and `f64`, keyed by
+    /// the UTF-8 token from `udp_session`, in lowercase hex
+    pub mac: String,
+}
+
+impl MoveDatagram {
+    /// Creates a datagram signed with the client's token, as clients do
+    #[cfg(test)]
+    pub fn new(token: &str, sequence: u64, pitch: f64, yaw: f64) -> Self {
+        let mac = Self::mac(token, sequence, pitch, yaw)
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        MoveDatagram {
+            sequence,
+            pitch,
+            yaw,
+            mac,
+        }
+    }
+
+    /// Decodes a datagram as JSON or CBOR, whichever the control connection uses. A JSON object
+    /// always starts with `{`, which no CBOR map does.
+    pub fn decode(datagram: &[u8]) -> Result<Self, String> {
+        match datagram.first() {
+            Some(b'{') => Encoding::Json.decode(datagram),
+            _ => Encoding::Cbor.decode(datagram),
+        }
+    }
+
+    /// Whether the datagram was signed with the token, checked in constant time
+    pub fn verify(&self, token: &str) -> bool {
+        let mac = self.mac.as_bytes();
+        if mac.len() != 64 {
+            return false;
+        }
+        let digit = |hex: u8| char::from(hex).to_digit(16);
+        let mac: Option<Vec<u8>> = mac
+            .chunks(2)
+            .map(|hex| Some((digit(hex[0])? << 4 | digit(hex[1])?) as u8))
+            .collect();
+        mac.is_some_and(|mac| {
+            Self::mac(token, self.sequence, self.pitch, self.yaw)
+                .verify_slice(&mac)
+                .is_ok()
+        })
+    }
+
+    fn mac(token: &str, sequence: u64, pitch: f64, yaw: f64) -> Hmac<Sha256> {
+        let mut signed = [0; 24];
+        BigEndian::write_u64(&mut signed[0..8], sequence);
+        BigEndian::write_f64(&mut signed[8..16], pitch);
+        BigEndian::write_f64(&mut signed[16..24], yaw);
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(token.as_bytes()).expect("HMAC takes keys of any size");
+        mac.update(&signed);
+        mac
+    }
+}
+
+/// A saved position, in steps as well as degrees
+#[derive(Clone, Debug, JsonSchema, PartialEq, Serialize)]
+pub struct PresetPosition {
+    pub name: String,
+    pub pitch: u32,
+    pub yaw: u32,
+    pub pitch_degrees: f64,
+    pub yaw_degrees: f64,
+}
+
+#[derive(Clone, Copy, Debug, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Not valid JSON, an unknown type, or missing fields
+    InvalidMessage,
+    /// A well-formed message asking for something that can't be done
+    InvalidCommand,
+    UnsupportedVersion,
+    AuthFailed,
+    /// Firing isn't allowed where the turret is pointing
+    CommandBlocked,
+    Preset,
+    Video,
+}
+
+#[derive(Clone, Debug, JsonSchema, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Hello {
+        version: u32,
+        encoding: Encoding,
+        auth_required: bool,
+    },
+    Authenticated,
+    /// Where and how to send moves over UDP, sent once the client has joined the queue
+    UdpSession {
+        port: u16,
+        /// Key to sign move datagrams with, which is never sent over UDP itself
+        token: String,
+    },
+    /// Sent whenever a client joins or leaves the queue. Only the client at position 0 can
+    /// control the turret.
+    Queue {
+        position: usize,
+        num_clients: usize,
+    },
+    Status {
+        status: HardwareStatus,
+        pitch: u32,
+        yaw: u32,
+        pitch_degrees: f64,
+        yaw_degrees: f64,
+    },
+    /// Where to punch a hole to for the RTP/UDP video, with the nonce to send there
+    VideoOffer {
+        nonce: String,
+        rtp_address: SocketAddr,
+    },
+    /// The video is on its way, and can be decoded with this GStreamer pipeline
+    VideoStreaming {
+        gstreamer_command: String,
+    },
+    #[serde(rename = "webrtc_offer")]
+    WebRtcOffer {
+        sdp: String,
+    },
+    #[serde(rename = "webrtc_ice_candidate")]
+    WebRtcIceCandidate {
+        candidate: String,
+        sdp_mline_index: u32,
+    },
+    Presets {
+        presets: Vec<PresetPosition>,
+    },
+    Patrol {
+        active: bool,
+        paused: bool,
+    },
+    Tracking {
+        enabled: bool,
+        paused: bool,
+    },
+    Recording {
+        active: bool,
+    },
+    Snapshot {
+        file_name: String,
+        reason: SnapshotReason,
+        pitch_degrees: Option<f64>,
+        yaw_degrees: Option<f64>,
+    },
+    Motion {
+        region: String,
+        bbox: BoundingBox,
+    },
+    Device {
+        name: Device,
+        connected: bool,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+impl Response {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        Response::Error {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// Picks the version to speak with a client that speaks up to the given one
+pub fn negotiate(version: u32) -> Result<u32, Response> {
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(Response::error(
+            ErrorCode::UnsupportedVersion,
+            format!(
+                "Protocol version {} is no longer supported, the oldest supported is {}",
+                version, MIN_PROTOCOL_VERSION
+            ),
+        ));
+    }
+    Ok(version.min(PROTOCOL_VERSION))
+}
+
+/// Describes every message as a JSON Schema generated from the types above. A message is valid if
+/// it matches either `Request`, for those sent by clients, or `Response`, for those sent by the
+/// server, which are both in the definitions along with `MoveDatagram`.
+pub fn schema() -> RootSchema {
+    let mut generator = SchemaSettings::draft07().into_generator();
+    let request = generator.subschema_for::<Request>();
+    let response = generator.subschema_for::<Response>();
+    generator.subschema_for::<MoveDatagram>();
+    let mut schema = SchemaObject {
+        metadata: Some(Box::new(Metadata {
+            title: Some("Sentry protocol".to_string()),
+            description: Some(format!(
+                "Messages exchanged with clients over the control connection, as of protocol version {}",
+                PROTOCOL_VERSION
+            )),
+            ..Metadata::default()
+        })),
+        subschemas: Some(Box::new(SubschemaValidation {
+            one_of: Some(vec![request, response]),
+            ..SubschemaValidation::default()
+        })),
+        ..SchemaObject::default()
+    };
+    schema
+        .extensions
+        .insert("version".to_string(), PROTOCOL_VERSION.into());
+    RootSchema {
+        meta_schema: generator.settings().meta_schema.clone(),
+        definitions: generator.take_definitions(),
+        schema,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_tagged_requests() {
+        let parse = |json: &str| serde_json::from_str::<Request>(json);
+        assert_eq!(
+            parse(r#"{"type": "hello", "version": 2}"#).unwrap(),
+            Request::Hello {
+                version: 2,
+                encoding: Encoding::Json
+            }
+        );
+        assert_eq!(
+            parse(r#"{"type": "hello", "version": 2, "encoding": "cbor"}"#).unwrap(),
+            Request::Hello {
+                version: 2,
+                encoding: Encoding::Cbor
+            }
+        );
+        assert_eq!(
+            parse(r#"{"type": "auth", "token": "hunter2"}"#).unwrap(),
+            Request::Auth {
+                user: None,
+                token: "hunter2".to_string()
+            }
+        );
+        assert_eq!(
+            parse(r#"{"type": "move", "pitch": 0.5, "yaw": -1}"#).unwrap(),
+            Request::Move {
+                pitch: 0.5,
+                yaw: -1.0
+            }
+        );
+        assert_eq!(
+            parse(r#"{"type": "webrtc_answer", "sdp": "v=0"}"#).unwrap(),
+            Request::WebRtcAnswer {
+                sdp: "v=0".to_string()
+            }
+        );
+        assert_eq!(
+            parse(r#"{"type": "start_webrtc"}"#).unwrap(),
+            Request::StartWebRtc {}
+        );
+        assert_eq!(
+            parse(r#"{"type": "start_rtp"}"#).unwrap(),
+            Request::StartRtp {}
+        );
+
+        assert!(parse(r#"{"type": "self_destruct"}"#).is_err());
+        assert!(parse(r#"{"type": "move", "pitch": 0.5}"#).is_err());
+        assert!(parse(r#"{"command": "fire"}"#).is_err());
+        assert!(parse(r#"{"type": "ping", "force": true}"#).is_err());
+        assert!(parse(r#"{"type": "move", "pitch": 0.5, "yaw": 0, "speed": 2}"#).is_err());
+    }
+
+    #[test]
+    fn tags_responses() {
+        assert_eq!(
+            serde_json::to_value(Response::Queue {
+                position: 0,
+                num_clients: 2
+            })
+            .unwrap(),
+            json!({"type": "queue", "position": 0, "num_clients": 2})
+        );
+        assert_eq!(
+            serde_json::to_value(Response::Status {
+                status: HardwareStatus::HomingRequired,
+                pitch: 0,
+                yaw: 0,
+                pitch_degrees: -72.0,
+                yaw_degrees: 0.0,
+            })
+            .unwrap()["status"],
+            "homing_required"
+        );
+        assert_eq!(
+            serde_json::to_value(Response::error(ErrorCode::InvalidCommand, "No")).unwrap(),
+            json!({"type": "error", "code": "invalid_command", "message": "No"})
+        );
+    }
+
+    /// Validates a message against one of the schema's definitions
+    fn matches_schema(schema: &serde_json::Value, definition: &str, message: &str) -> bool {
+        let mut schema = schema.clone();
+        let root = schema.as_object_mut().unwrap();
+        root.remove("oneOf");
+        root.insert(
+            "$ref".to_string(),
+            format!("#/definitions/{}", definition).into(),
+        );
+        let validator = jsonschema::JSONSchema::compile(&schema).unwrap();
+        validator.is_valid(&serde_json::from_str(message).unwrap())
+    }
+
+    #[test]
+    fn describes_every_message() {
+        let schema = serde_json::to_value(schema()).unwrap();
+        assert_eq!(schema["version"], PROTOCOL_VERSION);
+        assert_eq!(
+            schema["oneOf"],
+            json!([
+                {"$ref": "#/definitions/Request"},
+                {"$ref": "#/definitions/Response"}
+            ])
+        );
+        assert!(schema["definitions"]["HardwareStatus"].is_object());
+        assert!(schema["definitions"]["MoveDatagram"].is_object());
+
+        // Messages as the Android app sends them
+        for message in &[
+            r#"{"type":"hello","version":2}"#,
+            r#"{"type":"ping"}"#,
+            r#"{"type":"start_rtp"}"#,
+            r#"{"type":"move","pitch":0.500,"yaw":-0.250}"#,
+            r#"{"type":"fire_and_reload"}"#,
+            r#"{"type":"release_magazine"}"#,
+            r#"{"type":"motors_off"}"#,
+        ] {
+            assert!(matches_schema(&schema, "Request", message), "{}", message);
+        }
+        for message in &[
+            r#"{"type":"fire_and_forget"}"#,
+            r#"{"type":"move","pitch":0.5}"#,
+            r#"{"type":"ping","force":true}"#,
+        ] {
+            assert!(!matches_schema(&schema, "Request", message), "{}", message);
+        }
+
+        let response = Response::Queue {
+            position: 1,
+            num_clients: 2,
+        };
+        assert!(matches_schema(
+            &schema,
+            "Response",
+            &String::from_utf8(Encoding::Json.encode(&response)).unwrap()
+        ));
+    }
+
+    #[test]
+    fn encodes_cbor() {
+        let mut request = std::collections::BTreeMap::new();
+        request.insert("type", serde_cbor::Value::Text("move".to_string()));
+        request.insert("pitch", serde_cbor::Value::Float(0.5));
+        // Whole numbers are fine where floats are expected
+        request.insert("yaw", serde_cbor::Value::Integer(-1));
+        assert_eq!(
+            Encoding::Cbor.decode(&serde_cbor::to_vec(&request).unwrap()),
+            Ok(Request::Move {
+                pitch: 0.5,
+                yaw: -1.0
+            })
+        );
+        assert!(Encoding::Cbor
+            .decode::<Request>(br#"{"type": "ping"}"#)
+            .is_err());
+
+        let response = Response::Queue {
+            position: 1,
+            num_clients: 2,
+        };
+        let encoded = Encoding::Cbor.encode(&response);
+        assert!(encoded.len() < Encoding::Json.encode(&response).len());
+        let value: serde_cbor::Value = serde_cbor::from_slice(&encoded).unwrap();
+        match value {
+            serde_cbor::Value::Map(fields) => assert_eq!(
+                fields[&serde_cbor::Value::Text("type".to_string())],
+                serde_cbor::Value::Text("queue".to_string())
+            ),
+            other => panic!("Expected a map, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decodes_datagrams_in_either_encoding() {
+        let datagram = MoveDatagram::new("abc", 7, 0.5, -0.25);
+        let json = serde_json::to_vec(&datagram).unwrap();
+        let cbor = serde_cbor::to_vec(&datagram).unwrap();
+        assert_eq!(MoveDatagram::decode(&json), Ok(datagram.clone()));
+        assert_eq!(MoveDatagram::decode(&cbor), Ok(datagram));
+        assert!(MoveDatagram::decode(br#"{"type": "fire"}"#).is_err());
+        assert!(MoveDatagram::decode(b"").is_err());
+    }
+
+    #[test]
+    fn signs_datagrams() {
+        let datagram = MoveDatagram::new("abc", 7, 0.5, -0.25);
+        assert_eq!(
+            datagram.mac,
+            "87bfbc6270aceba9adf7ec66acb63c97131f42443f1484c404f830197910b103"
+        );
+        assert!(datagram.verify("abc"));
+        assert!(!datagram.verify("abd"));
+        assert!(!MoveDatagram {
+            sequence: 8,
+            ..datagram.clone()
+        }
+        .verify("abc"));
+        assert!(!MoveDatagram {
+            yaw: 1.0,
+            ..datagram.clone()
+        }
+        .verify("abc"));
+        assert!(!MoveDatagram {
+            mac: datagram.mac[..62].to_string(),
+            ..datagram.clone()
+        }
+        .verify("abc"));
+        assert!(!MoveDatagram {
+            mac: "zz".repeat(32),
+            ..datagram
+        }
+        .verify("abc"));
+    }
+
+    #[test]
+    fn negotiates_versions() {
+        assert_eq!(negotiate(PROTOCOL_VERSION), Ok(PROTOCOL_VERSION));
+        assert_eq!(negotiate(PROTOCOL_VERSION + 3), Ok(PROTOCOL_VERSION));
+        match negotiate(1) {
+            Err(Response::Error { code, .. }) => assert_eq!(code, ErrorCode::UnsupportedVersion),
+            other => panic!("Expected an error, got {:?}", other),
+        }
+    }
+}
@@ -1,20 +1,74 @@
 use crate::sentry::bus::BusSender;
-use crate::sentry::config::Config;
-use crate::sentry::{Bus, Client, Command, HardwareStatus, Message, MessageContent, MessageSource};
+use crate::sentry::config::{self, Config, TcpServerConfig, TlsConfig};
+use crate::sentry::protocol::{
+    self, Encoding, ErrorCode, MoveDatagram, PresetPosition, Request, Response,
+};
+use crate::sentry::{
+    Bus, Client, Command, CommandOutcome, Message, MessageContent, MessageSource, PresetCommand,
+};
+use futures::future::Either;
 use futures::sync::mpsc::{unbounded, UnboundedSender};
-use serde_json::json;
+use native_tls::Identity;
+use rand::prelude::*;
 use std::cell::Cell;
-use std::net::SocketAddr;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant, SystemTime};
-use tokio::codec::{Decoder, LinesCodec};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::codec::Decoder;
+use tokio::net::TcpListener;
 use tokio::prelude::*;
 use tokio::timer::Interval;
+use tokio_tls::TlsAcceptor;
+use tokio_tungstenite::accept_async;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+mod framing;
+mod udp;
+use framing::MessageCodec;
+
+/// How long a client has to send its hello message after connecting
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a client has to send its auth message after the handshake
+const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a client has to complete the TLS and WebSocket handshakes after connecting
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Encoded messages from a client, whichever transport they arrive over
+type ClientSource = Box<dyn Stream<Item = Vec<u8>, Error = String> + Send>;
+/// Encoded messages to a client, whichever transport they are sent over
+type ClientSink = Box<dyn Sink<SinkItem = Vec<u8>, SinkError = String> + Send>;
+/// How a connection's messages are framed, which is switched once the handshake agrees on it
+type SharedEncoding = Arc<Mutex<Encoding>>;
+
+/// How messages are framed on a listener's connections
+#[derive(Clone, Copy)]
+enum Transport {
+    /// Newline-delimited JSON or length-prefixed CBOR over a raw socket
+    Lines,
+    /// One message per WebSocket frame, for browser clients
+    WebSocket,
+}
+
+impl Transport {
+    fn name(self) -> &'static str {
+        match self {
+            Transport::Lines => "TCP",
+            Transport::WebSocket => "WebSocket",
+        }
+    }
+}
 
 struct ClientTx {
     address: SocketAddr,
-    tx: UnboundedSender<String>,
+    encoding: Encoding,
+    tx: UnboundedSender<Vec<u8>>,
+    /// Token the client's move datagrams have to be signed with
+    udp_token: String,
+    /// Sequence number of the last move datagram accepted from the client
+    last_sequence: Option<u64>,
 }
 
 pub struct ClientQueue {
@@ -32,8 +86,20 @@ impl ClientQueue {
         self.clients.len()
     }
 
-    fn enqueue(&mut self, address: SocketAddr, tx: UnboundedSender<String>) -> usize {
-        self.clients.push(ClientTx { address, tx });
+    fn enqueue(
+        &mut self,
+        address: SocketAddr,
+        encoding: Encoding,
+        tx: UnboundedSender<Vec<u8>>,
+        udp_token: String,
+    ) -> usize {
+        self.clients.push(ClientTx {
+            address,
+            encoding,
+            tx,
+            udp_token,
+            last_sequence: None,
+        });
         self.send_client_states();
         self.clients.len() - 1
     }
@@ -56,9 +122,30 @@ impl ClientQueue {
         self.clients.iter().position(|c| c.address == client)
     }
 
-    pub fn send(&mut self, client: SocketAddr, message: String) {
+    /// Finds the client a move datagram is from, which has to be sent from the same host as their
+    /// control connection and signed with their token, unless it arrived after a later one
+    fn accept_datagram(&mut self, sender: IpAddr, datagram: &MoveDatagram) -> Option<Client> {
+        let queue_position = self
+            .clients
+            .iter()
+            .position(|c| c.address.ip() == sender && datagram.verify(&c.udp_token))?;
+        let client = &mut self.clients[queue_position];
+        if client
+            .last_sequence
+            .is_some_and(|last| datagram.sequence <= last)
+        {
+            return None;
+        }
+        client.last_sequence = Some(datagram.sequence);
+        Some(Client {
+            address: client.address,
+            queue_position,
+        })
+    }
+
+    pub fn send(&mut self, client: SocketAddr, message: &Response) {
         if let Some(client) = self.clients.iter().find(|c| c.address == client) {
-            if let Err(err) = client.tx.unbounded_send(message) {
+            if let Err(err) = client.tx.unbounded_send(client.encoding.encode(message)) {
                 error!(
                     "Failed to send message to client {}: {}",
                     client.address, err
@@ -77,10 +164,10 @@ impl ClientQueue {
         }
     }
 
-    pub fn send_to_all(&mut self, message: String) {
+    pub fn send_to_all(&mut self, message: &Response) {
         let len = self.clients.len();
         for i in 0..len {
-            self.send(self.clients[i].address, message.to_owned());
+            self.send(self.clients[i].address, message);
         }
     }
 
@@ -89,11 +176,10 @@ impl ClientQueue {
         for i in 0..len {
             self.send(
                 self.clients[i].address,
-                json!({
-                    "queue_position": i,
-                    "num_clients": len,
-                })
-                .to_string(),
+                &Response::Queue {
+                    position: i,
+                    num_clients: len,
+                },
             );
         }
     }
@@ -102,107 +188,50 @@ impl ClientQueue {
 pub fn start(config: Config, bus: Bus<Message>) -> impl Future<Item = (), Error = String> {
     let (bus_sink, bus_stream) = bus;
     let clients = Arc::new(RwLock::new(ClientQueue::new()));
-    let addr = SocketAddr::new(
-        config.server.host.as_str().parse().unwrap(),
-        config.server.port,
-    );
+    let host = config.server.host.as_str().parse().unwrap();
+    let mut listeners = vec![(SocketAddr::new(host, config.server.port), Transport::Lines)];
+    if let Some(port) = config.server.websocket_port {
+        listeners.push((SocketAddr::new(host, port), Transport::WebSocket));
+    }
 
-    info!("Binding TCP server on {}...", addr);
+    let udp = match config.server.udp_port {
+        Some(port) => Either::A(udp::listen(
+            SocketAddr::new(host, port),
+            bus_sink.clone(),
+            clients.clone(),
+        )),
+        None => Either::B(future::ok(())),
+    };
 
-    // Listen for incoming connections
-    future::result(TcpListener::bind(&addr))
-        .map_err(|err| format!("Could not bind TCP server: {}", err))
+    // Listen for incoming connections on every configured transport, sharing the client queue
+    future::result(config.server.tls.as_ref().map(tls_acceptor).transpose())
         .and_then({
             let clients = clients.clone();
-            move |listener| {
-                listener
-                    .incoming()
-                    .map_err(|err| format!("TCP client connection error: {}", err))
-                    .for_each(move |socket| {
-                        info!(
-                            "Incoming TCP connection from {}",
-                            socket.peer_addr().unwrap()
-                        );
-                        tokio::spawn(handle_client(socket, bus_sink.clone(), clients.clone()));
-                        Ok(())
-                    })
+            let config = config.clone();
+            move |tls_acceptor| {
+                future::join_all(listeners.into_iter().map(move |(addr, transport)| {
+                    listen(
+                        addr,
+                        transport,
+                        tls_acceptor.clone(),
+                        config.clone(),
+                        bus_sink.clone(),
+                        clients.clone(),
+                    )
+                }))
             }
         })
+        .join(udp)
         .map(|_| ())
         .select(
             bus_stream
                 .map_err(|_| format!("Failed to read from bus"))
                 .for_each(move |message| {
-                    match message.content {
-                        MessageContent::VideoOffer {
-                            nonce,
-                            for_client,
-                            rtp_address,
-                        } => {
-                            clients.write().unwrap().send(
-                                for_client,
-                                json!({
-                                    "video_offer": {
-                                        "nonce": nonce,
-                                        "rtp_address": rtp_address,
-                                    }
-                                })
-                                .to_string(),
-                            );
-                        }
-                        MessageContent::VideoStreaming { for_client } => {
-                            clients.write().unwrap().send(
-                                for_client,
-                                json!({
-                                    "video_streaming": {
-                                        "gstreamer_command": config.video.decoder,
-                                    }
-                                })
-                                .to_string(),
-                            );
-                        }
-                        MessageContent::VideoError {
-                            message,
-                            for_client,
-                        } => {
-                            let json = json!({
-                                "video_error": {
-                                    "message": message,
-                                }
-                            })
-                            .to_string();
-
-                            if let Some(client) = for_client {
-                                clients.write().unwrap().send(client, json);
-                            } else {
-                                clients.write().unwrap().send_to_all(json);
-                            }
+                    if let Some((recipient, response)) = response(message.content, &config) {
+                        match recipient {
+                            Some(client) => clients.write().unwrap().send(client, &response),
+                            None => clients.write().unwrap().send_to_all(&response),
                         }
-                        MessageContent::HardwareState {
-                            pitch_pos,
-                            yaw_pos,
-                            status,
-                        } => {
-                            clients.write().unwrap().send_to_all(
-                                json!({
-                                    "status": match status {
-                                        HardwareStatus::Ready => "ready",
-                                        HardwareStatus::NotLoaded => "not_loaded",
-                                        HardwareStatus::MagazineReleased => "magazine_released",
-                                        HardwareStatus::Reloading => "reloading",
-                                        HardwareStatus::HomingRequired => "homing_required",
-                                        HardwareStatus::Homing => "homing",
-                                        HardwareStatus::MotorsOff => "motors_off",
-                                        HardwareStatus::HomingFailed => "homing_failed",
-                                        HardwareStatus::Error => "error",
-                                    },
-                                    "pitch": pitch_pos,
-                                    "yaw": yaw_pos,
-                                })
-                                .to_string(),
-                            );
-                        }
-                        _ => {}
                     }
                     Ok(())
                 }),
@@ -211,15 +240,488 @@ pub fn start(config: Config, bus: Bus<Message>) -> impl Future<Item = (), Error
         .map_err(|(err, _)| err)
 }
 
+/// Returns what to tell clients about a bus message, and which client to tell if not all of them
+fn response(content: MessageContent, config: &Config) -> Option<(Option<SocketAddr>, Response)> {
+    let arduino = &config.arduino;
+    Some(match content {
+        MessageContent::VideoOffer {
+            nonce,
+            for_client,
+            rtp_address,
+        } => (
+            Some(for_client),
+            Response::VideoOffer { nonce, rtp_address },
+        ),
+        MessageContent::VideoStreaming { for_client } => (
+            Some(for_client),
+            Response::VideoStreaming {
+                gstreamer_command: config.video.decoder.clone(),
+            },
+        ),
+        MessageContent::VideoError {
+            message,
+            for_client,
+        } => (for_client, Response::error(ErrorCode::Video, message)),
+        MessageContent::WebRtcOffer { sdp, for_client } => {
+            (Some(for_client), Response::WebRtcOffer { sdp })
+        }
+        MessageContent::WebRtcIceCandidate {
+            candidate,
+            sdp_mline_index,
+            for_client: Some(for_client),
+        } => (
+            Some(for_client),
+            Response::WebRtcIceCandidate {
+                candidate,
+                sdp_mline_index,
+            },
+        ),
+        MessageContent::Presets {
+            presets,
+            for_client,
+        } => (
+            for_client,
+            Response::Presets {
+                presets: presets
+                    .into_iter()
+                    .map(|preset| PresetPosition {
+                        pitch_degrees: arduino.pitch.to_degrees(preset.pitch),
+                        yaw_degrees: arduino.yaw.to_degrees(preset.yaw),
+                        name: preset.name,
+                        pitch: preset.pitch,
+                        yaw: preset.yaw,
+                    })
+                    .collect(),
+            },
+        ),
+        MessageContent::PatrolState { active, paused } => {
+            (None, Response::Patrol { active, paused })
+        }
+        MessageContent::TrackingState { enabled, paused } => {
+            (None, Response::Tracking { enabled, paused })
+        }
+        MessageContent::Snapshot {
+            file_name,
+            pitch,
+            yaw,
+            reason,
+        } => (
+            None,
+            Response::Snapshot {
+                file_name,
+                reason,
+                pitch_degrees: pitch.map(|pitch| arduino.pitch.to_degrees(pitch)),
+                yaw_degrees: yaw.map(|yaw| arduino.yaw.to_degrees(yaw)),
+            },
+        ),
+        MessageContent::MotionDetected {
+            region,
+            bbox,
+            alert: true,
+        } => (None, Response::Motion { region, bbox }),
+        MessageContent::RecordingState { active } => (None, Response::Recording { active }),
+        MessageContent::PresetError {
+            message,
+            for_client,
+        } => (
+            Some(for_client),
+            Response::error(ErrorCode::Preset, message),
+        ),
+        MessageContent::CommandProcessed {
+            source: MessageSource::Client(client),
+            outcome: CommandOutcome::Blocked { reason },
+            ..
+        } => (
+            Some(client.address),
+            Response::error(
+                ErrorCode::CommandBlocked,
+                format!("Cannot fire because {}", reason),
+            ),
+        ),
+        MessageContent::DeviceConnected(device) => (
+            None,
+            Response::Device {
+                name: device,
+                connected: true,
+            },
+        ),
+        MessageContent::DeviceDisconnected(device) => (
+            None,
+            Response::Device {
+                name: device,
+                connected: false,
+            },
+        ),
+        MessageContent::HardwareState {
+            pitch_pos,
+            yaw_pos,
+            status,
+            ..
+        } => (
+            None,
+            Response::Status {
+                status,
+                pitch: pitch_pos,
+                yaw: yaw_pos,
+                pitch_degrees: arduino.pitch.to_degrees(pitch_pos),
+                yaw_degrees: arduino.yaw.to_degrees(yaw_pos),
+            },
+        ),
+        _ => return None,
+    })
+}
+
+fn listen(
+    addr: SocketAddr,
+    transport: Transport,
+    tls_acceptor: Option<TlsAcceptor>,
+    config: Config,
+    bus_sink: BusSender<Message>,
+    clients: Arc<RwLock<ClientQueue>>,
+) -> impl Future<Item = (), Error = String> {
+    info!("Binding {} server on {}...", transport.name(), addr);
+
+    future::result(TcpListener::bind(&addr))
+        .map_err(move |err| format!("Could not bind {} server: {}", transport.name(), err))
+        .and_then(move |listener| {
+            listener
+                .incoming()
+                .map_err(move |err| {
+                    format!("{} client connection error: {}", transport.name(), err)
+                })
+                .for_each(move |socket| {
+                    let addr = socket.peer_addr().unwrap();
+                    info!("Incoming {} connection from {}", transport.name(), addr);
+                    let framed = match &tls_acceptor {
+                        Some(tls_acceptor) => Either::A(
+                            tls_acceptor
+                                .accept(socket)
+                                .map_err(|err| format!("TLS handshake failed: {}", err))
+                                .and_then(move |socket| frame(socket, transport)),
+                        ),
+                        None => Either::B(frame(socket, transport)),
+                    };
+                    tokio::spawn(
+                        framed
+                            .timeout(HANDSHAKE_TIMEOUT)
+                            .map_err(move |err| {
+                                let err = err
+                                    .into_inner()
+                                    .unwrap_or_else(|| "handshake timed out".to_string());
+                                warn!("Dropping client {}: {}", addr, err);
+                            })
+                            .and_then({
+                                let config = config.clone();
+                                let bus_sink = bus_sink.clone();
+                                let clients = clients.clone();
+                                move |(client_source, client_sink, encoding)| {
+                                    handle_client(
+                                        addr,
+                                        client_source,
+                                        client_sink,
+                                        encoding,
+                                        config,
+                                        bus_sink,
+                                        clients,
+                                    )
+                                }
+                            }),
+                    );
+                    Ok(())
+                })
+        })
+}
+
+/// Splits a connection into streams of messages, performing the WebSocket handshake if needed.
+/// Messages are framed for JSON until the encoding returned alongside them is switched.
+fn frame<S: AsyncRead + AsyncWrite + Send + 'static>(
+    socket: S,
+    transport: Transport,
+) -> impl Future<Item = (ClientSource, ClientSink, SharedEncoding), Error = String> {
+    let encoding = Arc::new(Mutex::new(Encoding::Json));
+    match transport {
+        Transport::Lines => {
+            let (client_sink, client_source) =
+                MessageCodec::new(encoding.clone()).framed(socket).split();
+            Either::A(future::ok((
+                Box::new(client_source.map_err(|err| err.to_string())) as ClientSource,
+                Box::new(client_sink.sink_map_err(|err| err.to_string())) as ClientSink,
+                encoding,
+            )))
+        }
+        Transport::WebSocket => Either::B(
+            accept_async(socket)
+                .map_err(|err| format!("WebSocket handshake failed: {}", err))
+                .map(|websocket| {
+                    let (client_sink, client_source) = websocket.split();
+                    let client_source = client_source
+                        .map_err(|err| err.to_string())
+                        // Pings and pongs are answered by the WebSocket itself
+                        .filter_map(|message| match message {
+                            WsMessage::Text(text) => Some(text.into_bytes()),
+                            WsMessage::Binary(data) => Some(data),
+                            _ => None,
+                        });
+                    let client_sink = client_sink.sink_map_err(|err| err.to_string()).with({
+                        let encoding = encoding.clone();
+                        move |message: Vec<u8>| match *encoding.lock().unwrap() {
+                            Encoding::Json => String::from_utf8(message)
+                                .map(WsMessage::Text)
+                                .map_err(|err| err.to_string()),
+                            Encoding::Cbor => Ok(WsMessage::Binary(message)),
+                        }
+                    });
+                    (
+                        Box::new(client_source) as ClientSource,
+                        Box::new(client_sink) as ClientSink,
+                        encoding,
+                    )
+                }),
+        ),
+    }
+}
+
+/// Loads the server certificate and private key for accepting TLS connections
+pub fn tls_acceptor(config: &TlsConfig) -> Result<TlsAcceptor, String> {
+    let read = |name: &str| {
+        config::file_path(name).and_then(|path| {
+            fs::read(&path).map_err(|err| format!("Could not read \"{}\": {}", path.display(), err))
+        })
+    };
+    let identity = Identity::from_pkcs8(&read(&config.cert)?, &read(&config.key)?)
+        .map_err(|err| format!("Invalid TLS certificate or key: {}", err))?;
+    native_tls::TlsAcceptor::new(identity)
+        .map(TlsAcceptor::from)
+        .map_err(|err| format!("Could not create TLS acceptor: {}", err))
+}
+
+/// Compares secrets without returning early, so the time taken doesn't reveal how much of a guess was right
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Checks the credentials in a client's auth message, returning who they authenticated as
+fn check_credentials(
+    message: &[u8],
+    encoding: Encoding,
+    config: &TcpServerConfig,
+) -> Result<String, String> {
+    let (user, token) = match encoding.decode(message) {
+        Ok(Request::Auth { user, token }) => (user, token),
+        Ok(_) => return Err("first message was not an auth message".to_string()),
+        Err(err) => return Err(format!("invalid auth message: {}", err)),
+    };
+
+    match user {
+        Some(user) => match config.users.get(&user) {
+            Some(expected) if constant_time_eq(expected, &token) => Ok(format!("user '{}'", user)),
+            _ => Err(format!("invalid token for user '{}'", user)),
+        },
+        None => match &config.secret {
+            Some(secret) if constant_time_eq(secret, &token) => Ok("shared secret".to_string()),
+            _ => Err("invalid shared secret".to_string()),
+        },
+    }
+}
+
+/// Reads a client's hello message, returning the version to speak with it and the encoding it
+/// asked for
+fn check_hello(message: &[u8]) -> Result<(u32, Encoding), Response> {
+    match serde_json::from_slice::<Request>(message) {
+        Ok(Request::Hello { version, encoding }) => {
+            protocol::negotiate(version).map(|version| (version, encoding))
+        }
+        Ok(_) => Err(Response::error(
+            ErrorCode::InvalidMessage,
+            "The first message has to be hello",
+        )),
+        Err(err) => Err(Response::error(
+            ErrorCode::InvalidMessage,
+            format!("Invalid hello message: {}", err),
+        )),
+    }
+}
+
+/// Waits for the next message from a client, for at most `timeout`
+fn next_message(
+    client_source: ClientSource,
+    timeout: Duration,
+    what: &'static str,
+) -> impl Future<Item = (Vec<u8>, ClientSource), Error = String> {
+    client_source
+        .into_future()
+        .map_err(move |(err, _)| format!("could not read {} message: {}", what, err))
+        .timeout(timeout)
+        .map_err(move |err| {
+            err.into_inner().unwrap_or_else(|| {
+                format!(
+                    "did not send {} message within {} seconds",
+                    what,
+                    timeout.as_secs()
+                )
+            })
+        })
+        .and_then(move |(message, client_source)| match message {
+            Some(message) => Ok((message, client_source)),
+            None => Err(format!("disconnected before sending {} message", what)),
+        })
+}
+
+/// Agrees on a protocol version and encoding with the client, which has to start by sending hello.
+/// Both hello messages are JSON, and the connection switches to the encoding right after them.
+fn handshake(
+    addr: SocketAddr,
+    client_source: ClientSource,
+    client_sink: ClientSink,
+    shared_encoding: SharedEncoding,
+    config: &TcpServerConfig,
+) -> impl Future<Item = (ClientSource, ClientSink, Encoding), Error = String> {
+    let auth_required = config.requires_auth();
+    next_message(client_source, HELLO_TIMEOUT, "hello").and_then(move |(message, client_source)| {
+        match check_hello(&message) {
+            Ok((version, encoding)) => {
+                info!(
+                    "Client {} speaks protocol version {} encoded as {:?}",
+                    addr, version, encoding
+                );
+                let response = Response::Hello {
+                    version,
+                    encoding,
+                    auth_required,
+                };
+                Either::A(
+                    client_sink
+                        .send(Encoding::Json.encode(&response))
+                        .map(move |client_sink| {
+                            *shared_encoding.lock().unwrap() = encoding;
+                            (client_source, client_sink, encoding)
+                        })
+                        .map_err(|err| format!("could not send hello response: {}", err)),
+                )
+            }
+            Err(response) => {
+                let err = match &response {
+                    Response::Error { message, .. } => message.clone(),
+                    _ => "handshake failed".to_string(),
+                };
+                Either::B(
+                    client_sink
+                        .send(Encoding::Json.encode(&response))
+                        .then(|_| Err(err)),
+                )
+            }
+        }
+    })
+}
+
+/// Waits for the client's auth message if the server requires one
+fn authenticate(
+    addr: SocketAddr,
+    client_source: ClientSource,
+    client_sink: ClientSink,
+    encoding: Encoding,
+    config: &TcpServerConfig,
+) -> impl Future<Item = (ClientSource, ClientSink), Error = String> {
+    if !config.requires_auth() {
+        return Either::A(future::ok((client_source, client_sink)));
+    }
+
+    let config = config.clone();
+    Either::B(next_message(client_source, AUTH_TIMEOUT, "auth").and_then(
+        move |(message, client_source)| {
+            match check_credentials(&message, encoding, &config) {
+                Ok(identity) => {
+                    info!("Client {} authenticated with {}", addr, identity);
+                    Either::A(
+                        client_sink
+                            .send(encoding.encode(&Response::Authenticated))
+                            .map(|client_sink| (client_source, client_sink))
+                            .map_err(|err| format!("could not send auth response: {}", err)),
+                    )
+                }
+                Err(err) => Either::B(
+                    client_sink
+                        .send(encoding.encode(&Response::error(
+                            ErrorCode::AuthFailed,
+                            "Authentication failed",
+                        )))
+                        .then(|_| Err(err)),
+                ),
+            }
+        },
+    ))
+}
+
 fn handle_client(
-    socket: TcpStream,
+    addr: SocketAddr,
+    client_source: ClientSource,
+    client_sink: ClientSink,
+    shared_encoding: SharedEncoding,
+    config: Config,
+    bus_sink: BusSender<Message>,
+    clients: Arc<RwLock<ClientQueue>>,
+) -> impl Future<Item = (), Error = ()> {
+    // Clients only join the queue once they have agreed on a protocol and authenticated
+    handshake(
+        addr,
+        client_source,
+        client_sink,
+        shared_encoding,
+        &config.server,
+    )
+    .and_then({
+        let config = config.server.clone();
+        move |(client_source, client_sink, encoding)| {
+            authenticate(addr, client_source, client_sink, encoding, &config)
+                .map(move |(client_source, client_sink)| (client_source, client_sink, encoding))
+        }
+    })
+    .map_err(move |err| warn!("Dropping client {}: {}", addr, err))
+    .and_then(move |(client_source, client_sink, encoding)| {
+        serve_client(
+            addr,
+            client_source,
+            client_sink,
+            encoding,
+            config,
+            bus_sink,
+            clients,
+        )
+    })
+}
+
+fn serve_client(
+    addr: SocketAddr,
+    client_source: ClientSource,
+    client_sink: ClientSink,
+    encoding: Encoding,
+    config: Config,
     bus_sink: BusSender<Message>,
     clients: Arc<RwLock<ClientQueue>>,
 ) -> impl Future<Item = (), Error = ()> {
-    let addr = socket.peer_addr().unwrap();
-    let (client_sink, client_source) = LinesCodec::new().framed(socket).split();
-    let (proxy_tx, proxy_rx) = unbounded::<String>();
-    let queue_position = clients.write().unwrap().enqueue(addr, proxy_tx);
+    let (proxy_tx, proxy_rx) = unbounded::<Vec<u8>>();
+    let udp_token: String = thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(32)
+        .collect();
+    let queue_position =
+        clients
+            .write()
+            .unwrap()
+            .enqueue(addr, encoding, proxy_tx, udp_token.clone());
+    if let Some(port) = config.server.udp_port {
+        clients.write().unwrap().send(
+            addr,
+            &Response::UdpSession {
+                port,
+                token: udp_token,
+            },
+        );
+    }
     let last_message_time = Arc::new(Mutex::new(Cell::new(SystemTime::now())));
 
     info!(
@@ -244,9 +746,9 @@ fn handle_client(
         // Only accept messages from the active client
         .filter_map({
             let clients = clients.clone();
-            move |message| {
-                match process_message(message) {
-                    Some(content) => Some(Message {
+            move |message: Vec<u8>| {
+                match process_message(&message, encoding, &config) {
+                    Ok(content) => Some(Message {
                         content,
                         source: MessageSource::Client(Client {
                             address: addr,
@@ -255,7 +757,13 @@ fn handle_client(
                                 .unwrap_or(std::usize::MAX),
                         })
                     }),
-                    _ => None
+                    Err(response) => {
+                        if let Response::Error { ref message, .. } = response {
+                            warn!("Rejected message from client {}: {}", addr, message);
+                        }
+                        clients.write().unwrap().send(addr, &response);
+                        None
+                    }
                 }
             }
         })
@@ -338,36 +846,523 @@ fn handle_client(
         })
 }
 
-fn process_message(message: String) -> Option<MessageContent> {
-    use serde_json::Value::{Number as JsonNumber, String as JsonString};
-
-    if let Ok(json) = serde_json::from_str::<serde_json::Value>(message.as_str()) {
-        if let JsonString(command) = &json["command"] {
-            match command.as_str() {
-                "fire" => Some(MessageContent::Command(Command::Fire)),
-                "release_magazine" => Some(MessageContent::Command(Command::ReleaseMagazine)),
-                "load_magazine" => Some(MessageContent::Command(Command::LoadMagazine)),
-                "reload" => Some(MessageContent::Command(Command::Reload)),
-                "fire_and_reload" => Some(MessageContent::Command(Command::FireAndReload)),
-                "home" => Some(MessageContent::Command(Command::Home)),
-                "motors_on" => Some(MessageContent::Command(Command::MotorsOn)),
-                "motors_off" => Some(MessageContent::Command(Command::MotorsOff)),
-                _ => {
-                    warn!("Received invalid command '{}' from client", command);
-                    None
-                }
+/// Turns a message from a client into the bus message it stands for, or the error to reply with
+fn process_message(
+    message: &[u8],
+    encoding: Encoding,
+    config: &Config,
+) -> Result<MessageContent, Response> {
+    let request = encoding.decode(message).map_err(|err| {
+        Response::error(
+            ErrorCode::InvalidMessage,
+            format!("Invalid message: {}", err),
+        )
+    })?;
+    let command = |command| Ok(MessageContent::Command(command));
+    match request {
+        Request::Hello { .. } | Request::Auth { .. } => Err(Response::error(
+            ErrorCode::InvalidMessage,
+            "The handshake has already been completed",
+        )),
+        Request::Ping {} => Ok(MessageContent::Ping),
+        Request::Move { pitch, yaw } => command(Command::Move { pitch, yaw }),
+        Request::MoveTo {
+            pitch,
+            yaw,
+            pitch_degrees,
+            yaw_degrees,
+        } => match (pitch, yaw, pitch_degrees, yaw_degrees) {
+            (Some(pitch), Some(yaw), _, _) => command(Command::MoveTo { pitch, yaw }),
+            (_, _, Some(pitch), Some(yaw)) => command(Command::MoveTo {
+                pitch: config.arduino.pitch.to_steps(pitch),
+                yaw: config.arduino.yaw.to_steps(yaw),
+            }),
+            _ => Err(Response::error(
+                ErrorCode::InvalidCommand,
+                "move_to needs pitch and yaw, either in steps or in degrees",
+            )),
+        },
+        Request::Home {} => command(Command::Home),
+        Request::Fire {} => command(Command::Fire),
+        Request::FireAndReload {} => command(Command::FireAndReload),
+        Request::Reload {} => command(Command::Reload),
+        Request::ReleaseMagazine {} => command(Command::ReleaseMagazine),
+        Request::LoadMagazine {} => command(Command::LoadMagazine),
+        Request::MotorsOn {} => command(Command::MotorsOn),
+        Request::MotorsOff {} => command(Command::MotorsOff),
+        Request::StartRtp {} => Ok(MessageContent::StartRtp),
+        Request::StartWebRtc {} => Ok(MessageContent::StartWebRtc),
+        Request::WebRtcAnswer { sdp } => Ok(MessageContent::WebRtcAnswer { sdp }),
+        Request::WebRtcIceCandidate {
+            candidate,
+            sdp_mline_index,
+        } => Ok(MessageContent::WebRtcIceCandidate {
+            candidate,
+            sdp_mline_index,
+            for_client: None,
+        }),
+        Request::StartPatrol {} => Ok(MessageContent::StartPatrol),
+        Request::StopPatrol {} => Ok(MessageContent::StopPatrol),
+        Request::StartTracking {} => Ok(MessageContent::StartTracking),
+        Request::StopTracking {} => Ok(MessageContent::StopTracking),
+        Request::StartRecording {} => Ok(MessageContent::StartRecording),
+        Request::StopRecording {} => Ok(MessageContent::StopRecording),
+        Request::Snapshot {} => Ok(MessageContent::TakeSnapshot),
+        Request::ListPresets {} => Ok(MessageContent::PresetCommand(PresetCommand::List)),
+        Request::SavePreset { name } => {
+            Ok(MessageContent::PresetCommand(PresetCommand::Save(name)))
+        }
+        Request::DeletePreset { name } => {
+            Ok(MessageContent::PresetCommand(PresetCommand::Delete(name)))
+        }
+        Request::RecallPreset { name } => {
+            Ok(MessageContent::PresetCommand(PresetCommand::Recall(name)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sentry::bus::{self, BusReceiver};
+    use tokio::codec::LinesCodec;
+    use tokio::net::TcpStream;
+    use tokio::runtime::current_thread::Runtime;
+    use tokio::timer::Delay;
+    use tokio_tungstenite::client_async;
+    use tokio_tungstenite::tungstenite::Error as WsError;
+    use url::Url;
+
+    fn server_config() -> TcpServerConfig {
+        toml::from_str(
+            r#"
+            host = "127.0.0.1"
+            port = 0
+            secret = "hunter2"
+
+            [users]
+            alice = "correct horse"
+            "#,
+        )
+        .expect("Invalid test configuration")
+    }
+
+    #[test]
+    fn accepts_valid_credentials() {
+        let config = server_config();
+        assert_eq!(
+            check_credentials(
+                br#"{"type": "auth", "token": "hunter2"}"#,
+                Encoding::Json,
+                &config
+            ),
+            Ok("shared secret".to_string())
+        );
+        assert_eq!(
+            check_credentials(
+                br#"{"type": "auth", "user": "alice", "token": "correct horse"}"#,
+                Encoding::Json,
+                &config
+            ),
+            Ok("user 'alice'".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_credentials() {
+        let config = server_config();
+        for message in &[
+            r#"{"type": "auth", "token": "hunter3"}"#,
+            r#"{"type": "auth", "user": "alice", "token": "hunter2"}"#,
+            r#"{"type": "auth", "user": "bob", "token": "correct horse"}"#,
+            r#"{"type": "fire"}"#,
+            "not json",
+        ] {
+            assert!(
+                check_credentials(message.as_bytes(), Encoding::Json, &config).is_err(),
+                "{}",
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn checks_hello_messages() {
+        assert_eq!(
+            check_hello(br#"{"type": "hello", "version": 2}"#),
+            Ok((protocol::PROTOCOL_VERSION, Encoding::Json))
+        );
+        assert_eq!(
+            check_hello(br#"{"type": "hello", "version": 2, "encoding": "cbor"}"#),
+            Ok((protocol::PROTOCOL_VERSION, Encoding::Cbor))
+        );
+        for message in &[
+            r#"{"type": "hello", "version": 1}"#,
+            r#"{"type": "fire"}"#,
+            r#"{"command": "fire"}"#,
+            r#"{"type": "hello", "version": 2, "encoding": "xml"}"#,
+        ] {
+            assert!(check_hello(message.as_bytes()).is_err(), "{}", message);
+        }
+    }
+
+    #[test]
+    fn only_requires_auth_when_configured() {
+        assert!(server_config().requires_auth());
+        let open: TcpServerConfig = toml::from_str("host = \"127.0.0.1\"\nport = 0").unwrap();
+        assert!(!open.requires_auth());
+    }
+
+    /// Finds a free port for the server to bind to
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .port()
+    }
+
+    fn test_config(port: u16, server: &str) -> Config {
+        toml::from_str(&format!(
+            r#"
+            camera = {{}}
+
+            [server]
+            host = "127.0.0.1"
+            port = {}
+            {}
+
+            [video]
+            encoder = "fakesink"
+            decoder = "fakesink"
+            host = "127.0.0.1"
+
+            [arduino]
+            device = "/dev/null"
+            baud = 115200
+            pitch_max_speed = 1000
+            yaw_max_speed = 1000
+            pitch_homing_speed = 1000
+            yaw_homing_speed = 1000
+
+            [arduino.pitch]
+            gear_ratio = 9.0
+            microsteps = 4
+            min_degrees = -72.0
+            max_degrees = 53.0
+
+            [arduino.yaw]
+            gear_ratio = 9.0
+            microsteps = 4
+            min_degrees = 0.0
+            max_degrees = 352.0
+            "#,
+            port, server,
+        ))
+        .expect("Invalid test configuration")
+    }
+
+    /// Runs the server module, returning the runtime it runs on and a receiver for its bus
+    fn start_server(config: Config) -> (Runtime, BusReceiver<Message>) {
+        let mut runtime = Runtime::new().unwrap();
+        let (bus_sink, bus_stream) = bus::new::<Message>();
+        let server_bus = (bus_sink, bus_stream.clone());
+        let test_stream = bus_stream.clone();
+        // Clones only receive messages while the original receiver is being polled
+        runtime.spawn(bus_stream.for_each(|_| Ok(())));
+        runtime.spawn(
+            future::lazy(move || start(config, server_bus))
+                .map_err(|err| panic!("Server failed: {}", err)),
+        );
+        (runtime, test_stream)
+    }
+
+    /// Connects to the server once it has had a moment to bind
+    fn connect(port: u16) -> impl Future<Item = TcpStream, Error = String> {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        Delay::new(Instant::now() + Duration::from_millis(100))
+            .map_err(|err| err.to_string())
+            .and_then(move |_| TcpStream::connect(&addr).map_err(|err| err.to_string()))
+    }
+
+    fn parse(message: Option<String>) -> serde_json::Value {
+        serde_json::from_str(&message.expect("Connection closed")).unwrap()
+    }
+
+    /// Sends hello, returning the message that follows the server's reply to it
+    fn say_hello<S>(client: S) -> impl Future<Item = (Option<String>, S), Error = String>
+    where
+        S: Stream<Item = String> + Sink<SinkItem = String>,
+        S::Error: std::fmt::Display,
+        S::SinkError: std::fmt::Display,
+    {
+        client
+            .send(r#"{"type": "hello", "version": 2}"#.to_string())
+            .map_err(|err| err.to_string())
+            .and_then(|client| client.into_future().map_err(|(err, _)| err.to_string()))
+            .and_then(|(hello, client)| {
+                assert_eq!(parse(hello)["type"], "hello");
+                client.into_future().map_err(|(err, _)| err.to_string())
+            })
+    }
+
+    fn process_json(message: &str, config: &Config) -> Result<MessageContent, Response> {
+        process_message(message.as_bytes(), Encoding::Json, config)
+    }
+
+    #[test]
+    fn parses_webrtc_signalling() {
+        let config = test_config(0, "");
+        match process_json(r#"{"type": "start_webrtc"}"#, &config) {
+            Ok(MessageContent::StartWebRtc) => {}
+            _ => panic!("Expected StartWebRtc"),
+        }
+        match process_json(r#"{"type": "webrtc_answer", "sdp": "v=0"}"#, &config) {
+            Ok(MessageContent::WebRtcAnswer { sdp }) => assert_eq!(sdp, "v=0"),
+            _ => panic!("Expected WebRtcAnswer"),
+        }
+        match process_json(
+            r#"{"type": "webrtc_ice_candidate", "candidate": "candidate:1 1 UDP 1 10.0.0.2 5000 typ host", "sdp_mline_index": 0}"#,
+            &config,
+        ) {
+            Ok(MessageContent::WebRtcIceCandidate {
+                candidate,
+                sdp_mline_index: 0,
+                for_client: None,
+            }) => assert!(candidate.starts_with("candidate:1")),
+            _ => panic!("Expected WebRtcIceCandidate"),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_messages() {
+        let config = test_config(0, "");
+        let code = |message| match process_json(message, &config) {
+            Err(Response::Error { code, .. }) => code,
+            _ => panic!("Expected an error for {}", message),
+        };
+        assert_eq!(code("not json"), ErrorCode::InvalidMessage);
+        assert_eq!(code(r#"{"command": "fire"}"#), ErrorCode::InvalidMessage);
+        assert_eq!(
+            code(r#"{"type": "self_destruct"}"#),
+            ErrorCode::InvalidMessage
+        );
+        assert_eq!(
+            code(r#"{"type": "hello", "version": 2}"#),
+            ErrorCode::InvalidMessage
+        );
+        assert_eq!(
+            code(r#"{"type": "move_to", "pitch": 100, "yaw_degrees": 90}"#),
+            ErrorCode::InvalidCommand
+        );
+        match process_json(
+            r#"{"type": "move_to", "pitch_degrees": 0, "yaw_degrees": 90}"#,
+            &config,
+        ) {
+            Ok(MessageContent::Command(Command::MoveTo {
+                pitch: 1440,
+                yaw: 1800,
+            })) => {}
+            _ => panic!("Expected MoveTo"),
+        }
+    }
+
+    #[test]
+    fn accepts_tls_connections() {
+        let port = free_port();
+        let (mut runtime, _bus_stream) = start_server(test_config(
+            port,
+            &format!(
+                r#"tls = {{ cert = "{0}/testdata/cert.pem", key = "{0}/testdata/key.pem" }}"#,
+                env!("CARGO_MANIFEST_DIR")
+            ),
+        ));
+
+        // The test certificate is self-signed
+        let connector = tokio_tls::TlsConnector::from(
+            native_tls::TlsConnector::builder()
+                .danger_accept_invalid_certs(true)
+                .build()
+                .unwrap(),
+        );
+        let (message, _) = runtime
+            .block_on(
+                connect(port)
+                    .and_then(move |socket| {
+                        connector
+                            .connect("localhost", socket)
+                            .map_err(|err| err.to_string())
+                    })
+                    .and_then(|socket| say_hello(LinesCodec::new().framed(socket)))
+                    .timeout(Duration::from_secs(5)),
+            )
+            .expect("Failed to connect over TLS");
+
+        assert_eq!(parse(message)["position"], 0);
+    }
+
+    #[test]
+    fn switches_to_cbor_after_hello() {
+        let port = free_port();
+        let (mut runtime, mut bus_stream) = start_server(test_config(port, ""));
+
+        // The client frames messages the same way as the server
+        let encoding = Arc::new(Mutex::new(Encoding::Json));
+        let (hello, client) = runtime
+            .block_on(
+                connect(port)
+                    .and_then({
+                        let encoding = encoding.clone();
+                        move |socket| {
+                            MessageCodec::new(encoding)
+                                .framed(socket)
+                                .send(
+                                    br#"{"type": "hello", "version": 2, "encoding": "cbor"}"#
+                                        .to_vec(),
+                                )
+                                .map_err(|err| err.to_string())
+                        }
+                    })
+                    .and_then(|client| client.into_future().map_err(|(err, _)| err.to_string()))
+                    .timeout(Duration::from_secs(5)),
+            )
+            .expect("Failed to connect over TCP");
+        let hello: serde_json::Value = serde_json::from_slice(&hello.unwrap()).unwrap();
+        assert_eq!(hello["encoding"], "cbor");
+
+        *encoding.lock().unwrap() = Encoding::Cbor;
+        let (queue, client) = runtime
+            .block_on(
+                client
+                    .into_future()
+                    .map_err(|(err, _)| err.to_string())
+                    .timeout(Duration::from_secs(5)),
+            )
+            .expect("Failed to read queue position");
+        let queue: serde_json::Value = serde_cbor::from_slice(&queue.unwrap()).unwrap();
+        assert_eq!(queue["type"], "queue");
+        assert_eq!(queue["position"], 0);
+
+        let home = serde_cbor::to_vec(&serde_json::json!({"type": "home"})).unwrap();
+        let _client = runtime.block_on(client.send(home)).unwrap();
+        let (message, _) = runtime
+            .block_on(
+                bus_stream
+                    .by_ref()
+                    .filter(|message| matches!(message.content, MessageContent::Command(_)))
+                    .into_future()
+                    .map_err(|_| ())
+                    .timeout(Duration::from_secs(5)),
+            )
+            .expect("Timed out waiting for command");
+        match message.map(|message| message.content) {
+            Some(MessageContent::Command(Command::Home)) => {}
+            _ => panic!("Expected home command from the CBOR client"),
+        }
+    }
+
+    #[test]
+    fn accepts_moves_over_udp() {
+        let (port, udp_port) = (free_port(), free_port());
+        let (mut runtime, mut bus_stream) =
+            start_server(test_config(port, &format!("udp_port = {}", udp_port)));
+
+        let (session, _client) = runtime
+            .block_on(
+                connect(port)
+                    .and_then(|socket| say_hello(LinesCodec::new().framed(socket)))
+                    .and_then(|(_, client)| {
+                        client.into_future().map_err(|(err, _)| err.to_string())
+                    })
+                    .timeout(Duration::from_secs(5)),
+            )
+            .expect("Failed to connect over TCP");
+        let session = parse(session);
+        assert_eq!(session["type"], "udp_session");
+        assert_eq!(session["port"], udp_port);
+
+        let datagram = MoveDatagram::new(session["token"].as_str().unwrap(), 1, 0.5, 0.0);
+        std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .send_to(
+                &serde_json::to_vec(&datagram).unwrap(),
+                ("127.0.0.1", udp_port),
+            )
+            .unwrap();
+        let (message, _) = runtime
+            .block_on(
+                bus_stream
+                    .by_ref()
+                    .filter(|message| matches!(message.content, MessageContent::Command(_)))
+                    .into_future()
+                    .map_err(|_| ())
+                    .timeout(Duration::from_secs(5)),
+            )
+            .expect("Timed out waiting for move");
+        match message.map(|message| (message.source, message.content)) {
+            Some((
+                MessageSource::Client(client),
+                MessageContent::Command(Command::Move { pitch, .. }),
+            )) => {
+                assert_eq!(client.queue_position, 0);
+                assert_eq!(pitch, 0.5);
             }
-        } else if let (JsonNumber(pitch), JsonNumber(yaw)) = (&json["pitch"], &json["yaw"]) {
-            Some(MessageContent::Command(Command::Move {
-                pitch: pitch.as_f64()?,
-                yaw: yaw.as_f64()?,
-            }))
-        } else if JsonString("ping".to_string()) == json {
-            Some(MessageContent::Ping)
-        } else {
-            None
+            _ => panic!("Expected a move from the client"),
+        }
+    }
+
+    #[test]
+    fn websocket_clients_share_the_queue() {
+        let (port, websocket_port) = (free_port(), free_port());
+        let (mut runtime, mut bus_stream) = start_server(test_config(
+            port,
+            &format!("websocket_port = {}", websocket_port),
+        ));
+
+        let (message, _tcp_client) = runtime
+            .block_on(
+                connect(port)
+                    .and_then(|socket| say_hello(LinesCodec::new().framed(socket)))
+                    .timeout(Duration::from_secs(5)),
+            )
+            .expect("Failed to connect over TCP");
+        assert_eq!(parse(message)["position"], 0);
+
+        let url = Url::parse(&format!("ws://127.0.0.1:{}/", websocket_port)).unwrap();
+        let (message, websocket) = runtime
+            .block_on(
+                connect(websocket_port)
+                    .and_then(|socket| client_async(url, socket).map_err(|err| err.to_string()))
+                    .and_then(|(websocket, _)| {
+                        say_hello(
+                            websocket
+                                .with(|text| Ok::<_, WsError>(WsMessage::Text(text)))
+                                .map(|message| message.into_text().unwrap_or_default()),
+                        )
+                    })
+                    .timeout(Duration::from_secs(5)),
+            )
+            .expect("Failed to connect over WebSocket");
+        assert_eq!(parse(message)["position"], 1);
+
+        // Commands arrive on the bus the same way they do from TCP clients
+        let _websocket = runtime
+            .block_on(websocket.send(r#"{"type": "home"}"#.to_string()))
+            .unwrap();
+        let (message, _) = runtime
+            .block_on(
+                bus_stream
+                    .by_ref()
+                    .filter(|message| matches!(message.content, MessageContent::Command(_)))
+                    .into_future()
+                    .map_err(|_| ())
+                    .timeout(Duration::from_secs(5)),
+            )
+            .expect("Timed out waiting for command");
+        match message.map(|message| (message.source, message.content)) {
+            Some((MessageSource::Client(client), MessageContent::Command(Command::Home))) => {
+                assert_eq!(client.queue_position, 1)
+            }
+            _ => panic!("Expected home command from the WebSocket client"),
         }
-    } else {
-        None
     }
 }
@@ -0,0 +1,108 @@
+//! Frames messages on raw sockets, as lines until the handshake agrees on a binary encoding
+use crate::sentry::protocol::Encoding;
+use bytes::{BufMut, Bytes, BytesMut};
+use std::io;
+use std::sync::{Arc, Mutex};
+use tokio::codec::{Decoder, Encoder, LengthDelimitedCodec, LinesCodec};
+
+/// Largest binary message either side may send, which is far more than any message needs
+const MAX_FRAME_LENGTH: usize = 64 * 1024;
+
+/// Frames messages for whichever encoding a connection is using. The encoding is shared with the
+/// handshake, which switches it once the hello messages have been exchanged.
+pub struct MessageCodec {
+    encoding: Arc<Mutex<Encoding>>,
+    lines: LinesCodec,
+    length_delimited: LengthDelimitedCodec,
+}
+
+impl MessageCodec {
+    pub fn new(encoding: Arc<Mutex<Encoding>>) -> Self {
+        let mut length_delimited = LengthDelimitedCodec::new();
+        length_delimited.set_max_frame_length(MAX_FRAME_LENGTH);
+        MessageCodec {
+            encoding,
+            lines: LinesCodec::new(),
+            length_delimited,
+        }
+    }
+
+    fn encoding(&self) -> Encoding {
+        *self.encoding.lock().unwrap()
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Vec<u8>>, io::Error> {
+        match self.encoding() {
+            Encoding::Json => Ok(self.lines.decode(buf)?.map(String::into_bytes)),
+            Encoding::Cbor => Ok(self
+                .length_delimited
+                .decode(buf)?
+                .map(|frame| frame.to_vec())),
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Vec<u8>>, io::Error> {
+        match self.encoding() {
+            // The last line doesn't need a newline
+            Encoding::Json => Ok(self.lines.decode_eof(buf)?.map(String::into_bytes)),
+            Encoding::Cbor => Ok(self
+                .length_delimited
+                .decode_eof(buf)?
+                .map(|frame| frame.to_vec())),
+        }
+    }
+}
+
+impl Encoder for MessageCodec {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn encode(&mut self, message: Vec<u8>, buf: &mut BytesMut) -> Result<(), io::Error> {
+        match self.encoding() {
+            Encoding::Json => {
+                buf.reserve(message.len() + 1);
+                buf.put_slice(&message);
+                buf.put_u8(b'\n');
+                Ok(())
+            }
+            Encoding::Cbor => self.length_delimited.encode(Bytes::from(message), buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn switches_from_lines_to_length_prefixes() {
+        let encoding = Arc::new(Mutex::new(Encoding::Json));
+        let mut codec = MessageCodec::new(encoding.clone());
+
+        let mut buf = BytesMut::from(&b"{\"type\":\"hello\"}\n\0\0\0\x03abc\0\0"[..]);
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(b"{\"type\":\"hello\"}".to_vec())
+        );
+        *encoding.lock().unwrap() = Encoding::Cbor;
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(b"abc".to_vec()));
+        // Waits for the rest of a frame
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        let mut buf = BytesMut::new();
+        codec.encode(b"abc".to_vec(), &mut buf).unwrap();
+        assert_eq!(&buf[..], b"\0\0\0\x03abc");
+    }
+
+    #[test]
+    fn rejects_oversized_frames() {
+        let mut codec = MessageCodec::new(Arc::new(Mutex::new(Encoding::Cbor)));
+        let mut buf = BytesMut::from(&b"\x7f\xff\xff\xff"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,142 @@
+//! Receives moves over UDP from clients in the queue, so a lost packet on a lossy link doesn't hold
+//! up the ones after it the way it would on the control connection
+use super::ClientQueue;
+use crate::sentry::bus::BusSender;
+use crate::sentry::protocol::MoveDatagram;
+use crate::sentry::{Command, Message, MessageContent, MessageSource};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use tokio::codec::BytesCodec;
+use tokio::net::{UdpFramed, UdpSocket};
+use tokio::prelude::*;
+
+pub fn listen(
+    addr: SocketAddr,
+    bus_sink: BusSender<Message>,
+    clients: Arc<RwLock<ClientQueue>>,
+) -> impl Future<Item = (), Error = String> {
+    info!("Binding UDP server on {}...", addr);
+
+    future::result(UdpSocket::bind(&addr))
+        .map_err(|err| format!("Could not bind UDP server: {}", err))
+        .and_then(move |socket| {
+            UdpFramed::new(socket, BytesCodec::new())
+                .map_err(|err| format!("UDP server error: {}", err))
+                .for_each(move |(datagram, sender)| {
+                    if let Some(message) = receive(&datagram, sender, &clients) {
+                        bus_sink
+                            .unbounded_send(message)
+                            .unwrap_or_else(|err| error!("Failed to send bus message: {}", err));
+                    }
+                    Ok(())
+                })
+        })
+}
+
+/// Turns a datagram into a move from the client who signed it, dropping any that are invalid or
+/// stale
+fn receive(datagram: &[u8], sender: SocketAddr, clients: &RwLock<ClientQueue>) -> Option<Message> {
+    let datagram = match MoveDatagram::decode(datagram) {
+        Ok(datagram) => datagram,
+        Err(err) => {
+            warn!("Invalid move datagram from {}: {}", sender, err);
+            return None;
+        }
+    };
+    let client = clients
+        .write()
+        .unwrap()
+        .accept_datagram(sender.ip(), &datagram)?;
+    Some(Message {
+        content: MessageContent::Command(Command::Move {
+            pitch: datagram.pitch,
+            yaw: datagram.yaw,
+        }),
+        source: MessageSource::Client(client),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sentry::protocol::Encoding;
+    use futures::sync::mpsc::unbounded;
+
+    fn datagram(token: &str, sequence: u64, yaw: f64) -> Vec<u8> {
+        serde_json::to_vec(&MoveDatagram::new(token, sequence, 0.0, yaw)).unwrap()
+    }
+
+    fn yaw(message: Option<Message>) -> Option<f64> {
+        match message.map(|message| message.content) {
+            Some(MessageContent::Command(Command::Move { yaw, .. })) => Some(yaw),
+            _ => None,
+        }
+    }
+
+    fn queue() -> RwLock<ClientQueue> {
+        let clients = RwLock::new(ClientQueue::new());
+        let (tx, _rx) = unbounded();
+        clients.write().unwrap().enqueue(
+            SocketAddr::from(([10, 0, 0, 2], 5000)),
+            Encoding::Json,
+            tx,
+            "secret".to_string(),
+        );
+        clients
+    }
+
+    #[test]
+    fn drops_stale_and_unknown_datagrams() {
+        let clients = queue();
+        let sender = SocketAddr::from(([10, 0, 0, 2], 6000));
+        let receive = |datagram: Vec<u8>| receive(&datagram, sender, &clients);
+
+        match receive(datagram("secret", 1, 0.5)).map(|message| message.source) {
+            Some(MessageSource::Client(client)) => {
+                assert_eq!(client.address, SocketAddr::from(([10, 0, 0, 2], 5000)));
+                assert_eq!(client.queue_position, 0);
+            }
+            _ => panic!("Expected a move from the client"),
+        }
+        assert_eq!(yaw(receive(datagram("secret", 3, 1.0))), Some(1.0));
+        // Arrived after a later one
+        assert_eq!(yaw(receive(datagram("secret", 2, 0.75))), None);
+        assert_eq!(yaw(receive(datagram("secret", 3, 0.75))), None);
+        assert_eq!(yaw(receive(datagram("guess", 4, 0.75))), None);
+        assert_eq!(yaw(receive(b"{}".to_vec())), None);
+        assert_eq!(yaw(receive(datagram("secret", 4, -1.0))), Some(-1.0));
+    }
+
+    #[test]
+    fn drops_datagrams_from_other_hosts() {
+        let clients = queue();
+        let spoofed = SocketAddr::from(([10, 0, 0, 3], 6000));
+        assert_eq!(
+            yaw(receive(&datagram("secret", 1, 0.5), spoofed, &clients)),
+            None
+        );
+
+        let sender = SocketAddr::from(([10, 0, 0, 2], 6000));
+        assert_eq!(
+            yaw(receive(&datagram("secret", 1, 0.5), sender, &clients)),
+            Some(0.5)
+        );
+    }
+
+    #[test]
+    fn ignores_sequence_numbers_of_forged_datagrams() {
+        let clients = queue();
+        let sender = SocketAddr::from(([10, 0, 0, 2], 6000));
+        let receive = |datagram: &[u8]| receive(datagram, sender, &clients);
+
+        // Unsigned, or signed without the token, so must not hold back the client's own moves
+        let forged = MoveDatagram {
+            mac: String::new(),
+            ..MoveDatagram::new("secret", u64::MAX, 0.0, 1.0)
+        };
+        assert_eq!(yaw(receive(&serde_json::to_vec(&forged).unwrap())), None);
+        assert_eq!(yaw(receive(&datagram("guess", u64::MAX, 1.0))), None);
+        assert_eq!(yaw(receive(&datagram("secret", 1, 0.5))), Some(0.5));
+        assert_eq!(yaw(receive(&datagram("secret", 2, -0.5))), Some(-0.5));
+    }
+}
@@ -0,0 +1,517 @@
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, BytesMut};
+use crc::crc16::checksum_usb as crc16;
+use std::collections::VecDeque;
+use std::io;
+use std::time::{Duration, Instant};
+use tokio::codec::{Decoder, Encoder};
+use tokio::net::UnixStream;
+use tokio::prelude::*;
+use tokio::timer::Interval;
+
+// Wire values from the firmware's Status.h and Command.h
+const STATUS_READY: u8 = 100;
+const STATUS_NOT_LOADED: u8 = 101;
+const STATUS_MAGAZINE_RELEASED: u8 = 102;
+const STATUS_RELOADING: u8 = 103;
+const STATUS_HOMING_REQUIRED: u8 = 104;
+const STATUS_HOMING: u8 = 105;
+const STATUS_MOTORS_OFF: u8 = 106;
+const STATUS_HOMING_FAILED: u8 = 107;
+
+const COMMAND_MOVE: u8 = 200;
+const COMMAND_HOME: u8 = 201;
+const COMMAND_RELEASE_MAGAZINE: u8 = 202;
+const COMMAND_LOAD_MAGAZINE: u8 = 203;
+const COMMAND_RELOAD: u8 = 204;
+const COMMAND_FIRE: u8 = 205;
+const COMMAND_FIRE_AND_RELOAD: u8 = 206;
+const COMMAND_MOTORS_ON: u8 = 207;
+const COMMAND_MOTORS_OFF: u8 = 208;
+const COMMAND_MOVE_TO: u8 = 209;
+
+// Motion constants as computed by the firmware's config.h
+const PITCH_MAX_STEPS: i64 = 2500;
+const PITCH_HOME_OFFSET: i64 = 0;
+const PITCH_HOME_INVERTED: bool = true;
+const PITCH_ACCEL: f64 = 18000.0;
+
+const YAW_MAX_STEPS: i64 = 8213;
+const YAW_HOME_OFFSET: i64 = 1866;
+const YAW_HOME_INVERTED: bool = true;
+const YAW_ACCEL: f64 = 12000.0;
+
+const SLIDE_OPEN_POS: i64 = 1320;
+const SLIDE_CLOSED_POS: i64 = 240;
+const SLIDE_FIRED_POS: i64 = 80;
+const SLIDE_ACCEL: f64 = 40000.0;
+const SLIDE_SPEED: f64 = 2200.0;
+
+/// Interval between position status updates
+const RESPONSE_INTERVAL: Duration = Duration::from_millis(10);
+/// Motors are stopped if no message has been received for this long
+const WATCHDOG_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The firmware side of `ArduinoCodec`: decodes command frames and encodes status frames
+pub(crate) struct FirmwareCodec;
+
+impl Decoder for FirmwareCodec {
+    type Item = (u8, i32, i32);
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        while src.len() >= 11 {
+            if BigEndian::read_u16(src) == crc16(&src[2..11]) {
+                let message = src.split_to(11);
+                return Ok(Some((
+                    message[2],
+                    BigEndian::read_i32(&message[3..]),
+                    BigEndian::read_i32(&message[7..]),
+                )));
+            }
+            // CRC failed, skip one byte
+            src.advance(1);
+        }
+        Ok(None)
+    }
+}
+
+impl Encoder for FirmwareCodec {
+    type Item = (u8, u32, u32);
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let (status, pitch_pos, yaw_pos) = item;
+        let mut message: [u8; 11] = [0; 11];
+        message[2] = status;
+        BigEndian::write_u32(&mut message[3..], pitch_pos);
+        BigEndian::write_u32(&mut message[7..], yaw_pos);
+        let crc = crc16(&message[2..]);
+        BigEndian::write_u16(&mut message[0..], crc);
+        dst.reserve(11);
+        dst.put_slice(&message);
+        Ok(())
+    }
+}
+
+/// A simulated stepper motor with a trapezoidal speed profile, like the firmware's StepperDriver
+struct Stepper {
+    enabled: bool,
+    /// The current position in steps
+    position: f64,
+    /// The target position in steps
+    target: i64,
+    /// The current speed in steps/second, negative when moving backwards
+    speed: f64,
+    /// The maximum permitted speed in steps/second
+    max_speed: f64,
+    /// The acceleration in steps/second^2
+    acceleration: f64,
+}
+
+impl Stepper {
+    fn new(position: i64, acceleration: f64, max_speed: f64) -> Self {
+        Stepper {
+            enabled: false,
+            position: position as f64,
+            target: position,
+            speed: 0.0,
+            max_speed,
+            acceleration,
+        }
+    }
+
+    fn position(&self) -> i64 {
+        self.position.round() as i64
+    }
+
+    fn set_position(&mut self, position: i64) {
+        self.position = position as f64;
+        self.target = position;
+        self.speed = 0.0;
+    }
+
+    fn is_at_target(&self) -> bool {
+        self.speed == 0.0 && self.position() == self.target
+    }
+
+    fn emergency_stop(&mut self) {
+        self.target = self.position();
+        self.speed = 0.0;
+    }
+
+    fn update(&mut self, elapsed: f64) {
+        if !self.enabled {
+            self.speed = 0.0;
+            return;
+        }
+
+        let distance = self.target as f64 - self.position;
+        // Never go faster than what still allows us to decelerate to a stop at the target
+        let desired_speed = if distance == 0.0 {
+            0.0
+        } else {
+            distance.signum()
+                * self
+                    .max_speed
+                    .min((2.0 * self.acceleration * distance.abs()).sqrt())
+        };
+        let max_change = self.acceleration * elapsed;
+        self.speed += (desired_speed - self.speed)
+            .max(-max_change)
+            .min(max_change);
+        self.position += self.speed * elapsed;
+
+        let remaining = self.target as f64 - self.position;
+        if remaining == 0.0 || remaining.signum() != distance.signum() {
+            // Reached or passed the target
+            self.position = self.target as f64;
+            self.speed = 0.0;
+        }
+    }
+}
+
+/// Steps of the blocking operations the firmware performs, executed in order
+#[derive(Clone, Copy)]
+enum Operation {
+    HomePitch(u32),
+    HomeYaw(u32),
+    Homed,
+    MoveSlide(i64),
+    Fired,
+    MagazineReleased,
+    MagazineLoaded,
+    BeginReload,
+    EndReload,
+}
+
+/// Software model of the turret, mirroring the state machine in sentry.ino
+struct Turret {
+    pitch: Stepper,
+    yaw: Stepper,
+    slide: Stepper,
+    loaded: bool,
+    mag_released: bool,
+    homed: bool,
+    homing: bool,
+    homing_failed: bool,
+    reloading: bool,
+    /// Whether the axes are being driven by speed commands, which stop when they're no longer received
+    jogging: bool,
+    /// Operations that would block the firmware's main loop
+    operations: VecDeque<Operation>,
+    last_message_time: Instant,
+    last_update_time: Instant,
+}
+
+impl Turret {
+    fn new() -> Self {
+        let mut slide = Stepper::new(SLIDE_CLOSED_POS, SLIDE_ACCEL, SLIDE_SPEED);
+        slide.enabled = true;
+        Turret {
+            // The real position is unknown until homed, so start somewhere in the middle
+            pitch: Stepper::new(PITCH_MAX_STEPS / 2, PITCH_ACCEL, 0.0),
+            yaw: Stepper::new(YAW_MAX_STEPS / 2, YAW_ACCEL, 0.0),
+            slide,
+            // Safer to assume loaded when powered on
+            loaded: true,
+            mag_released: false,
+            homed: false,
+            homing: false,
+            homing_failed: false,
+            reloading: false,
+            jogging: false,
+            operations: VecDeque::new(),
+            last_message_time: Instant::now(),
+            last_update_time: Instant::now(),
+        }
+    }
+
+    fn is_busy(&self) -> bool {
+        !self.operations.is_empty()
+    }
+
+    fn handle_command(&mut self, command: u8, a: i32, b: i32) {
+        if self.is_busy() {
+            debug!("Simulated arduino is busy, ignoring command {}", command);
+            return;
+        }
+        self.last_message_time = Instant::now();
+
+        match command {
+            COMMAND_MOVE => self.move_axes(a, b),
+            COMMAND_MOVE_TO => self.move_axes_to(
+                i64::from(a as u32 >> 16),
+                i64::from(a as u32 & 0xFFFF),
+                b as u32 >> 16,
+                b as u32 & 0xFFFF,
+            ),
+            COMMAND_HOME => self.home(a as u32, b as u32),
+            COMMAND_RELEASE_MAGAZINE => self.release_magazine(),
+            COMMAND_LOAD_MAGAZINE => self.load_magazine(),
+            COMMAND_RELOAD => {
+                if !self.loaded {
+                    self.reload();
+                }
+            }
+            COMMAND_FIRE => self.fire(),
+            COMMAND_FIRE_AND_RELOAD => {
+                self.fire();
+                self.reload();
+            }
+            COMMAND_MOTORS_ON => {
+                self.pitch.enabled = true;
+                self.yaw.enabled = true;
+            }
+            COMMAND_MOTORS_OFF => {
+                self.pitch.enabled = false;
+                self.yaw.enabled = false;
+                self.pitch.emergency_stop();
+                self.yaw.emergency_stop();
+            }
+            _ => warn!("Simulated arduino received unknown command {}", command),
+        }
+    }
+
+    fn move_axes(&mut self, pitch_speed: i32, yaw_speed: i32) {
+        if !self.homed || !self.pitch.enabled || !self.yaw.enabled {
+            return;
+        }
+        self.pitch.max_speed = f64::from(pitch_speed).abs();
+        self.pitch.target = if pitch_speed >= 0 { PITCH_MAX_STEPS } else { 0 };
+        self.yaw.max_speed = f64::from(yaw_speed).abs();
+        self.yaw.target = if yaw_speed >= 0 { YAW_MAX_STEPS } else { 0 };
+        self.jogging = true;
+    }
+
+    fn move_axes_to(
+        &mut self,
+        pitch_target: i64,
+        yaw_target: i64,
+        pitch_speed: u32,
+        yaw_speed: u32,
+    ) {
+        if !self.homed || !self.pitch.enabled || !self.yaw.enabled {
+            return;
+        }
+        self.pitch.max_speed = f64::from(pitch_speed);
+        self.pitch.target = pitch_target.clamp(0, PITCH_MAX_STEPS);
+        self.yaw.max_speed = f64::from(yaw_speed);
+        self.yaw.target = yaw_target.clamp(0, YAW_MAX_STEPS);
+        self.jogging = false;
+    }
+
+    fn home(&mut self, pitch_speed: u32, yaw_speed: u32) {
+        if !self.pitch.enabled || !self.yaw.enabled {
+            return;
+        }
+        self.homing = true;
+        self.homing_failed = false;
+        self.operations.push_back(Operation::HomePitch(pitch_speed));
+        self.operations.push_back(Operation::HomeYaw(yaw_speed));
+        self.operations.push_back(Operation::Homed);
+    }
+
+    fn release_magazine(&mut self) {
+        if !self.loaded {
+            self.operations
+                .push_back(Operation::MoveSlide(SLIDE_OPEN_POS));
+            self.operations.push_back(Operation::MagazineReleased);
+        }
+    }
+
+    fn load_magazine(&mut self) {
+        if self.mag_released {
+            self.operations
+                .push_back(Operation::MoveSlide(SLIDE_CLOSED_POS));
+            self.operations.push_back(Operation::MagazineLoaded);
+        }
+    }
+
+    fn fire(&mut self) {
+        if self.loaded {
+            self.operations
+                .push_back(Operation::MoveSlide(SLIDE_FIRED_POS));
+            self.operations.push_back(Operation::MoveSlide(0));
+            self.operations.push_back(Operation::Fired);
+        }
+    }
+
+    fn reload(&mut self) {
+        // Queued after a fire the magazine is not loaded yet, so the checks
+        // the firmware does in releaseMagazine() and loadMagazine() always pass here
+        self.operations.push_back(Operation::BeginReload);
+        self.operations
+            .push_back(Operation::MoveSlide(SLIDE_OPEN_POS));
+        self.operations.push_back(Operation::MagazineReleased);
+        self.operations
+            .push_back(Operation::MoveSlide(SLIDE_CLOSED_POS));
+        self.operations.push_back(Operation::MagazineLoaded);
+        self.operations.push_back(Operation::EndReload);
+    }
+
+    fn update(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_update_time).as_secs_f64();
+        self.last_update_time = now;
+
+        if self.jogging
+            && !self.is_busy()
+            && now.duration_since(self.last_message_time) > WATCHDOG_TIMEOUT
+        {
+            // Haven't received a message in a while
+            self.pitch.max_speed = 0.0;
+            self.yaw.max_speed = 0.0;
+        }
+
+        // Run instantaneous operations until one needs time to complete
+        while let Some(&operation) = self.operations.front() {
+            let done = match operation {
+                Operation::HomePitch(0) | Operation::HomeYaw(0) => {
+                    self.homing_failed = true;
+                    false
+                }
+                Operation::HomePitch(speed) => Self::home_axis(
+                    &mut self.pitch,
+                    speed,
+                    PITCH_MAX_STEPS,
+                    PITCH_HOME_INVERTED,
+                    PITCH_HOME_OFFSET,
+                ),
+                Operation::HomeYaw(speed) => Self::home_axis(
+                    &mut self.yaw,
+                    speed,
+                    YAW_MAX_STEPS,
+                    YAW_HOME_INVERTED,
+                    YAW_HOME_OFFSET,
+                ),
+                Operation::Homed => {
+                    self.homed = true;
+                    self.homing = false;
+                    true
+                }
+                Operation::MoveSlide(position) => {
+                    self.slide.target = position;
+                    self.slide.is_at_target()
+                }
+                Operation::Fired => {
+                    self.slide.set_position(0);
+                    self.loaded = false;
+                    true
+                }
+                Operation::MagazineReleased => {
+                    self.mag_released = true;
+                    true
+                }
+                Operation::MagazineLoaded => {
+                    self.loaded = true;
+                    self.mag_released = false;
+                    true
+                }
+                Operation::BeginReload => {
+                    self.reloading = true;
+                    true
+                }
+                Operation::EndReload => {
+                    self.reloading = false;
+                    true
+                }
+            };
+            if self.homing_failed {
+                self.homing = false;
+                self.operations.clear();
+            } else if done {
+                self.operations.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        self.pitch.update(elapsed);
+        self.yaw.update(elapsed);
+        self.slide.update(elapsed);
+    }
+
+    /// Drives an axis toward its endstop, returning true once it has been reached
+    fn home_axis(axis: &mut Stepper, speed: u32, max: i64, inverted: bool, offset: i64) -> bool {
+        // The simulated endstop sits exactly where the firmware puts the axis after homing
+        let home = if inverted { max - offset } else { offset };
+        axis.max_speed = f64::from(speed);
+        axis.target = home;
+        axis.is_at_target()
+    }
+
+    fn status(&self) -> u8 {
+        if self.homing_failed {
+            STATUS_HOMING_FAILED
+        } else if !self.pitch.enabled || !self.yaw.enabled {
+            STATUS_MOTORS_OFF
+        } else if self.homing {
+            STATUS_HOMING
+        } else if !self.homed {
+            STATUS_HOMING_REQUIRED
+        } else if self.reloading {
+            STATUS_RELOADING
+        } else if self.mag_released {
+            STATUS_MAGAZINE_RELEASED
+        } else if !self.loaded {
+            STATUS_NOT_LOADED
+        } else {
+            STATUS_READY
+        }
+    }
+
+    fn status_message(&self) -> (u8, u32, u32) {
+        (
+            self.status(),
+            self.pitch.position().max(0) as u32,
+            self.yaw.position().max(0) as u32,
+        )
+    }
+}
+
+enum Event {
+    Command(u8, i32, i32),
+    Tick,
+}
+
+/// Starts a simulated arduino, returning the stream the server should use in place of a serial port
+pub fn start() -> Result<UnixStream, String> {
+    let (server_end, firmware_end) = UnixStream::pair().map_err(|err| {
+        format!(
+            "Could not create socket pair for simulated arduino: {}",
+            err
+        )
+    })?;
+    let (firmware_sink, firmware_stream) = FirmwareCodec.framed(firmware_end).split();
+    let mut turret = Turret::new();
+
+    info!("Starting simulated arduino");
+    tokio::spawn(
+        firmware_stream
+            .map(|(command, a, b)| Event::Command(command, a, b))
+            .map_err(|err| format!("Failed to read from server: {}", err))
+            .select(
+                Interval::new(Instant::now(), RESPONSE_INTERVAL)
+                    .map(|_| Event::Tick)
+                    .map_err(|err| format!("Timer error: {}", err)),
+            )
+            .filter_map(move |event| match event {
+                Event::Command(command, a, b) => {
+                    turret.handle_command(command, a, b);
+                    None
+                }
+                Event::Tick => {
+                    turret.update();
+                    Some(turret.status_message())
+                }
+            })
+            .forward(
+                firmware_sink.sink_map_err(|err| format!("Failed to write to server: {}", err)),
+            )
+            .map(|_| ())
+            .map_err(|err| info!("Simulated arduino stopped: {}", err)),
+    );
+
+    Ok(server_end)
+}
@@ -0,0 +1,547 @@
+//! Steers the turret toward detected motion with a PID loop on each axis
+//!
+//! The detector compares frames, so while the turret moves the whole frame changes and the
+//! target can't be seen. The tracker takes a short step toward each sighting instead, then
+//! stops and waits for the picture to settle before looking for the target again.
+use crate::sentry::config::{Config, PidGains, TrackingConfig};
+use crate::sentry::{
+    BoundingBox, Bus, BusSender, Command, Message, MessageContent, MessageSource, PresetCommand,
+};
+use std::time::{Duration, Instant};
+use tokio::prelude::*;
+use tokio::timer::Interval;
+
+/// How often the tracker checks on its steps and sightings
+const UPDATE_INTERVAL: Duration = Duration::from_millis(50);
+/// Limit on the accumulated error, so the integral term can't wind up while the turret is
+/// held back by something else
+const MAX_INTEGRAL: f64 = 1.0;
+
+struct Pid {
+    gains: PidGains,
+    integral: f64,
+    previous_error: Option<f64>,
+}
+
+impl Pid {
+    fn new(gains: PidGains) -> Self {
+        Pid {
+            gains,
+            integral: 0.0,
+            previous_error: None,
+        }
+    }
+
+    fn update(&mut self, error: f64, dt: f64) -> f64 {
+        self.integral = (self.integral + error * dt).clamp(-MAX_INTEGRAL, MAX_INTEGRAL);
+        let derivative = self
+            .previous_error
+            .map(|previous| (error - previous) / dt)
+            .unwrap_or(0.0);
+        self.previous_error = Some(error);
+        self.gains.kp * error + self.gains.ki * self.integral + self.gains.kd * derivative
+    }
+
+    fn reset(&mut self) {
+        self.integral = 0.0;
+        self.previous_error = None;
+    }
+}
+
+/// Where the target was seen, as offsets from the centre of the frame from -1 to 1.
+/// Positive x is to the right and positive y is up.
+#[derive(Debug, PartialEq)]
+struct Target {
+    x: f64,
+    y: f64,
+}
+
+impl Target {
+    fn new(bbox: &BoundingBox) -> Self {
+        Target {
+            x: (bbox.x + bbox.width / 2.0) * 2.0 - 1.0,
+            y: 1.0 - (bbox.y + bbox.height / 2.0) * 2.0,
+        }
+    }
+}
+
+/// Whether a command from a client means a human is steering the turret. Clients keep sending
+/// zero moves while their joystick is idle, so those don't count.
+fn is_input(command: &Command) -> bool {
+    match command {
+        Command::Move { pitch, yaw } => *pitch != 0.0 || *yaw != 0.0,
+        _ => true,
+    }
+}
+
+struct Tracker {
+    config: TrackingConfig,
+    enabled: bool,
+    /// Whether tracking is holding off because the client in control has taken over
+    paused: bool,
+    /// The latest sighting that hasn't been stepped toward yet
+    target: Option<Target>,
+    last_seen: Option<Instant>,
+    pitch: Pid,
+    yaw: Pid,
+    last_input: Option<Instant>,
+    /// When the step in progress ends, while the tracker is moving the turret
+    step_end: Option<Instant>,
+    /// Motion is ignored until then, since the detector would see the turret's own movement
+    settled_at: Option<Instant>,
+    bus_sink: BusSender<Message>,
+}
+
+impl Tracker {
+    fn new(config: TrackingConfig, bus_sink: BusSender<Message>) -> Self {
+        Tracker {
+            pitch: Pid::new(config.pitch),
+            yaw: Pid::new(config.yaw),
+            config,
+            enabled: false,
+            paused: false,
+            target: None,
+            last_seen: None,
+            last_input: None,
+            step_end: None,
+            settled_at: None,
+            bus_sink,
+        }
+    }
+
+    fn is_yielding(&self, now: Instant) -> bool {
+        let resume_after = Duration::from_secs_f64(self.config.resume_after.max(0.0));
+        self.last_input
+            .map(|time| now.duration_since(time) < resume_after)
+            .unwrap_or(false)
+    }
+
+    /// Whether motion can be trusted to be the target, rather than the turret's own movement
+    fn is_looking(&self, now: Instant) -> bool {
+        self.step_end.is_none() && self.settled_at.is_none_or(|time| now >= time)
+    }
+
+    fn reset(&mut self) {
+        self.pitch.reset();
+        self.yaw.reset();
+    }
+
+    fn handle_message(&mut self, message: Message, now: Instant) {
+        let from_controller = match message.source {
+            MessageSource::Client(ref client) => client.queue_position == 0,
+            _ => false,
+        };
+        match message.content {
+            MessageContent::MotionDetected { region, bbox, .. }
+                if self
+                    .config
+                    .region
+                    .as_ref()
+                    .is_none_or(|name| *name == region)
+                    && self.is_looking(now) =>
+            {
+                self.target = Some(Target::new(&bbox));
+                self.last_seen = Some(now);
+            }
+            MessageContent::Command(ref command) if from_controller && is_input(command) => {
+                self.yield_to_client(now)
+            }
+            MessageContent::PresetCommand(PresetCommand::Recall(_)) if from_controller => {
+                self.yield_to_client(now)
+            }
+            MessageContent::StartTracking | MessageContent::StopTracking if from_controller => {
+                let enabled = matches!(message.content, MessageContent::StartTracking);
+                if enabled == self.enabled {
+                    return;
+                }
+                if enabled {
+                    info!("Starting tracking");
+                } else {
+                    info!("Stopping tracking");
+                    self.stop(now);
+                }
+                self.enabled = enabled;
+                self.target = None;
+                self.last_seen = None;
+                self.reset();
+                self.send_state();
+            }
+            MessageContent::ClientConnected(_) => self.send_state(),
+            _ => {}
+        }
+    }
+
+    fn yield_to_client(&mut self, now: Instant) {
+        self.last_input = Some(now);
+        if self.enabled && !self.paused {
+            info!("Pausing tracking while the client in control steers the turret");
+            // The client is moving the turret now, so there is nothing to stop
+            self.step_end = None;
+            self.target = None;
+            self.paused = true;
+            self.reset();
+            self.send_state();
+        }
+    }
+
+    /// Ends the step in progress, if there is one, and waits for the picture to settle
+    fn stop(&mut self, now: Instant) {
+        if self.step_end.take().is_some() {
+            self.settled_at = Some(now + Duration::from_secs_f64(self.config.settle.max(0.0)));
+            self.send(MessageContent::Command(Command::Move {
+                pitch: 0.0,
+                yaw: 0.0,
+            }));
+        }
+    }
+
+    /// Returns the rate to move an axis at during a step to reduce its error
+    fn rate(pid: &mut Pid, error: f64, dead_zone: f64, max_rate: f64, step: f64) -> f64 {
+        if error.abs() <= dead_zone {
+            pid.reset();
+            return 0.0;
+        }
+        pid.update(error, step).clamp(-max_rate, max_rate)
+    }
+
+    fn update(&mut self, now: Instant) {
+        if !self.enabled {
+            return;
+        }
+        let paused = self.is_yielding(now);
+        if paused != self.paused {
+            if !paused {
+                info!("Resuming tracking");
+            }
+            self.paused = paused;
+            self.send_state();
+        }
+        if paused {
+            return;
+        }
+
+        if let Some(step_end) = self.step_end {
+            if now >= step_end {
+                self.stop(now);
+            }
+            return;
+        }
+
+        let timeout = Duration::from_secs_f64(self.config.target_timeout.max(0.0));
+        if self
+            .last_seen
+            .is_some_and(|seen| now.duration_since(seen) >= timeout)
+        {
+            self.target = None;
+            self.last_seen = None;
+            self.reset();
+            return;
+        }
+        let target = match self.target.take() {
+            Some(target) => target,
+            None => return,
+        };
+
+        let (dead_zone, max_rate) = (self.config.dead_zone, self.config.max_rate.abs());
+        let step = self.config.step;
+        let pitch = Self::rate(&mut self.pitch, target.y, dead_zone, max_rate, step);
+        let yaw = Self::rate(&mut self.yaw, target.x, dead_zone, max_rate, step);
+        if pitch != 0.0 || yaw != 0.0 {
+            self.step_end = Some(now + Duration::from_secs_f64(step));
+            self.send(MessageContent::Command(Command::Move { pitch, yaw }));
+        }
+    }
+
+    fn send_state(&self) {
+        self.send(MessageContent::TrackingState {
+            enabled: self.enabled,
+            paused: self.paused,
+        });
+    }
+
+    fn send(&self, content: MessageContent) {
+        self.bus_sink
+            .unbounded_send(Message {
+                content,
+                source: MessageSource::Tracking,
+            })
+            .unwrap_or_else(|err| error!("Failed to send bus message: {}", err));
+    }
+}
+
+enum Event {
+    Message(Message),
+    Tick,
+}
+
+/// Returns the tracking configuration, if it can work with the motion detector's
+fn check_config(config: &Config) -> Result<TrackingConfig, String> {
+    let tracking = config.tracking.clone().unwrap_or_default();
+    let motion = config
+        .motion
+        .as_ref()
+        .ok_or_else(|| "Tracking requires a [motion] section".to_string())?;
+    if tracking.step <= 0.0 {
+        return Err("tracking.step has to be positive".to_string());
+    }
+    // The target can only be seen again once the step is over, the picture has settled and
+    // the detector has analysed another frame
+    let frame_interval = 1.0 / f64::from(motion.max_rate.max(1));
+    let cycle = tracking.step + tracking.settle.max(0.0) + frame_interval;
+    if tracking.target_timeout <= cycle {
+        return Err(format!(
+            "tracking.target_timeout has to be longer than {:.2}s, the time a step and the \
+             settling after it take with the motion detector's max_rate",
+            cycle
+        ));
+    }
+    if tracking.settle < 2.0 * frame_interval {
+        warn!(
+            "tracking.settle is shorter than two motion frames ({:.2}s), so the turret's own \
+             movement may be mistaken for the target",
+            2.0 * frame_interval
+        );
+    }
+    Ok(tracking)
+}
+
+pub fn start(config: Config, bus: Bus<Message>) -> impl Future<Item = (), Error = String> {
+    let (bus_sink, bus_stream) = bus;
+
+    future::result(check_config(&config)).and_then(move |tracking| {
+        let mut tracker = Tracker::new(tracking, bus_sink);
+        bus_stream
+            .map(Event::Message)
+            .map_err(|_| "Failed to read from bus".to_string())
+            .select(
+                Interval::new(Instant::now(), UPDATE_INTERVAL)
+                    .map(|_| Event::Tick)
+                    .map_err(|err| format!("Tracking timer error: {}", err)),
+            )
+            .for_each(move |event| {
+                match event {
+                    Event::Message(message) => tracker.handle_message(message, Instant::now()),
+                    Event::Tick => tracker.update(Instant::now()),
+                }
+                Ok(())
+            })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sentry::bus::{self, BusReceiver};
+    use crate::sentry::Client;
+    use std::net::SocketAddr;
+
+    fn tracker() -> (Tracker, BusReceiver<Message>) {
+        let (bus_sink, bus_stream) = bus::new::<Message>();
+        let config = TrackingConfig {
+            pitch: PidGains {
+                kp: 0.5,
+                ki: 0.0,
+                kd: 0.0,
+            },
+            yaw: PidGains {
+                kp: 2.0,
+                ki: 0.0,
+                kd: 0.0,
+            },
+            dead_zone: 0.1,
+            max_rate: 0.8,
+            ..TrackingConfig::default()
+        };
+        (Tracker::new(config, bus_sink), bus_stream)
+    }
+
+    fn from_client(content: MessageContent) -> Message {
+        Message {
+            content,
+            source: MessageSource::Client(Client {
+                address: SocketAddr::from(([127, 0, 0, 1], 5000)),
+                queue_position: 0,
+            }),
+        }
+    }
+
+    fn motion(x: f64, y: f64) -> Message {
+        Message {
+            content: MessageContent::MotionDetected {
+                region: "frame".to_string(),
+                bbox: BoundingBox {
+                    x,
+                    y,
+                    width: 0.1,
+                    height: 0.1,
+                },
+                alert: false,
+            },
+            source: MessageSource::VideoServer,
+        }
+    }
+
+    /// Takes the moves the tracker has sent so far
+    fn moves(bus_stream: &mut BusReceiver<Message>) -> Vec<(f64, f64)> {
+        let mut moves = Vec::new();
+        while let Ok(Async::Ready(Some(message))) = bus_stream.poll() {
+            if let MessageContent::Command(Command::Move { pitch, yaw }) = message.content {
+                moves.push((pitch, yaw));
+            }
+        }
+        moves
+    }
+
+    #[test]
+    fn pid_accumulates_and_damps() {
+        let mut pid = Pid::new(PidGains {
+            kp: 1.0,
+            ki: 2.0,
+            kd: 0.5,
+        });
+        assert_eq!(pid.update(0.5, 0.1), 0.5 + 2.0 * 0.05);
+        // The error shrinking by 0.1 over 0.1s damps the output
+        let output = pid.update(0.4, 0.1);
+        assert!((output - (0.4 + 2.0 * 0.09 - 0.5)).abs() < 1e-9);
+
+        for _ in 0..100 {
+            pid.update(1.0, 1.0);
+        }
+        assert_eq!(pid.integral, MAX_INTEGRAL);
+        pid.reset();
+        assert_eq!(pid.update(0.0, 0.1), 0.0);
+    }
+
+    #[test]
+    fn steps_toward_target_only_when_enabled() {
+        futures::future::lazy(|| {
+            let (mut tracker, mut bus_stream) = tracker();
+            let now = Instant::now();
+            let at = |millis| now + Duration::from_millis(millis);
+            // Right of centre and well below it
+            tracker.handle_message(motion(0.65, 0.85), now);
+            tracker.update(now);
+            assert!(moves(&mut bus_stream).is_empty());
+
+            tracker.handle_message(from_client(MessageContent::StartTracking), now);
+            tracker.handle_message(motion(0.65, 0.85), now);
+            tracker.update(now);
+            let moves_sent = moves(&mut bus_stream);
+            assert_eq!(moves_sent.len(), 1);
+            let (pitch, yaw) = moves_sent[0];
+            assert!((pitch + 0.4).abs() < 1e-9);
+            assert_eq!(yaw, 0.8);
+
+            // Motion during the step is the turret's own, and the step ends on time
+            tracker.handle_message(motion(0.0, 0.0), at(100));
+            tracker.update(at(100));
+            assert!(moves(&mut bus_stream).is_empty());
+            tracker.update(at(250));
+            assert_eq!(moves(&mut bus_stream), vec![(0.0, 0.0)]);
+
+            // So is motion while the picture settles
+            tracker.handle_message(motion(0.0, 0.0), at(500));
+            tracker.update(at(500));
+            assert!(moves(&mut bus_stream).is_empty());
+
+            // Within the dead zone the turret stays put
+            tracker.handle_message(motion(0.47, 0.45), at(800));
+            tracker.update(at(800));
+            assert!(moves(&mut bus_stream).is_empty());
+
+            tracker.handle_message(motion(0.0, 0.45), at(900));
+            tracker.update(at(900));
+            assert_eq!(moves(&mut bus_stream).len(), 1);
+            tracker.update(at(1150));
+            assert_eq!(moves(&mut bus_stream), vec![(0.0, 0.0)]);
+
+            // A target that hasn't moved for a while is lost
+            tracker.update(at(5000));
+            assert!(tracker.last_seen.is_none());
+            assert!(moves(&mut bus_stream).is_empty());
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    fn config(sections: &str) -> Config {
+        toml::from_str(&format!(
+            r#"
+            camera = {{}}
+
+            [server]
+            host = "127.0.0.1"
+            port = 0
+
+            [video]
+            encoder = "fakesink"
+            decoder = "fakesink"
+            host = "127.0.0.1"
+
+            [arduino]
+            device = "/dev/null"
+            baud = 115200
+            pitch_max_speed = 1000
+            yaw_max_speed = 1000
+            pitch_homing_speed = 1000
+            yaw_homing_speed = 1000
+
+            {}
+            "#,
+            sections
+        ))
+        .expect("Invalid test configuration")
+    }
+
+    #[test]
+    fn checks_the_motion_config() {
+        assert!(check_config(&config("[tracking]")).is_err());
+        assert!(check_config(&config("[tracking]\n[motion]")).is_ok());
+        // A step and settling take 0.95s at 5 frames per second
+        assert!(check_config(&config("[tracking]\ntarget_timeout = 0.9\n[motion]")).is_err());
+        assert!(check_config(&config(
+            "[tracking]\ntarget_timeout = 0.9\n[motion]\nmax_rate = 10"
+        ))
+        .is_ok());
+        assert!(check_config(&config("[tracking]\nstep = 0.0\n[motion]")).is_err());
+    }
+
+    #[test]
+    fn yields_to_the_client_in_control() {
+        futures::future::lazy(|| {
+            let (mut tracker, mut bus_stream) = tracker();
+            let now = Instant::now();
+            tracker.handle_message(from_client(MessageContent::StartTracking), now);
+
+            // An idle joystick doesn't take control
+            let idle = Command::Move {
+                pitch: 0.0,
+                yaw: 0.0,
+            };
+            tracker.handle_message(from_client(MessageContent::Command(idle)), now);
+            tracker.handle_message(motion(0.9, 0.45), now);
+            tracker.update(now);
+            assert_eq!(moves(&mut bus_stream).len(), 1);
+
+            let steer = Command::Move {
+                pitch: 0.0,
+                yaw: -1.0,
+            };
+            tracker.handle_message(from_client(MessageContent::Command(steer)), now);
+            assert!(tracker.paused);
+            tracker.handle_message(motion(0.9, 0.45), now);
+            tracker.update(now + Duration::from_secs(1));
+            assert!(moves(&mut bus_stream).is_empty());
+
+            let later = now + Duration::from_secs(6);
+            tracker.handle_message(motion(0.9, 0.45), later);
+            tracker.update(later);
+            assert!(!tracker.paused);
+            assert_eq!(moves(&mut bus_stream).len(), 1);
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+}
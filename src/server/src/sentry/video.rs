@@ -1,4 +1,5 @@
-use crate::sentry::config::Config;
+use crate::sentry::camera;
+use crate::sentry::config::{Config, VideoSource};
 use crate::sentry::MessageContent::VideoError;
 use crate::sentry::{Bus, BusSender, Client, Message, MessageContent, MessageSource};
 use futures::future;
@@ -6,13 +7,29 @@ use futures::oneshot;
 use gstreamer as gst;
 use gstreamer::prelude::*;
 use rand::prelude::*;
-use std::collections::HashMap;
 use std::fs;
 use std::net::SocketAddr;
-use std::process;
 use std::thread;
+use std::time::{Duration, Instant};
 use tokio::net::UdpSocket;
 use tokio::prelude::*;
+use tokio::timer::Interval;
+
+mod motion;
+mod recording;
+mod snapshot;
+mod webrtc;
+
+use recording::Recorder;
+use snapshot::Snapshotter;
+
+/// How often to check whether a recording of a shot should stop
+const RECORDING_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+enum Event {
+    Message(Message),
+    Tick,
+}
 
 struct UdpHandshakeComplete {
     server_addr: SocketAddr,
@@ -125,44 +142,6 @@ impl Future for UdpHandshake {
     }
 }
 
-fn find_camera_device(properties: &HashMap<String, String>) -> Option<String> {
-    for device in fs::read_dir("/dev")
-        .ok()?
-        .into_iter()
-        .filter_map(|entry| entry.ok())
-        .filter_map(|entry| entry.file_name().into_string().ok())
-        .filter(|name| name.as_str().find("video").is_some())
-        .map(|name| format!("/dev/{}", name))
-    {
-        let info = String::from_utf8(
-            process::Command::new("udevadm")
-                .arg("info")
-                .arg("-a")
-                .arg("-n")
-                .arg(&device)
-                .output()
-                .ok()?
-                .stdout,
-        )
-        .ok()?;
-
-        if properties
-            .into_iter()
-            .filter(|&(property, value)| {
-                info.as_str()
-                    .find(format!("ATTRS{{{}}}==\"{}\"", property, value).as_str())
-                    .is_none()
-            })
-            .count()
-            == 0
-        {
-            // Device matches all properties
-            return Some(device);
-        }
-    }
-    None
-}
-
 pub fn start(config: Config, bus: Bus<Message>) -> impl Future<Item = (), Error = String> {
     let (bus_sink, bus_stream) = bus;
 
@@ -172,25 +151,95 @@ pub fn start(config: Config, bus: Bus<Message>) -> impl Future<Item = (), Error
             move |_| create_pipeline(config)
         })
         .and_then(move |pipeline| {
-            bus_stream
-                .for_each({
-                    let pipeline = pipeline.clone();
-                    let config = config.clone();
-                    move |message| {
-                        match message.content {
-                            MessageContent::ClientConnected(client) => {
-                                tokio::spawn(
-                                    add_client_sink(
-                                        pipeline.clone(),
-                                        config.clone(),
-                                        client.clone(),
-                                        bus_sink.clone(),
-                                    )
-                                    .or_else({
-                                        let bus_sink = bus_sink.clone();
-                                        move |err| {
+            let branches = add_branches(&pipeline, &config, &bus_sink);
+            future::result(branches).and_then(move |(mut recorder, mut snapshotter)| {
+                bus_stream
+                    .map(Event::Message)
+                    .map_err(|_| "Failed to read from bus".to_string())
+                    .select(
+                        Interval::new(Instant::now(), RECORDING_UPDATE_INTERVAL)
+                            .map(|_| Event::Tick)
+                            .map_err(|err| format!("Recording timer error: {}", err)),
+                    )
+                    .for_each({
+                        let pipeline = pipeline.clone();
+                        let config = config.clone();
+                        move |event| {
+                            let message = match event {
+                                Event::Message(message) => message,
+                                Event::Tick => {
+                                    if let Some(recorder) = &mut recorder {
+                                        recorder.update();
+                                    }
+                                    return Ok(());
+                                }
+                            };
+                            if let Some(recorder) = &mut recorder {
+                                recorder.handle_message(&message);
+                            }
+                            if let Some(snapshotter) = &mut snapshotter {
+                                snapshotter.handle_message(&message);
+                            }
+                            match message.content {
+                                // Only clients that ask for RTP get a UDP sink, so browsers
+                                // streaming over WebRTC aren't sent a second copy of the video
+                                MessageContent::StartRtp => {
+                                    let client = match &message.source {
+                                        MessageSource::Client(client) => client.clone(),
+                                        _ => return Ok(()),
+                                    };
+                                    tokio::spawn(
+                                        add_client_sink(
+                                            pipeline.clone(),
+                                            config.clone(),
+                                            client.clone(),
+                                            bus_sink.clone(),
+                                        )
+                                        .or_else({
+                                            let bus_sink = bus_sink.clone();
+                                            move |err| {
+                                                error!(
+                                                    "Error adding video sink for {}: {}",
+                                                    client.address, err
+                                                );
+                                                bus_sink
+                                                    .unbounded_send(Message {
+                                                        content: VideoError {
+                                                            message: err,
+                                                            for_client: Some(client.address),
+                                                        },
+                                                        source: MessageSource::VideoServer,
+                                                    })
+                                                    .unwrap();
+                                                Ok(())
+                                            }
+                                        }),
+                                    );
+                                }
+                                MessageContent::ClientDisconnected(client) => {
+                                    if let Err(err) = drop_client_sink(&pipeline, &client) {
+                                        error!(
+                                            "Error dropping video sink for {}: {}",
+                                            client.address, err
+                                        );
+                                    }
+                                    if let Err(err) = webrtc::drop_peer(&pipeline, client.address) {
+                                        error!(
+                                            "Error dropping WebRTC peer for {}: {}",
+                                            client.address, err
+                                        );
+                                    }
+                                }
+                                MessageContent::StartWebRtc => {
+                                    if let MessageSource::Client(client) = &message.source {
+                                        if let Err(err) = webrtc::add_peer(
+                                            &pipeline,
+                                            &config,
+                                            client.address,
+                                            bus_sink.clone(),
+                                        ) {
                                             error!(
-                                                "Error adding video sink for {}: {}",
+                                                "Error adding WebRTC peer for {}: {}",
                                                 client.address, err
                                             );
                                             bus_sink
@@ -202,42 +251,114 @@ pub fn start(config: Config, bus: Bus<Message>) -> impl Future<Item = (), Error
                                                     source: MessageSource::VideoServer,
                                                 })
                                                 .unwrap();
-                                            Ok(())
                                         }
-                                    }),
-                                );
-                            }
-                            MessageContent::ClientDisconnected(client) => {
-                                if let Err(err) = drop_client_sink(&pipeline, &client) {
-                                    error!(
-                                        "Error dropping video sink for {}: {}",
-                                        client.address, err
-                                    );
+                                    }
+                                }
+                                MessageContent::WebRtcAnswer { sdp } => {
+                                    if let MessageSource::Client(client) = &message.source {
+                                        if let Err(err) =
+                                            webrtc::set_answer(&pipeline, client.address, &sdp)
+                                        {
+                                            error!(
+                                                "Error applying WebRTC answer from {}: {}",
+                                                client.address, err
+                                            );
+                                        }
+                                    }
+                                }
+                                MessageContent::WebRtcIceCandidate {
+                                    candidate,
+                                    sdp_mline_index,
+                                    ..
+                                } => {
+                                    if let MessageSource::Client(client) = &message.source {
+                                        if let Err(err) = webrtc::add_ice_candidate(
+                                            &pipeline,
+                                            client.address,
+                                            sdp_mline_index,
+                                            &candidate,
+                                        ) {
+                                            warn!(
+                                                "Error adding ICE candidate from {}: {}",
+                                                client.address, err
+                                            );
+                                        }
+                                    }
                                 }
+                                _ => {}
                             }
-                            _ => {}
+                            Ok(())
                         }
-                        Ok(())
-                    }
-                })
-                .map(|_| ())
-                .map_err(|err| format!("Error in bus receiver loop: {:?}", err))
-                .select(play_pipeline_future(pipeline))
-                .map_err(|(err, _)| err)
-                .map(|_| ())
+                    })
+                    .map(|_| ())
+                    .select(play_pipeline_future(pipeline))
+                    .map_err(|(err, _)| err)
+                    .map(|_| ())
+            })
         })
 }
 
+/// Returns the gstreamer description of the configured video source
+fn source_description(config: &Config) -> Result<String, String> {
+    match &config.video.source {
+        VideoSource::V4l2 => {
+            let device = camera::find(&config.camera)?.ok_or_else(|| {
+                format!(
+                    "Failed to find camera device matching properties {:?}",
+                    config.camera
+                )
+            })?;
+            info!("Found camera device {}", device);
+            // Catch missing permissions or a vanished device before gstreamer gives a vaguer error
+            fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&device)
+                .map_err(|err| format!("Could not open camera device {}: {}", device, err))?;
+            Ok(format!("v4l2src device={}", device))
+        }
+        VideoSource::Test { pattern } => {
+            Ok(format!("videotestsrc pattern={} is-live=true", pattern))
+        }
+        VideoSource::Uri { uri } => Ok(format!("uridecodebin uri=\"{}\" ! videoconvert", uri)),
+    }
+}
+
+/// Adds the configured recording, snapshot and motion detection branches to the pipeline
+fn add_branches(
+    pipeline: &gst::Pipeline,
+    config: &Config,
+    bus_sink: &BusSender<Message>,
+) -> Result<(Option<Recorder>, Option<Snapshotter>), String> {
+    let recorder = config
+        .recording
+        .clone()
+        .map(|recording| Recorder::new(pipeline, recording, bus_sink.clone()))
+        .transpose()?;
+    let snapshotter = config
+        .snapshots
+        .clone()
+        .map(|snapshots| Snapshotter::new(pipeline, config, snapshots, bus_sink.clone()))
+        .transpose()?;
+    if let Some(motion) = &config.motion {
+        motion::add_branch(pipeline, motion, config.tracking.as_ref(), bus_sink.clone())?;
+    }
+    Ok((recorder, snapshotter))
+}
+
 fn create_pipeline(config: Config) -> Result<gst::Pipeline, String> {
     info!("Creating gstreamer pipeline");
-    let device = find_camera_device(&config.camera).ok_or(format!(
-        "Failed to find camera device matching properties {:?}",
-        config.camera
-    ))?;
-    info!("Found camera device {}", device);
+    let source = source_description(&config)?;
+    info!("Using video source {:?}: {}", config.video.source, source);
+    // Motion detection needs the frames before they are encoded
+    let source = if config.motion.is_some() {
+        format!("{} ! tee name=raw_tee ! queue", source)
+    } else {
+        source
+    };
     let command = format!(
-        "videotestsrc pattern=smpte ! {} ! tee name=tee allow-not-linked=true",
-        //device,
+        "{} ! {} ! tee name=tee allow-not-linked=true",
+        source,
         config.video.encoder.as_str()
     );
 
@@ -356,8 +477,12 @@ fn get_tee(pipeline: &gst::Pipeline) -> Result<gst::Element, String> {
         .ok_or(format!("Could not find element tee"))
 }
 
+/// Removes the client's UDP sink from the pipeline, if they have one
 fn drop_client_sink(pipeline: &gst::Pipeline, client: &Client) -> Result<(), String> {
-    let queue = get_client_queue(pipeline, client)?;
+    let queue = match get_client_queue(pipeline, client) {
+        Ok(queue) => queue,
+        Err(_) => return Ok(()),
+    };
     let sink = get_client_sink(pipeline, client)?;
     let tee = get_tee(pipeline)?;
     tee.unlink(&queue);
@@ -386,6 +511,8 @@ fn add_client_sink(
     info!("Starting UDP handshake with client {}", client.address);
     UdpHandshake::begin(config.clone(), client.clone(), bus_sink.clone()).and_then(
         move |handshake| {
+            // Start over if the client asks again, e.g. after its video player restarted
+            drop_client_sink(&pipeline, &client)?;
             info!("Adding gstreamer sink for client {}", client.address);
             let queue = gst::ElementFactory::make("queue", get_client_queue_name(&client).as_str())
                 .ok_or(format!("Could not create queue element"))?;
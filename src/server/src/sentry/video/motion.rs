@@ -0,0 +1,377 @@
+//! Detects motion by differencing small greyscale frames, tapped from the pipeline before encoding
+use crate::sentry::config::{MotionConfig, MotionRegion, TrackingConfig};
+use crate::sentry::{BoundingBox, BusSender, Message, MessageContent, MessageSource};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Size frames are scaled down to before being compared, which also smooths out sensor noise.
+/// The width is a multiple of 4 so rows of GRAY8 pixels aren't padded.
+const WIDTH: usize = 160;
+const HEIGHT: usize = 120;
+
+/// A region of the frame, in pixels
+struct Region {
+    name: String,
+    left: usize,
+    top: usize,
+    right: usize,
+    bottom: usize,
+    last_reported: Option<Instant>,
+    /// Whether the tracker follows motion here, so it is reported even during the cooldown
+    tracked: bool,
+}
+
+impl Region {
+    fn new(region: &MotionRegion, tracked: bool, width: usize, height: usize) -> Self {
+        let scale =
+            |fraction: f64, size: usize| (fraction.clamp(0.0, 1.0) * size as f64).round() as usize;
+        Region {
+            name: region.name.clone(),
+            left: scale(region.x, width),
+            top: scale(region.y, height),
+            right: scale(region.x + region.width, width),
+            bottom: scale(region.y + region.height, height),
+            last_reported: None,
+            tracked,
+        }
+    }
+
+    fn area(&self) -> usize {
+        self.right.saturating_sub(self.left) * self.bottom.saturating_sub(self.top)
+    }
+}
+
+/// Motion found in one of the regions
+#[derive(Debug, PartialEq)]
+pub struct Detection {
+    pub region: String,
+    pub bbox: BoundingBox,
+    /// Whether the region's cooldown has passed, so clients should be told about it
+    pub alert: bool,
+}
+
+fn difference(a: u8, b: u8) -> u8 {
+    a.max(b) - a.min(b)
+}
+
+pub struct MotionDetector {
+    threshold: u8,
+    min_area: f64,
+    cooldown: Duration,
+    width: usize,
+    height: usize,
+    regions: Vec<Region>,
+    previous: Option<Vec<u8>>,
+}
+
+impl MotionDetector {
+    pub fn new(
+        config: &MotionConfig,
+        tracking: Option<&TrackingConfig>,
+        width: usize,
+        height: usize,
+    ) -> Self {
+        let whole_frame = [MotionRegion {
+            name: "frame".to_string(),
+            x: 0.0,
+            y: 0.0,
+            width: 1.0,
+            height: 1.0,
+        }];
+        let regions = if config.regions.is_empty() {
+            &whole_frame[..]
+        } else {
+            &config.regions[..]
+        };
+        MotionDetector {
+            threshold: config.threshold,
+            min_area: config.min_area,
+            cooldown: Duration::from_secs_f64(config.cooldown.max(0.0)),
+            width,
+            height,
+            regions: regions
+                .iter()
+                .map(|region| {
+                    let tracked = tracking.is_some_and(|tracking| {
+                        tracking
+                            .region
+                            .as_ref()
+                            .is_none_or(|name| *name == region.name)
+                    });
+                    Region::new(region, tracked, width, height)
+                })
+                .collect(),
+            previous: None,
+        }
+    }
+
+    /// Compares a GRAY8 frame to the previous one, returning the regions that moved and the
+    /// bounding box of the movement in each
+    pub fn process(&mut self, frame: &[u8], now: Instant) -> Vec<Detection> {
+        if frame.len() < self.width * self.height {
+            return Vec::new();
+        }
+        let previous = match self.previous.replace(frame.to_vec()) {
+            Some(previous) => previous,
+            None => return Vec::new(),
+        };
+
+        let (width, height, cooldown) = (self.width, self.height, self.cooldown);
+        let mut detections = Vec::new();
+        for region in &mut self.regions {
+            let cooling_down = region
+                .last_reported
+                .map(|time| now.duration_since(time) < cooldown)
+                .unwrap_or(false);
+            if (cooling_down && !region.tracked) || region.area() == 0 {
+                continue;
+            }
+
+            let mut changed = 0;
+            let (mut left, mut top, mut right, mut bottom) = (usize::MAX, usize::MAX, 0, 0);
+            for y in region.top..region.bottom {
+                for x in region.left..region.right {
+                    let index = y * width + x;
+                    if difference(frame[index], previous[index]) > self.threshold {
+                        changed += 1;
+                        left = left.min(x);
+                        top = top.min(y);
+                        right = right.max(x + 1);
+                        bottom = bottom.max(y + 1);
+                    }
+                }
+            }
+            if changed == 0 || (changed as f64) < self.min_area * region.area() as f64 {
+                continue;
+            }
+
+            if !cooling_down {
+                region.last_reported = Some(now);
+            }
+            detections.push(Detection {
+                region: region.name.clone(),
+                bbox: BoundingBox {
+                    x: left as f64 / width as f64,
+                    y: top as f64 / height as f64,
+                    width: (right - left) as f64 / width as f64,
+                    height: (bottom - top) as f64 / height as f64,
+                },
+                alert: !cooling_down,
+            });
+        }
+        detections
+    }
+}
+
+/// Adds a branch off the raw video tee that feeds scaled down frames to a motion detector
+pub fn add_branch(
+    pipeline: &gst::Pipeline,
+    config: &MotionConfig,
+    tracking: Option<&TrackingConfig>,
+    bus_sink: BusSender<Message>,
+) -> Result<(), String> {
+    let description = format!(
+        "queue leaky=downstream max-size-buffers=1 ! videoconvert ! videoscale \
+         ! videorate drop-only=true max-rate={} \
+         ! video/x-raw,format=GRAY8,width={},height={} \
+         ! appsink name=motion_sink emit-signals=true drop=true max-buffers=1 sync=false",
+        config.max_rate, WIDTH, HEIGHT
+    );
+    let bin = gst::parse_bin_from_description(&description, true)
+        .map_err(|err| format!("Failed to parse motion branch \"{}\": {}", description, err))?;
+    bin.set_name("motion")
+        .map_err(|_| "Could not name motion branch".to_string())?;
+    let sink = bin
+        .get_by_name("motion_sink")
+        .ok_or_else(|| "Could not find element motion_sink".to_string())?;
+
+    let detector = Mutex::new(MotionDetector::new(config, tracking, WIDTH, HEIGHT));
+    let bus_sink = Mutex::new(bus_sink);
+    sink.connect("new-sample", false, move |values| {
+        let sample = values[0]
+            .get::<gst::Element>()
+            .and_then(|sink| sink.emit("pull-sample", &[]).ok())
+            .and_then(|value| value)
+            .and_then(|value| value.get::<gst::Sample>());
+        let buffer = match sample.as_ref().and_then(|sample| sample.get_buffer()) {
+            Some(buffer) => buffer,
+            None => return Some(gst::FlowReturn::Error.to_value()),
+        };
+        if let Some(map) = buffer.map_readable() {
+            let detections = detector
+                .lock()
+                .unwrap()
+                .process(map.as_slice(), Instant::now());
+            for Detection {
+                region,
+                bbox,
+                alert,
+            } in detections
+            {
+                if alert {
+                    info!("Motion detected in region {} at {:?}", region, bbox);
+                }
+                bus_sink
+                    .lock()
+                    .unwrap()
+                    .unbounded_send(Message {
+                        content: MessageContent::MotionDetected {
+                            region,
+                            bbox,
+                            alert,
+                        },
+                        source: MessageSource::VideoServer,
+                    })
+                    .unwrap_or_else(|err| error!("Failed to send bus message: {}", err));
+            }
+        }
+        Some(gst::FlowReturn::Ok.to_value())
+    })
+    .map_err(|_| "Could not connect to new-sample of motion_sink".to_string())?;
+
+    let raw_tee = pipeline
+        .get_by_name("raw_tee")
+        .ok_or_else(|| "Could not find element raw_tee".to_string())?;
+    pipeline
+        .add(&bin)
+        .map_err(|_| "Could not add motion branch to pipeline".to_string())?;
+    raw_tee
+        .link(&bin)
+        .map_err(|_| "Could not link raw_tee to motion branch".to_string())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A dark frame with a bright square, like `videotestsrc pattern=ball` draws
+    fn frame_with_square(left: usize, top: usize, size: usize) -> Vec<u8> {
+        let mut frame = vec![0; WIDTH * HEIGHT];
+        for y in top..top + size {
+            for x in left..left + size {
+                frame[y * WIDTH + x] = 255;
+            }
+        }
+        frame
+    }
+
+    fn config(regions: Vec<MotionRegion>) -> MotionConfig {
+        MotionConfig {
+            regions,
+            ..MotionConfig::default()
+        }
+    }
+
+    #[test]
+    fn detects_moving_square() {
+        let mut detector = MotionDetector::new(&config(Vec::new()), None, WIDTH, HEIGHT);
+        let start = Instant::now();
+        assert!(detector
+            .process(&frame_with_square(0, 0, 20), start)
+            .is_empty());
+        assert!(detector
+            .process(&frame_with_square(0, 0, 20), start)
+            .is_empty());
+
+        let detections = detector.process(&frame_with_square(40, 60, 20), start);
+        assert_eq!(
+            detections,
+            vec![Detection {
+                region: "frame".to_string(),
+                bbox: BoundingBox {
+                    x: 0.0,
+                    y: 0.0,
+                    width: 60.0 / 160.0,
+                    height: 80.0 / 120.0,
+                },
+                alert: true,
+            }]
+        );
+
+        // Reports are rate-limited per region
+        let moved = frame_with_square(80, 60, 20);
+        assert!(detector.process(&moved, start).is_empty());
+        let later = start + Duration::from_secs(3);
+        assert_eq!(
+            detector.process(&frame_with_square(0, 0, 20), later).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn only_reports_regions_that_moved() {
+        let region = |name: &str, x: f64| MotionRegion {
+            name: name.to_string(),
+            x,
+            y: 0.0,
+            width: 0.5,
+            height: 1.0,
+        };
+        let mut detector = MotionDetector::new(
+            &config(vec![region("left", 0.0), region("right", 0.5)]),
+            None,
+            WIDTH,
+            HEIGHT,
+        );
+        let start = Instant::now();
+        detector.process(&frame_with_square(100, 10, 20), start);
+        let detections = detector.process(&frame_with_square(120, 50, 20), start);
+        assert_eq!(detections.len(), 1);
+        assert_eq!(detections[0].region, "right");
+        assert_eq!(detections[0].bbox.x, 100.0 / 160.0);
+
+        // A few noisy pixels don't count
+        let mut noisy = frame_with_square(120, 50, 20);
+        noisy[0] = 255;
+        let later = start + Duration::from_secs(3);
+        assert!(detector.process(&noisy, later).is_empty());
+    }
+
+    #[test]
+    fn keeps_reporting_the_tracked_region() {
+        let region = |name: &str, x: f64| MotionRegion {
+            name: name.to_string(),
+            x,
+            y: 0.0,
+            width: 0.5,
+            height: 1.0,
+        };
+        let tracking = TrackingConfig {
+            region: Some("left".to_string()),
+            ..TrackingConfig::default()
+        };
+        let mut detector = MotionDetector::new(
+            &config(vec![region("left", 0.0), region("right", 0.5)]),
+            Some(&tracking),
+            WIDTH,
+            HEIGHT,
+        );
+        let start = Instant::now();
+        let squares = |x: usize| {
+            let mut frame = frame_with_square(x, 10, 20);
+            for (index, pixel) in frame_with_square(x + 80, 10, 20).into_iter().enumerate() {
+                frame[index] = frame[index].max(pixel);
+            }
+            frame
+        };
+        detector.process(&squares(0), start);
+        let alerts = |detections: Vec<Detection>| {
+            detections
+                .into_iter()
+                .map(|detection| (detection.region, detection.alert))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            alerts(detector.process(&squares(20), start)),
+            vec![("left".to_string(), true), ("right".to_string(), true)]
+        );
+        // Only the tracked region is reported during the cooldown, without alerting clients
+        assert_eq!(
+            alerts(detector.process(&squares(40), start)),
+            vec![("left".to_string(), false)]
+        );
+    }
+}
@@ -0,0 +1,376 @@
+//! Records the encoded video to disk, on request and around every shot
+use super::get_tee;
+use crate::sentry::config::{self, RecordingConfig};
+use crate::sentry::{BusSender, Command, CommandOutcome, Message, MessageContent, MessageSource};
+use chrono::Local;
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::prelude::*;
+use tokio::timer::Delay;
+
+const FILE_PREFIX: &str = "sentry-";
+
+/// How long to give the muxer to finish writing a file before the recording is torn down
+const FINALIZE_DELAY: Duration = Duration::from_secs(5);
+
+/// Deletes the oldest recordings until they fit in `max_size` bytes, and any older than `max_age`
+fn prune(directory: &Path, max_size: Option<u64>, max_age: Option<Duration>) -> Result<(), String> {
+    let mut files = fs::read_dir(directory)
+        .map_err(|err| format!("Could not list \"{}\": {}", directory.display(), err))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(FILE_PREFIX))
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            Some((entry.path(), metadata.len(), metadata.modified().ok()?))
+        })
+        .collect::<Vec<_>>();
+    // File names start with the time they were created, so this puts the oldest first
+    files.sort();
+
+    let mut total_size = files.iter().map(|&(_, size, _)| size).sum::<u64>();
+    for (path, size, modified) in files {
+        let too_big = max_size
+            .map(|max_size| total_size > max_size)
+            .unwrap_or(false);
+        let too_old = max_age
+            .and_then(|max_age| Some(SystemTime::now().duration_since(modified).ok()? > max_age))
+            .unwrap_or(false);
+        if !too_big && !too_old {
+            continue;
+        }
+        info!("Deleting old recording \"{}\"", path.display());
+        fs::remove_file(&path)
+            .map_err(|err| format!("Could not delete \"{}\": {}", path.display(), err))?;
+        total_size -= size;
+    }
+    Ok(())
+}
+
+/// The muxing half of a recording, which is added to the pipeline while recording
+fn create_branch(
+    config: &RecordingConfig,
+    directory: &Path,
+    name: &str,
+) -> Result<gst::Bin, String> {
+    let description = format!(
+        "{} ! splitmuxsink name=splitmuxsink max-size-time={}",
+        config.depayloader,
+        Duration::from_secs(config.segment_duration).as_nanos()
+    );
+    let bin = gst::parse_bin_from_description(&description, true).map_err(|err| {
+        format!(
+            "Failed to parse recording branch \"{}\": {}",
+            description, err
+        )
+    })?;
+    bin.set_name(name)
+        .map_err(|_| format!("Could not name recording branch {}", name))?;
+
+    let splitmuxsink = bin
+        .get_by_name("splitmuxsink")
+        .ok_or_else(|| "Could not find element splitmuxsink".to_string())?;
+    let muxer = gst::ElementFactory::make(config.format.muxer(), None)
+        .ok_or_else(|| format!("Could not create {} element", config.format.muxer()))?;
+    splitmuxsink
+        .set_property("muxer", &muxer)
+        .map_err(|_| "Could not set muxer of splitmuxsink".to_string())?;
+
+    // Name each file after the time it was started, and make room for it
+    let directory = directory.to_path_buf();
+    let extension = config.format.extension();
+    let max_size = config.max_size;
+    let max_age = config.max_age.map(Duration::from_secs);
+    splitmuxsink
+        .connect("format-location", false, move |_| {
+            if let Err(err) = prune(&directory, max_size, max_age) {
+                error!("Could not delete old recordings: {}", err);
+            }
+            let path = directory.join(format!(
+                "{}{}.{}",
+                FILE_PREFIX,
+                Local::now().format("%Y%m%d-%H%M%S-%3f"),
+                extension
+            ));
+            info!("Recording to \"{}\"", path.display());
+            Some(path.to_string_lossy().to_value())
+        })
+        .map_err(|_| "Could not connect to format-location of splitmuxsink".to_string())?;
+
+    // Drop frames until the first keyframe, so every recording can be decoded from the start
+    if let Some(pad) = splitmuxsink.get_sink_pads().first() {
+        pad.add_probe(gst::PadProbeType::BUFFER, |_, info| match &info.data {
+            Some(gst::PadProbeData::Buffer(buffer))
+                if buffer.get_flags().contains(gst::BufferFlags::DELTA_UNIT) =>
+            {
+                gst::PadProbeReturn::Drop
+            }
+            _ => gst::PadProbeReturn::Remove,
+        });
+    }
+
+    Ok(bin)
+}
+
+/// Decides when to record, and adds and removes the muxing branch from the pipeline accordingly
+pub struct Recorder {
+    config: RecordingConfig,
+    directory: PathBuf,
+    pipeline: gst::Pipeline,
+    /// Always linked to the tee, holding the last `pre_roll` seconds of video while not recording
+    buffer: gst::Element,
+    /// Holds back the buffer while not recording
+    block: Option<gst::PadProbeId>,
+    branch: Option<gst::Bin>,
+    count: u32,
+    /// Whether a client asked for a recording, which then runs until one stops it
+    requested: bool,
+    /// When to stop recording a shot
+    shot_until: Option<Instant>,
+    bus_sink: BusSender<Message>,
+}
+
+impl Recorder {
+    pub fn new(
+        pipeline: &gst::Pipeline,
+        config: RecordingConfig,
+        bus_sink: BusSender<Message>,
+    ) -> Result<Self, String> {
+        let directory = config::file_path(&config.path)?;
+        fs::create_dir_all(&directory).map_err(|err| {
+            format!(
+                "Could not create recording directory \"{}\": {}",
+                directory.display(),
+                err
+            )
+        })?;
+        info!("Recordings will be saved to \"{}\"", directory.display());
+
+        let buffer = gst::ElementFactory::make("queue", "recording_buffer")
+            .ok_or_else(|| "Could not create queue element".to_string())?;
+        // Leak the oldest video, so the queue always holds the most recent
+        buffer.set_property_from_str("leaky", "downstream");
+        buffer.set_property_from_str("max-size-bytes", "0");
+        if config.pre_roll > 0 {
+            buffer.set_property_from_str("max-size-buffers", "0");
+            buffer.set_property_from_str(
+                "max-size-time",
+                &Duration::from_secs(config.pre_roll).as_nanos().to_string(),
+            );
+        } else {
+            buffer.set_property_from_str("max-size-buffers", "1");
+            buffer.set_property_from_str("max-size-time", "0");
+        }
+
+        let mut recorder = Recorder {
+            config,
+            directory,
+            pipeline: pipeline.clone(),
+            buffer,
+            block: None,
+            branch: None,
+            count: 0,
+            requested: false,
+            shot_until: None,
+            bus_sink,
+        };
+        recorder.block()?;
+
+        let tee = get_tee(pipeline)?;
+        pipeline
+            .add(&recorder.buffer)
+            .map_err(|_| "Could not add recording_buffer to pipeline".to_string())?;
+        tee.link(&recorder.buffer)
+            .map_err(|_| "Could not link tee to recording_buffer".to_string())?;
+        Ok(recorder)
+    }
+
+    fn buffer_src(&self) -> Result<gst::Pad, String> {
+        self.buffer
+            .get_static_pad("src")
+            .ok_or_else(|| "Could not get src pad of recording_buffer".to_string())
+    }
+
+    fn block(&mut self) -> Result<(), String> {
+        self.block = self
+            .buffer_src()?
+            .add_probe(gst::PadProbeType::BLOCK_DOWNSTREAM, |_, _| {
+                gst::PadProbeReturn::Ok
+            });
+        Ok(())
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.branch.is_some()
+    }
+
+    fn start(&mut self) -> Result<(), String> {
+        self.count += 1;
+        let branch = create_branch(
+            &self.config,
+            &self.directory,
+            &format!("recording_{}", self.count),
+        )?;
+        let sink = branch
+            .get_static_pad("sink")
+            .ok_or_else(|| "Recording branch has no sink pad".to_string())?;
+
+        self.pipeline
+            .add(&branch)
+            .map_err(|_| format!("Could not add {} to pipeline", branch.get_name()))?;
+        self.buffer_src()?.link(&sink).map_err(|err| {
+            format!(
+                "Could not link recording_buffer to {}: {:?}",
+                branch.get_name(),
+                err
+            )
+        })?;
+        branch
+            .sync_state_with_parent()
+            .map_err(|_| format!("Could not start {}", branch.get_name()))?;
+
+        // Let the buffered video flow into the new files
+        if let Some(block) = self.block.take() {
+            self.buffer_src()?.remove_probe(block);
+        }
+        self.branch = Some(branch);
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), String> {
+        let branch = match self.branch.take() {
+            Some(branch) => branch,
+            None => return Ok(()),
+        };
+        self.block()?;
+
+        // Finish the current file, then remove the branch once the muxer has had time to write it
+        let sink = branch
+            .get_static_pad("sink")
+            .ok_or_else(|| "Recording branch has no sink pad".to_string())?;
+        self.buffer_src()?
+            .unlink(&sink)
+            .map_err(|_| format!("Could not unlink {}", branch.get_name()))?;
+        sink.send_event(gst::Event::new_eos().build());
+
+        let pipeline = self.pipeline.clone();
+        tokio::spawn(Delay::new(Instant::now() + FINALIZE_DELAY).then(move |_| {
+            if branch.set_state(gst::State::Null).is_err() || pipeline.remove(&branch).is_err() {
+                error!("Could not remove {} from pipeline", branch.get_name());
+            }
+            Ok(())
+        }));
+        Ok(())
+    }
+
+    /// Starts or stops recording, whichever is now needed
+    pub fn update(&mut self) {
+        let shooting = self
+            .shot_until
+            .map(|until| Instant::now() < until)
+            .unwrap_or(false);
+        let should_record = self.requested || shooting;
+        if should_record == self.is_recording() {
+            return;
+        }
+
+        let result = if should_record {
+            info!("Starting recording");
+            self.start()
+        } else {
+            info!("Stopping recording");
+            self.stop()
+        };
+        if let Err(err) = result {
+            error!("Recording error: {}", err);
+            self.requested = false;
+            self.shot_until = None;
+        }
+        self.send_state();
+    }
+
+    pub fn handle_message(&mut self, message: &Message) {
+        match &message.content {
+            MessageContent::CommandProcessed {
+                command: Command::Fire,
+                outcome: CommandOutcome::Forwarded,
+                ..
+            }
+            | MessageContent::CommandProcessed {
+                command: Command::FireAndReload,
+                outcome: CommandOutcome::Forwarded,
+                ..
+            } => {
+                self.shot_until =
+                    Some(Instant::now() + Duration::from_secs(self.config.fire_duration));
+            }
+            MessageContent::StartRecording | MessageContent::StopRecording => {
+                // Only the client in control of the turret can start or stop recording
+                match &message.source {
+                    MessageSource::Client(client) if client.queue_position == 0 => {}
+                    _ => return,
+                }
+                self.requested = matches!(message.content, MessageContent::StartRecording);
+            }
+            MessageContent::ClientConnected(_) => self.send_state(),
+            _ => return,
+        }
+        self.update();
+    }
+
+    fn send_state(&self) {
+        self.bus_sink
+            .unbounded_send(Message {
+                content: MessageContent::RecordingState {
+                    active: self.is_recording(),
+                },
+                source: MessageSource::VideoServer,
+            })
+            .unwrap_or_else(|err| error!("Failed to send bus message: {}", err));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn prunes_oldest_recordings() {
+        let directory = env::temp_dir().join("sentry-recording-prune");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        for name in &[
+            "sentry-20190101-120000-000.mp4",
+            "sentry-20190101-120100-000.mp4",
+            "sentry-20190101-120200-000.mp4",
+            "notes.txt",
+        ] {
+            fs::write(directory.join(name), [0; 100].as_ref()).unwrap();
+        }
+
+        prune(&directory, Some(250), None).unwrap();
+        let mut remaining = fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        remaining.sort();
+        assert_eq!(
+            remaining,
+            vec![
+                "notes.txt",
+                "sentry-20190101-120100-000.mp4",
+                "sentry-20190101-120200-000.mp4"
+            ]
+        );
+
+        prune(&directory, None, Some(Duration::from_secs(3600))).unwrap();
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 3);
+        prune(&directory, None, Some(Duration::from_secs(0))).unwrap();
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 1);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,250 @@
+//! Saves JPEG frames from the video, on request and whenever the turret fires
+use super::get_tee;
+use crate::sentry::config::{self, Config, SnapshotConfig};
+use crate::sentry::{
+    BusSender, Command, CommandOutcome, Message, MessageContent, MessageSource, SnapshotReason,
+};
+use chrono::{Local, SecondsFormat};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use serde_json::json;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait for a frame when the last one has already been taken
+const FRAME_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Waits for the next JPEG frame from the appsink
+fn pull_frame(sink: &gst::Element) -> Result<Vec<u8>, String> {
+    let sample = sink
+        .emit("try-pull-sample", &[&(FRAME_TIMEOUT.as_nanos() as u64)])
+        .map_err(|err| format!("Could not pull sample from snapshot_sink: {}", err))?
+        .and_then(|value| value.get::<gst::Sample>())
+        .ok_or_else(|| "No video frame available for snapshot".to_string())?;
+    let buffer = sample
+        .get_buffer()
+        .ok_or_else(|| "Snapshot sample has no buffer".to_string())?;
+    let map = buffer
+        .map_readable()
+        .ok_or_else(|| "Could not read snapshot buffer".to_string())?;
+    Ok(map.as_slice().to_vec())
+}
+
+/// Where the turret was pointing when a snapshot was taken
+#[derive(Clone, Copy)]
+struct Position {
+    pitch: u32,
+    yaw: u32,
+    pitch_degrees: f64,
+    yaw_degrees: f64,
+}
+
+/// Writes the frame and a JSON file describing it, returning the file name of the frame
+fn save(
+    directory: &Path,
+    frame: &[u8],
+    position: Option<Position>,
+    reason: SnapshotReason,
+) -> Result<String, String> {
+    let time = Local::now();
+    let stem = format!("snapshot-{}", time.format("%Y%m%d-%H%M%S-%3f"));
+    let file_name = format!("{}.jpg", stem);
+
+    let path = directory.join(&file_name);
+    fs::write(&path, frame)
+        .map_err(|err| format!("Could not write \"{}\": {}", path.display(), err))?;
+
+    let metadata = json!({
+        "file_name": file_name,
+        "time": time.to_rfc3339_opts(SecondsFormat::Millis, true),
+        "reason": reason.name(),
+        "pitch": position.map(|position| position.pitch),
+        "yaw": position.map(|position| position.yaw),
+        "pitch_degrees": position.map(|position| position.pitch_degrees),
+        "yaw_degrees": position.map(|position| position.yaw_degrees),
+    });
+    let path = directory.join(format!("{}.json", stem));
+    fs::write(&path, metadata.to_string())
+        .map_err(|err| format!("Could not write \"{}\": {}", path.display(), err))?;
+
+    Ok(file_name)
+}
+
+/// Keeps a branch off the tee encoding a few JPEG frames a second, and saves one when asked to
+pub struct Snapshotter {
+    config: Config,
+    on_fire: bool,
+    directory: PathBuf,
+    sink: gst::Element,
+    /// Last known turret position, if it has been homed
+    position: Option<Position>,
+    bus_sink: BusSender<Message>,
+}
+
+impl Snapshotter {
+    pub fn new(
+        pipeline: &gst::Pipeline,
+        config: &Config,
+        snapshots: SnapshotConfig,
+        bus_sink: BusSender<Message>,
+    ) -> Result<Self, String> {
+        let directory = config::file_path(&snapshots.path)?;
+        fs::create_dir_all(&directory).map_err(|err| {
+            format!(
+                "Could not create snapshot directory \"{}\": {}",
+                directory.display(),
+                err
+            )
+        })?;
+        info!("Snapshots will be saved to \"{}\"", directory.display());
+
+        // Only the newest frame is kept, and older ones are dropped rather than holding up the tee
+        let description = format!(
+            "queue leaky=downstream max-size-buffers=1 ! {} ! videoconvert \
+             ! videorate drop-only=true max-rate={} ! jpegenc quality={} \
+             ! appsink name=snapshot_sink drop=true max-buffers=1 sync=false",
+            snapshots.decoder, snapshots.max_rate, snapshots.quality
+        );
+        let bin = gst::parse_bin_from_description(&description, true).map_err(|err| {
+            format!(
+                "Failed to parse snapshot branch \"{}\": {}",
+                description, err
+            )
+        })?;
+        bin.set_name("snapshots")
+            .map_err(|_| "Could not name snapshot branch".to_string())?;
+        let sink = bin
+            .get_by_name("snapshot_sink")
+            .ok_or_else(|| "Could not find element snapshot_sink".to_string())?;
+
+        let tee = get_tee(pipeline)?;
+        pipeline
+            .add(&bin)
+            .map_err(|_| "Could not add snapshot branch to pipeline".to_string())?;
+        tee.link(&bin)
+            .map_err(|_| "Could not link tee to snapshot branch".to_string())?;
+
+        Ok(Snapshotter {
+            config: config.clone(),
+            on_fire: snapshots.on_fire,
+            directory,
+            sink,
+            position: None,
+            bus_sink,
+        })
+    }
+
+    /// Saves the next frame on a thread of its own, since it may have to wait for one
+    fn take(&self, reason: SnapshotReason, for_client: Option<SocketAddr>) {
+        let directory = self.directory.clone();
+        let sink = self.sink.clone();
+        let position = self.position;
+        let bus_sink = self.bus_sink.clone();
+
+        thread::spawn(move || {
+            let content = match pull_frame(&sink)
+                .and_then(|frame| save(&directory, &frame, position, reason))
+            {
+                Ok(file_name) => {
+                    info!("Saved snapshot {}", file_name);
+                    MessageContent::Snapshot {
+                        file_name,
+                        pitch: position.map(|position| position.pitch),
+                        yaw: position.map(|position| position.yaw),
+                        reason,
+                    }
+                }
+                Err(err) => {
+                    error!("Could not take snapshot: {}", err);
+                    MessageContent::VideoError {
+                        message: err,
+                        for_client,
+                    }
+                }
+            };
+            bus_sink
+                .unbounded_send(Message {
+                    content,
+                    source: MessageSource::VideoServer,
+                })
+                .unwrap_or_else(|err| error!("Failed to send bus message: {}", err));
+        });
+    }
+
+    pub fn handle_message(&mut self, message: &Message) {
+        match &message.content {
+            MessageContent::HardwareState {
+                pitch_pos,
+                yaw_pos,
+                homed,
+                ..
+            } => {
+                self.position = if *homed {
+                    Some(Position {
+                        pitch: *pitch_pos,
+                        yaw: *yaw_pos,
+                        pitch_degrees: self.config.arduino.pitch.to_degrees(*pitch_pos),
+                        yaw_degrees: self.config.arduino.yaw.to_degrees(*yaw_pos),
+                    })
+                } else {
+                    None
+                };
+            }
+            MessageContent::CommandProcessed {
+                command: Command::Fire,
+                outcome: CommandOutcome::Forwarded,
+                ..
+            }
+            | MessageContent::CommandProcessed {
+                command: Command::FireAndReload,
+                outcome: CommandOutcome::Forwarded,
+                ..
+            } if self.on_fire => self.take(SnapshotReason::Fire, None),
+            MessageContent::TakeSnapshot => {
+                let client = match &message.source {
+                    MessageSource::Client(client) => Some(client.address),
+                    _ => None,
+                };
+                self.take(SnapshotReason::Request, client);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn saves_frame_with_position() {
+        let directory = env::temp_dir().join("sentry-snapshots");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+
+        let position = Position {
+            pitch: 1440,
+            yaw: 100,
+            pitch_degrees: 0.0,
+            yaw_degrees: -85.5,
+        };
+        let file_name = save(&directory, b"jpeg", Some(position), SnapshotReason::Fire).unwrap();
+        assert!(file_name.starts_with("snapshot-") && file_name.ends_with(".jpg"));
+        assert_eq!(fs::read(directory.join(&file_name)).unwrap(), b"jpeg");
+
+        let metadata: serde_json::Value = serde_json::from_str(
+            &fs::read_to_string(directory.join(file_name.replace(".jpg", ".json"))).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(metadata["file_name"], file_name.as_str());
+        assert_eq!(metadata["reason"], "fire");
+        assert_eq!(metadata["pitch"], 1440);
+        assert_eq!(metadata["yaw_degrees"], -85.5);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
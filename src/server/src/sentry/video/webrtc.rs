@@ -0,0 +1,356 @@
+//! Streams the camera to browsers through webrtcbin, with signalling carried over the control connection
+use super::get_tee;
+use crate::sentry::config::Config;
+use crate::sentry::{BusSender, Message, MessageContent, MessageSource};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_sdp as gst_sdp;
+use gstreamer_webrtc as gst_webrtc;
+use std::net::SocketAddr;
+
+fn get_queue_name(client: SocketAddr) -> String {
+    format!("webrtc_queue_{}", client)
+}
+
+fn get_webrtcbin_name(client: SocketAddr) -> String {
+    format!("webrtc_{}", client)
+}
+
+fn get_webrtcbin(pipeline: &gst::Pipeline, client: SocketAddr) -> Result<gst::Element, String> {
+    pipeline
+        .get_by_name(get_webrtcbin_name(client).as_str())
+        .ok_or_else(|| format!("Client {} has not started a WebRTC session", client))
+}
+
+fn send(bus_sink: &BusSender<Message>, content: MessageContent) {
+    bus_sink
+        .unbounded_send(Message {
+            content,
+            source: MessageSource::VideoServer,
+        })
+        .unwrap_or_else(|err| error!("Failed to send bus message: {}", err));
+}
+
+fn send_error(bus_sink: &BusSender<Message>, client: SocketAddr, message: String) {
+    error!("WebRTC error for client {}: {}", client, message);
+    send(
+        bus_sink,
+        MessageContent::VideoError {
+            message,
+            for_client: Some(client),
+        },
+    );
+}
+
+/// Asks webrtcbin for an offer and passes it on to the client
+fn create_offer(
+    webrtcbin: &gst::Element,
+    client: SocketAddr,
+    bus_sink: BusSender<Message>,
+) -> Result<(), String> {
+    let promise = gst::Promise::new_with_change_func({
+        let webrtcbin = webrtcbin.clone();
+        move |promise| {
+            let offer = promise
+                .get_reply()
+                .and_then(|reply| reply.get::<gst_webrtc::WebRTCSessionDescription>("offer"));
+            let offer = match offer {
+                Some(offer) => offer,
+                None => return send_error(&bus_sink, client, "Could not create offer".to_string()),
+            };
+            if let Err(err) =
+                webrtcbin.emit("set-local-description", &[&offer, &None::<gst::Promise>])
+            {
+                return send_error(
+                    &bus_sink,
+                    client,
+                    format!("Could not set local description: {}", err),
+                );
+            }
+            match offer.get_sdp().as_text() {
+                Some(sdp) => send(
+                    &bus_sink,
+                    MessageContent::WebRtcOffer {
+                        sdp,
+                        for_client: client,
+                    },
+                ),
+                None => send_error(&bus_sink, client, "Could not serialize offer".to_string()),
+            }
+        }
+    });
+
+    webrtcbin
+        .emit("create-offer", &[&None::<gst::Structure>, &promise])
+        .map(|_| ())
+        .map_err(|err| format!("Could not create offer: {}", err))
+}
+
+/// Adds a webrtcbin for the client to the pipeline, which starts negotiation by sending them an offer
+pub fn add_peer(
+    pipeline: &gst::Pipeline,
+    config: &Config,
+    client: SocketAddr,
+    bus_sink: BusSender<Message>,
+) -> Result<(), String> {
+    // Start over if the client is renegotiating, e.g. after reloading the page
+    if get_webrtcbin(pipeline, client).is_ok() {
+        drop_peer(pipeline, client)?;
+    }
+
+    info!("Adding WebRTC peer for client {}", client);
+    let queue = gst::ElementFactory::make("queue", get_queue_name(client).as_str())
+        .ok_or_else(|| "Could not create queue element".to_string())?;
+    let webrtcbin = gst::ElementFactory::make("webrtcbin", get_webrtcbin_name(client).as_str())
+        .ok_or_else(|| "Could not create webrtcbin element".to_string())?;
+    let tee = get_tee(pipeline)?;
+
+    webrtcbin.set_property_from_str("bundle-policy", "max-bundle");
+    if let Some(stun_server) = &config.video.stun_server {
+        webrtcbin.set_property_from_str("stun-server", stun_server);
+    }
+
+    webrtcbin
+        .connect("on-negotiation-needed", false, {
+            let bus_sink = bus_sink.clone();
+            move |values| {
+                let webrtcbin = values[0].get::<gst::Element>().unwrap();
+                if let Err(err) = create_offer(&webrtcbin, client, bus_sink.clone()) {
+                    send_error(&bus_sink, client, err);
+                }
+                None
+            }
+        })
+        .map_err(|err| format!("Could not connect to on-negotiation-needed: {}", err))?;
+    webrtcbin
+        .connect("on-ice-candidate", false, move |values| {
+            if let (Some(sdp_mline_index), Some(candidate)) =
+                (values[1].get::<u32>(), values[2].get::<String>())
+            {
+                send(
+                    &bus_sink,
+                    MessageContent::WebRtcIceCandidate {
+                        candidate,
+                        sdp_mline_index,
+                        for_client: Some(client),
+                    },
+                );
+            }
+            None
+        })
+        .map_err(|err| format!("Could not connect to on-ice-candidate: {}", err))?;
+
+    pipeline
+        .add_many(&[&queue, &webrtcbin])
+        .map_err(|_| format!("Could not add WebRTC elements for {} to pipeline", client))?;
+    tee.link(&queue)
+        .map_err(|_| format!("Could not link {} to {}", tee.get_name(), queue.get_name()))?;
+    queue.link(&webrtcbin).map_err(|_| {
+        format!(
+            "Could not link {} to {}",
+            queue.get_name(),
+            webrtcbin.get_name()
+        )
+    })?;
+    for element in &[&queue, &webrtcbin] {
+        element
+            .sync_state_with_parent()
+            .map_err(|_| format!("Could not start {}", element.get_name()))?;
+    }
+
+    Ok(())
+}
+
+/// Removes the client's webrtcbin from the pipeline, if they have one
+pub fn drop_peer(pipeline: &gst::Pipeline, client: SocketAddr) -> Result<(), String> {
+    let webrtcbin = match get_webrtcbin(pipeline, client) {
+        Ok(webrtcbin) => webrtcbin,
+        Err(_) => return Ok(()),
+    };
+    info!("Removing WebRTC peer for client {}", client);
+    let queue = pipeline
+        .get_by_name(get_queue_name(client).as_str())
+        .ok_or_else(|| format!("Could not find WebRTC queue for client {}", client))?;
+    let tee = get_tee(pipeline)?;
+
+    tee.unlink(&queue);
+    queue.unlink(&webrtcbin);
+    for element in &[&queue, &webrtcbin] {
+        pipeline
+            .remove(*element)
+            .map_err(|_| format!("Could not remove {} from pipeline", element.get_name()))?;
+        element
+            .set_state(gst::State::Null)
+            .map_err(|_| format!("Could not set {} to state Null", element.get_name()))?;
+    }
+
+    Ok(())
+}
+
+/// Hands the client's answer to their webrtcbin
+pub fn set_answer(pipeline: &gst::Pipeline, client: SocketAddr, sdp: &str) -> Result<(), String> {
+    let webrtcbin = get_webrtcbin(pipeline, client)?;
+    let message = gst_sdp::SDPMessage::parse_buffer(sdp.as_bytes())
+        .map_err(|_| "Could not parse SDP answer".to_string())?;
+    let answer =
+        gst_webrtc::WebRTCSessionDescription::new(gst_webrtc::WebRTCSDPType::Answer, message);
+    webrtcbin
+        .emit("set-remote-description", &[&answer, &None::<gst::Promise>])
+        .map(|_| ())
+        .map_err(|err| format!("Could not set remote description: {}", err))
+}
+
+pub fn add_ice_candidate(
+    pipeline: &gst::Pipeline,
+    client: SocketAddr,
+    sdp_mline_index: u32,
+    candidate: &str,
+) -> Result<(), String> {
+    get_webrtcbin(pipeline, client)?
+        .emit("add-ice-candidate", &[&sdp_mline_index, &candidate])
+        .map(|_| ())
+        .map_err(|err| format!("Could not add ICE candidate: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sentry::bus;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use tokio::prelude::*;
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+            camera = {}
+
+            [server]
+            host = "127.0.0.1"
+            port = 0
+
+            [video]
+            encoder = "fakesink"
+            decoder = "fakesink"
+            host = "127.0.0.1"
+
+            [arduino]
+            device = "/dev/null"
+            baud = 115200
+            pitch_max_speed = 1000
+            yaw_max_speed = 1000
+            pitch_homing_speed = 1000
+            yaw_homing_speed = 1000
+            "#,
+        )
+        .expect("Invalid test configuration")
+    }
+
+    /// Answers an offer the way a browser would, handing the answer to the server's webrtcbin
+    fn answer(browser: &gst::Element, pipeline: gst::Pipeline, client: SocketAddr, sdp: &str) {
+        let offer = gst_webrtc::WebRTCSessionDescription::new(
+            gst_webrtc::WebRTCSDPType::Offer,
+            gst_sdp::SDPMessage::parse_buffer(sdp.as_bytes()).unwrap(),
+        );
+        browser
+            .emit("set-remote-description", &[&offer, &None::<gst::Promise>])
+            .unwrap();
+        let promise = gst::Promise::new_with_change_func({
+            let browser = browser.clone();
+            move |promise| {
+                let answer = promise
+                    .get_reply()
+                    .and_then(|reply| reply.get::<gst_webrtc::WebRTCSessionDescription>("answer"))
+                    .expect("Browser could not create an answer");
+                browser
+                    .emit("set-local-description", &[&answer, &None::<gst::Promise>])
+                    .unwrap();
+                set_answer(&pipeline, client, &answer.get_sdp().as_text().unwrap()).unwrap();
+            }
+        });
+        browser
+            .emit("create-answer", &[&None::<gst::Structure>, &promise])
+            .unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs webrtcbin from gst-plugins-bad and videotestsrc, vp8enc and rtpvp8pay"]
+    fn streams_to_a_browser() {
+        gst::init().unwrap();
+        for name in &["webrtcbin", "videotestsrc", "vp8enc", "rtpvp8pay"] {
+            assert!(
+                gst::ElementFactory::find(name).is_some(),
+                "{} is not installed",
+                name
+            );
+        }
+
+        let pipeline = gst::parse_launch(
+            "videotestsrc is-live=true ! vp8enc deadline=1 ! rtpvp8pay ! \
+             application/x-rtp,media=video,encoding-name=VP8,payload=96 ! \
+             tee name=tee allow-not-linked=true",
+        )
+        .unwrap()
+        .dynamic_cast::<gst::Pipeline>()
+        .unwrap();
+        // A second webrtcbin stands in for the browser, and answers the server's offer
+        let browser_pipeline = gst::Pipeline::new("browser");
+        let browser = gst::ElementFactory::make("webrtcbin", "browser_webrtc").unwrap();
+        browser_pipeline.add(&browser).unwrap();
+
+        let client = SocketAddr::from(([127, 0, 0, 1], 5000));
+        let (bus_sink, bus_stream) = bus::new::<Message>();
+        let (streaming_tx, streaming_rx) = mpsc::channel();
+        browser.connect_pad_added(move |_, _| {
+            let _ = streaming_tx.send(());
+        });
+        browser
+            .connect("on-ice-candidate", false, {
+                let pipeline = pipeline.clone();
+                move |values| {
+                    let sdp_mline_index = values[1].get::<u32>().unwrap();
+                    let candidate = values[2].get::<String>().unwrap();
+                    add_ice_candidate(&pipeline, client, sdp_mline_index, &candidate).unwrap();
+                    None
+                }
+            })
+            .unwrap();
+
+        // Signal between the two like the control connection would
+        thread::spawn({
+            let pipeline = pipeline.clone();
+            let browser = browser.clone();
+            move || {
+                for message in bus_stream.wait() {
+                    match message.unwrap().content {
+                        MessageContent::WebRtcOffer { sdp, for_client } => {
+                            assert_eq!(for_client, client);
+                            answer(&browser, pipeline.clone(), client, &sdp);
+                        }
+                        MessageContent::WebRtcIceCandidate {
+                            candidate,
+                            sdp_mline_index,
+                            ..
+                        } => {
+                            browser
+                                .emit("add-ice-candidate", &[&sdp_mline_index, &candidate])
+                                .unwrap();
+                        }
+                        MessageContent::VideoError { message, .. } => panic!("{}", message),
+                        _ => {}
+                    }
+                }
+            }
+        });
+
+        browser_pipeline.set_state(gst::State::Playing).unwrap();
+        pipeline.set_state(gst::State::Playing).unwrap();
+        add_peer(&pipeline, &config(), client, bus_sink).unwrap();
+
+        let streaming = streaming_rx.recv_timeout(Duration::from_secs(10));
+        pipeline.set_state(gst::State::Null).unwrap();
+        browser_pipeline.set_state(gst::State::Null).unwrap();
+        streaming.expect("The browser never received the video");
+    }
+}
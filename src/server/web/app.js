@@ -0,0 +1,490 @@
+'use strict';
+
+// Speaks the same JSON protocol as the Android app's MainActivity, over WebSocket. Every message
+// is an object whose type field says what it is, see src/sentry/protocol.rs.
+
+const PROTOCOL_VERSION = 2;
+
+const MOVE_INTERVAL = 50; // ms
+const PING_INTERVAL = 500; // ms
+const RECONNECT_DELAY = 500; // ms
+const JOYSTICK_RADIUS = 100; // px, matches #joystick-ring
+const JOYSTICK_DEADZONE = 0.01;
+const JOYSTICK_EXPONENT = 1.4;
+const MOTION_ALERT_DURATION = 3000; // ms
+const COMMAND_ERROR_DURATION = 5000; // ms
+
+const $ = id => document.getElementById(id);
+
+const settings = {
+    sensitivity: Number(localStorage.getItem('sensitivity') || 100),
+    reloadAfterFiring: localStorage.getItem('reloadAfterFiring') !== 'false',
+    invertX: localStorage.getItem('invertX') === 'true',
+    invertY: localStorage.getItem('invertY') === 'true',
+};
+
+const state = {
+    serverConfig: null,
+    socket: null,
+    peer: null,
+    // Resolves once the peer has the server's offer, so ICE candidates can be added to it
+    peerReady: null,
+    connected: false,
+    // Set once the server has accepted us into the queue
+    ready: false,
+    authError: null,
+    // Set if the server no longer speaks our protocol version
+    versionError: null,
+    queuePosition: -1,
+    numClients: 0,
+    status: null,
+    pitchDegrees: null,
+    yawDegrees: null,
+    // Devices the server has reported as unplugged
+    unplugged: new Set(),
+    // Null until the server reports it, which it only does when recording is configured
+    recording: null,
+    // Likewise for tracking, which is an object with enabled and paused once reported
+    tracking: null,
+    lastSnapshot: null,
+    // The last reported motion, which is shown until it goes stale
+    motion: null,
+    // Why the server last refused a command, until it goes stale
+    commandError: null,
+};
+
+const joystick = {
+    pressed: false,
+    originX: 0,
+    originY: 0,
+    x: 0,
+    y: 0,
+};
+
+function send(message) {
+    if (state.socket && state.socket.readyState === WebSocket.OPEN) {
+        state.socket.send(JSON.stringify(message));
+    }
+}
+
+function sendCommand(command) {
+    if (state.ready) {
+        send({ type: command });
+    }
+}
+
+function credentials() {
+    return {
+        user: localStorage.getItem('authUser') || '',
+        token: localStorage.getItem('authToken') || '',
+    };
+}
+
+function connect() {
+    const config = state.serverConfig;
+    if (config.auth && !credentials().token) {
+        updateUi();
+        return;
+    }
+
+    const protocol = config.tls ? 'wss' : 'ws';
+    const socket = new WebSocket(`${protocol}://${location.hostname}:${config.websocket_port}/`);
+    state.socket = socket;
+
+    socket.onopen = () => {
+        state.connected = true;
+        // The server won't listen to anything else until it has agreed on a version
+        send({ type: 'hello', version: PROTOCOL_VERSION });
+        updateUi();
+    };
+
+    socket.onmessage = event => {
+        let json;
+        try {
+            json = JSON.parse(event.data);
+        } catch (e) {
+            console.warn(`Error reading JSON message \`${event.data}\``);
+            return;
+        }
+        handleMessage(json);
+        updateUi();
+    };
+
+    socket.onclose = () => {
+        closePeer();
+        state.socket = null;
+        state.connected = false;
+        state.ready = false;
+        state.queuePosition = -1;
+        state.unplugged.clear();
+        state.recording = null;
+        state.tracking = null;
+        updateUi();
+        if (!state.authError && !state.versionError) {
+            setTimeout(connect, RECONNECT_DELAY);
+        }
+    };
+}
+
+function handleMessage(json) {
+    switch (json.type) {
+        case 'hello':
+            if (json.auth_required) {
+                const { user, token } = credentials();
+                send(user ? { type: 'auth', user, token } : { type: 'auth', token });
+            } else {
+                onReady();
+            }
+            break;
+        case 'authenticated':
+            state.authError = null;
+            onReady();
+            break;
+        case 'queue':
+            state.queuePosition = json.position;
+            state.numClients = json.num_clients;
+            break;
+        case 'webrtc_offer':
+            handleOffer(json.sdp).catch(err => console.error(`WebRTC negotiation failed: ${err}`));
+            break;
+        case 'webrtc_ice_candidate':
+            if (state.peer) {
+                const { candidate, sdp_mline_index: sdpMLineIndex } = json;
+                const peer = state.peer;
+                state.peerReady
+                    .then(() => peer.addIceCandidate({ candidate, sdpMLineIndex }))
+                    .catch(err => console.warn(`Could not add ICE candidate: ${err}`));
+            }
+            break;
+        case 'device':
+            if (json.connected) {
+                state.unplugged.delete(json.name);
+            } else {
+                state.unplugged.add(json.name);
+            }
+            break;
+        case 'recording':
+            state.recording = json.active;
+            break;
+        case 'tracking':
+            state.tracking = { enabled: json.enabled, paused: json.paused };
+            break;
+        case 'snapshot':
+            state.lastSnapshot = json.file_name;
+            break;
+        case 'motion': {
+            const motion = { region: json.region, bbox: json.bbox };
+            state.motion = motion;
+            setTimeout(() => {
+                if (state.motion === motion) {
+                    state.motion = null;
+                    updateUi();
+                }
+            }, MOTION_ALERT_DURATION);
+            break;
+        }
+        case 'status':
+            state.status = json.status;
+            state.pitchDegrees = json.pitch_degrees;
+            state.yawDegrees = json.yaw_degrees;
+            break;
+        case 'error':
+            handleError(json.code, json.message);
+            break;
+    }
+}
+
+function handleError(code, message) {
+    switch (code) {
+        case 'auth_failed':
+            state.authError = message;
+            localStorage.removeItem('authToken');
+            break;
+        case 'unsupported_version':
+            // The server hangs up, and reconnecting won't help until the page is reloaded
+            state.versionError = message;
+            break;
+        case 'command_blocked':
+        case 'invalid_command':
+        case 'preset':
+            state.commandError = message;
+            setTimeout(() => {
+                if (state.commandError === message) {
+                    state.commandError = null;
+                    updateUi();
+                }
+            }, COMMAND_ERROR_DURATION);
+            break;
+        default:
+            console.warn(`Server error (${code}): ${message}`);
+    }
+}
+
+function onReady() {
+    state.ready = true;
+    // Ask for the camera over WebRTC, since browsers can't take part in the RTP/UDP handshake
+    send({ type: 'start_webrtc' });
+}
+
+function closePeer() {
+    if (state.peer) {
+        state.peer.close();
+    }
+    state.peer = null;
+    state.peerReady = null;
+    $('video').srcObject = null;
+}
+
+async function handleOffer(sdp) {
+    closePeer();
+    const stunServer = state.serverConfig.stun_server;
+    const peer = new RTCPeerConnection({
+        // GStreamer writes STUN servers as stun://host:port, browsers as stun:host:port
+        iceServers: stunServer ? [{ urls: stunServer.replace('stun://', 'stun:') }] : [],
+        bundlePolicy: 'max-bundle',
+    });
+    state.peer = peer;
+
+    peer.ontrack = event => {
+        $('video').srcObject = event.streams[0] || new MediaStream([event.track]);
+    };
+    peer.onicecandidate = event => {
+        if (event.candidate) {
+            send({
+                type: 'webrtc_ice_candidate',
+                candidate: event.candidate.candidate,
+                sdp_mline_index: event.candidate.sdpMLineIndex,
+            });
+        }
+    };
+
+    state.peerReady = peer.setRemoteDescription({ type: 'offer', sdp });
+    await state.peerReady;
+    const answer = await peer.createAnswer();
+    await peer.setLocalDescription(answer);
+    send({ type: 'webrtc_answer', sdp: answer.sdp });
+}
+
+function statusMessage() {
+    const { connected, authError, queuePosition, status } = state;
+    if (state.versionError) return state.versionError;
+    if (authError) return `Authentication failed: ${authError}`;
+    if (!connected) return `Connecting to ${location.hostname}...`;
+    if (!state.ready) return 'Authenticating...';
+    if (state.unplugged.has('arduino')) return 'Arduino unplugged';
+    if (state.unplugged.has('camera')) return 'Camera unplugged';
+    if (queuePosition > 0) return 'Someone else is already in control';
+    switch (status) {
+        case 'error': return 'Hardware error, restart Arduino';
+        case 'homing_failed': return 'Homing failed';
+        case 'homing_required': return 'Homing required';
+        case 'homing': return 'Homing...';
+        case 'motors_off': return 'Motors are turned off';
+        case 'not_loaded': return 'Reload';
+        case 'reloading': return 'Reloading...';
+        default: return null;
+    }
+}
+
+function updateUi() {
+    const { status } = state;
+    const message = statusMessage();
+    $('message').hidden = message === null;
+    $('message').textContent = message || '';
+
+    $('connection').textContent = state.ready ? 'Connected' : 'Disconnected';
+    $('queue').textContent = state.ready && state.queuePosition >= 0
+        ? `Queue position ${state.queuePosition + 1} of ${state.numClients}`
+        : '';
+    $('position').textContent = state.pitchDegrees !== null && state.pitchDegrees !== undefined
+        ? `Pitch ${state.pitchDegrees.toFixed(1)}°, yaw ${state.yawDegrees.toFixed(1)}°`
+        : '';
+
+    const isActiveClient = state.ready && state.queuePosition === 0;
+    const menuOpen = !$('menu').hidden;
+    const canMove = isActiveClient
+        && ['ready', 'not_loaded', 'magazine_released'].includes(status);
+
+    $('joystick').hidden = !(isActiveClient && !menuOpen && canMove);
+    $('fire-button').hidden = !(isActiveClient && !menuOpen && status === 'ready');
+    $('home-button').hidden = !(isActiveClient && status !== 'motors_off');
+    $('mag-release-button').hidden = !(isActiveClient
+        && (status === 'not_loaded' || status === 'magazine_released'));
+    $('reload-button').hidden = !(isActiveClient && status === 'not_loaded');
+    $('motors-button').hidden = !isActiveClient;
+    $('record-button').hidden = !(isActiveClient && state.recording !== null);
+    $('track-button').hidden = !(isActiveClient && state.tracking !== null);
+    $('snapshot-button').hidden = !state.ready;
+    $('snapshot').textContent = state.lastSnapshot ? `Saved ${state.lastSnapshot}` : '';
+    $('command-error').textContent = state.commandError || '';
+    $('motion').textContent = state.motion ? `Motion in ${state.motion.region}` : '';
+
+    $('motors-button').textContent = status === 'motors_off' ? 'Turn Motors On' : 'Turn Motors Off';
+    $('record-button').textContent = state.recording ? 'Stop Recording' : 'Record';
+    $('record-button').classList.toggle('recording', Boolean(state.recording));
+    if (state.tracking) {
+        const { enabled, paused } = state.tracking;
+        $('track-button').textContent = enabled
+            ? (paused ? 'Stop Tracking (paused)' : 'Stop Tracking')
+            : 'Track Motion';
+    }
+    $('mag-release-button').textContent = status === 'magazine_released'
+        ? 'Load Magazine'
+        : 'Magazine Release';
+
+    $('auth').hidden = !(state.serverConfig && state.serverConfig.auth && !state.connected
+        && (state.authError || !credentials().token));
+
+    if ($('joystick').hidden) {
+        releaseJoystick();
+    }
+}
+
+function moveJoystick(event) {
+    let x = event.clientX - joystick.originX;
+    let y = event.clientY - joystick.originY;
+    // Constrain the knob to the ring
+    const magnitude = Math.hypot(x, y);
+    if (magnitude > JOYSTICK_RADIUS) {
+        x *= JOYSTICK_RADIUS / magnitude;
+        y *= JOYSTICK_RADIUS / magnitude;
+    }
+
+    const shape = value => {
+        if (Math.abs(value) < JOYSTICK_DEADZONE) return 0;
+        return Math.sign(value) * Math.pow(Math.abs(value), JOYSTICK_EXPONENT);
+    };
+    joystick.x = shape(x / JOYSTICK_RADIUS);
+    joystick.y = shape(-y / JOYSTICK_RADIUS);
+}
+
+function releaseJoystick() {
+    joystick.pressed = false;
+    joystick.x = 0;
+    joystick.y = 0;
+    $('joystick-ring').style.visibility = 'hidden';
+}
+
+function initJoystick() {
+    const area = $('joystick');
+    const ring = $('joystick-ring');
+
+    area.addEventListener('pointerdown', event => {
+        area.setPointerCapture(event.pointerId);
+        joystick.pressed = true;
+        joystick.originX = event.clientX;
+        joystick.originY = event.clientY;
+        const bounds = area.getBoundingClientRect();
+        ring.style.left = `${event.clientX - bounds.left}px`;
+        ring.style.top = `${event.clientY - bounds.top}px`;
+        ring.style.visibility = 'visible';
+        moveJoystick(event);
+    });
+    area.addEventListener('pointermove', event => {
+        if (joystick.pressed) {
+            moveJoystick(event);
+        }
+    });
+    area.addEventListener('pointerup', releaseJoystick);
+    area.addEventListener('pointercancel', releaseJoystick);
+
+    setInterval(() => {
+        if (!state.ready || $('joystick').hidden) return;
+        const scale = settings.sensitivity / 100;
+        send({
+            type: 'move',
+            pitch: Number((joystick.y * scale * (settings.invertY ? -1 : 1)).toFixed(3)),
+            yaw: Number((joystick.x * scale * (settings.invertX ? -1 : 1)).toFixed(3)),
+        });
+    }, MOVE_INTERVAL);
+}
+
+function initControls() {
+    $('fire-button').addEventListener('click', () => {
+        sendCommand(settings.reloadAfterFiring ? 'fire_and_reload' : 'fire');
+    });
+    $('home-button').addEventListener('click', () => sendCommand('home'));
+    $('reload-button').addEventListener('click', () => sendCommand('reload'));
+    $('mag-release-button').addEventListener('click', () => {
+        sendCommand(state.status === 'magazine_released' ? 'load_magazine' : 'release_magazine');
+    });
+    $('motors-button').addEventListener('click', () => {
+        sendCommand(state.status === 'motors_off' ? 'motors_on' : 'motors_off');
+    });
+
+    $('record-button').addEventListener('click', () => {
+        sendCommand(state.recording ? 'stop_recording' : 'start_recording');
+    });
+
+    $('track-button').addEventListener('click', () => {
+        sendCommand(state.tracking && state.tracking.enabled ? 'stop_tracking' : 'start_tracking');
+    });
+
+    $('snapshot-button').addEventListener('click', () => send({ type: 'snapshot' }));
+
+    $('menu-button').addEventListener('click', () => {
+        $('menu').hidden = !$('menu').hidden;
+        updateUi();
+    });
+}
+
+function initSettings() {
+    const save = () => {
+        for (const [key, value] of Object.entries(settings)) {
+            localStorage.setItem(key, String(value));
+        }
+    };
+
+    $('sensitivity').value = settings.sensitivity;
+    $('sensitivity-value').textContent = `${settings.sensitivity}%`;
+    $('sensitivity').addEventListener('input', event => {
+        settings.sensitivity = Number(event.target.value);
+        $('sensitivity-value').textContent = `${settings.sensitivity}%`;
+        save();
+    });
+
+    const checkboxes = {
+        'reload-after-firing': 'reloadAfterFiring',
+        'invert-x': 'invertX',
+        'invert-y': 'invertY',
+    };
+    for (const [id, key] of Object.entries(checkboxes)) {
+        $(id).checked = settings[key];
+        $(id).addEventListener('change', event => {
+            settings[key] = event.target.checked;
+            save();
+        });
+    }
+}
+
+function initAuth() {
+    $('auth-user').value = credentials().user;
+    $('auth').addEventListener('submit', event => {
+        event.preventDefault();
+        localStorage.setItem('authUser', $('auth-user').value);
+        localStorage.setItem('authToken', $('auth-token').value);
+        $('auth-token').value = '';
+        state.authError = null;
+        connect();
+    });
+}
+
+async function main() {
+    initJoystick();
+    initControls();
+    initSettings();
+    initAuth();
+
+    // Keep the server's watchdog from dropping us
+    setInterval(() => {
+        if (state.ready) {
+            send({ type: 'ping' });
+        }
+    }, PING_INTERVAL);
+
+    const response = await fetch('config.json');
+    state.serverConfig = await response.json();
+    connect();
+}
+
+main();